@@ -0,0 +1,102 @@
+// Cleanup latency against the number of active clients, run with `cargo bench --bench cleanup`.
+// Runs `StatsStore::cleanup` on a `MemoryStore`, so it doesn't need a database on disk.
+use std::{
+    hint::black_box,
+    net::{IpAddr, Ipv4Addr},
+    time::{Duration, Instant},
+};
+
+use melodybrain::{
+    StoredIpStats, WORLDWIDE,
+    aggregate::Aggregation,
+    store::{FIRST_V4_BUCKET, MemoryStore, StatsStore},
+};
+
+const LIVENESS_WINDOW: u64 = 10;
+const CLEANUP_PERIOD: u64 = 20;
+const TICKS: u64 = 50;
+// Share of clients that stop sending heartbeats every cleanup period
+const CHURN: usize = 100;
+const COUNTRY: u8 = 5;
+
+// Every client in a /24 of its own
+fn client(key: usize) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::from_bits((FIRST_V4_BUCKET + key as u32) << 8))
+}
+
+fn bench_active(clients: usize) {
+    let store = MemoryStore::empty(1);
+    let mut now = 1_000_000;
+
+    for key in 0..clients {
+        store.with_record(client(key), true, |record| {
+            record.stats.first_seen = now;
+            record.stats.last_seen = now - (key as u64 % LIVENESS_WINDOW);
+            record.stats.country = COUNTRY;
+            record.pending.activate(COUNTRY, true);
+            record.schedule_expiry(record.stats.last_seen + LIVENESS_WINDOW + 1);
+        });
+    }
+    store.merge(Aggregation::Median);
+
+    let mut total = Duration::ZERO;
+    let mut worst = Duration::ZERO;
+
+    for tick in 0..TICKS {
+        now += CLEANUP_PERIOD;
+
+        // Everybody still around sends a heartbeat, except for a rotating slice of clients that went quiet
+        for key in (0..clients).filter(|key| !(key + tick as usize).is_multiple_of(CHURN)) {
+            store.with_record(client(key), false, |record| {
+                if record.stats.last_seen != 0 {
+                    record.stats.last_seen = now - (key as u64 % LIVENESS_WINDOW);
+                }
+            });
+        }
+
+        let start = Instant::now();
+        store.cleanup(black_box(now), LIVENESS_WINDOW);
+        let elapsed = start.elapsed();
+
+        total += elapsed;
+        worst = worst.max(elapsed);
+    }
+
+    let left = store.merge(Aggregation::Median).stats(WORLDWIDE).connected;
+    println!(
+        "{clients:>10} active | mean {:>12.3?} | worst {:>12.3?} | {left:>10} still active",
+        total / TICKS as u32,
+        worst,
+    );
+}
+
+// What every cleanup used to cost, regardless of how many clients are active
+fn bench_full_scan() {
+    let first = Ipv4Addr::new(1, 0, 0, 0).to_bits() >> 8;
+    let last = Ipv4Addr::new(223, 255, 255, 255).to_bits() >> 8;
+    let mut bytes = vec![0u8; (last - first + 1) as usize * size_of::<StoredIpStats>()];
+    let records: &mut [StoredIpStats] = bytemuck::cast_slice_mut(&mut bytes);
+
+    let start = Instant::now();
+    let mut expired = 0;
+    for record in black_box(&mut *records) {
+        if record.first_seen != 0 && record.last_seen != 0 {
+            expired += 1;
+        }
+    }
+    black_box(expired);
+
+    println!(
+        "full scan of {} /24 buckets: {:.3?}",
+        records.len(),
+        start.elapsed()
+    );
+}
+
+fn main() {
+    for clients in [1_000, 10_000, 100_000, 1_000_000] {
+        bench_active(clients);
+    }
+
+    bench_full_scan();
+}
@@ -0,0 +1,172 @@
+// Floods a melodybrain-server with simulated clients and reports throughput and reply latency:
+//
+//     cargo run --release --example loadtest -- --server 127.0.0.1:2026 --clients 20000
+//
+// Every client asks for stats (the most expensive request) once per interval. Against a loopback server each client
+// binds its own 127.x.y.1 address so that it lands in its own /24 bucket, otherwise they all share the one bucket of
+// this machine. Raise `ulimit -n` above the number of clients first, every client needs its own socket.
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use clap::Parser;
+use melodybrain::{Cookie, Heartbeat, Message, StatsRequest, WORLDWIDE};
+use tokio::{net::UdpSocket, sync::Mutex, time::timeout_at};
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// Server to test
+    #[arg(long, default_value = "127.0.0.1:2026")]
+    server: SocketAddr,
+
+    /// Number of simulated clients
+    #[arg(long, default_value_t = 10_000)]
+    clients: u32,
+
+    /// Milliseconds between each client's requests
+    #[arg(long, default_value_t = 1000)]
+    interval_ms: u64,
+
+    /// How long to run for
+    #[arg(long, default_value_t = 20)]
+    duration_secs: u64,
+
+    /// Milliseconds to wait for a reply before counting it as lost
+    #[arg(long, default_value_t = 1000)]
+    timeout_ms: u64,
+}
+
+#[derive(Default)]
+struct Results {
+    sent: u64,
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+fn client_addr(server: SocketAddr, idx: u32) -> SocketAddr {
+    match server.ip() {
+        IpAddr::V4(ip) if ip.is_loopback() => {
+            let [_, _, hi, lo] = (idx + 1).to_be_bytes();
+            (Ipv4Addr::new(127, hi, lo, 1), 0).into()
+        }
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => "[::]:0".parse().unwrap(),
+    }
+}
+
+async fn client(args: Arc<Args>, idx: u32, results: Arc<Mutex<Results>>) {
+    let sock = UdpSocket::bind(client_addr(args.server, idx))
+        .await
+        .expect("failed to bind client socket, is ulimit -n high enough?");
+    sock.connect(args.server).await.unwrap();
+
+    let interval = Duration::from_millis(args.interval_ms);
+    let end = Instant::now() + Duration::from_secs(args.duration_secs);
+    let mut local = Results::default();
+    let mut buf = [0; 1200];
+    let mut cookie = Cookie::default();
+    let mut id = 0;
+
+    // Spread clients evenly over the interval instead of having them all fire at once
+    tokio::time::sleep(interval * idx / args.clients).await;
+    let mut ticker = tokio::time::interval(interval);
+
+    while Instant::now() < end {
+        ticker.tick().await;
+
+        id += 1;
+        let msg = Message::StatsRequest(StatsRequest {
+            id,
+            heartbeat: Heartbeat {
+                seed: idx as i32,
+                cookie,
+            },
+            country: WORLDWIDE,
+        });
+        let msg = msg.encode(&mut buf).unwrap();
+
+        let start = Instant::now();
+        if sock.send(msg).await.is_err() {
+            local.errors += 1;
+            continue;
+        }
+        local.sent += 1;
+
+        let deadline = start + Duration::from_millis(args.timeout_ms);
+        loop {
+            let reply = timeout_at(deadline.into(), sock.recv(&mut buf)).await;
+            match reply {
+                Ok(Ok(n)) => match Message::decode(&buf[..n]) {
+                    // Late reply to an earlier request that was already counted as lost
+                    Ok(msg) if msg.reply_id() != Some(id) => continue,
+                    Ok(Message::Stats { .. }) => local.latencies.push(start.elapsed()),
+                    // Only the first request of every client, so it isn't counted at all
+                    Ok(Message::Cookie { cookie: new, .. }) => {
+                        cookie = new;
+                        local.sent -= 1;
+                    }
+                    _ => local.errors += 1,
+                },
+                Ok(Err(_)) => local.errors += 1,
+                // Lost
+                Err(_) => {}
+            }
+            break;
+        }
+    }
+
+    let mut results = results.lock().await;
+    results.sent += local.sent;
+    results.errors += local.errors;
+    results.latencies.extend(local.latencies);
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Arc::new(Args::parse());
+    let results = Arc::new(Mutex::new(Results::default()));
+
+    println!(
+        "{} clients sending every {}ms for {}s, about as much load as {} real clients",
+        args.clients,
+        args.interval_ms,
+        args.duration_secs,
+        // Real clients send a heartbeat every 15 seconds
+        args.clients as u64 * 15_000 / args.interval_ms
+    );
+
+    let tasks: Vec<_> = (0..args.clients)
+        .map(|idx| tokio::spawn(client(Arc::clone(&args), idx, Arc::clone(&results))))
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let mut results = results.lock().await;
+    results.latencies.sort_unstable();
+
+    let received = results.latencies.len() as u64;
+    let percentile = |p: usize| {
+        results
+            .latencies
+            .get(results.latencies.len().saturating_sub(1) * p / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+
+    println!(
+        "sent {} requests ({:.0}/s), got {received} replies, lost {:.2}%, {} errors",
+        results.sent,
+        results.sent as f64 / args.duration_secs as f64,
+        100.0 * results.sent.saturating_sub(received) as f64 / results.sent.max(1) as f64,
+        results.errors
+    );
+    println!(
+        "latency p50 {:.2?} | p99 {:.2?} | max {:.2?}",
+        percentile(50),
+        percentile(99),
+        percentile(100)
+    );
+}
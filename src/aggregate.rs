@@ -0,0 +1,119 @@
+use std::collections::VecDeque;
+
+// How a country's seed is derived from the seeds its clients contribute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregation {
+    // Every contribution moves the seed 1/divisor of the way towards it. Cheap and smooth, but a single client that
+    // keeps sending the same extreme seed will eventually drag the average all the way there.
+    Ema { divisor: i64 },
+    // Median of the recent contributions, one client can only ever shift it by one rank
+    Median,
+    // Mean of the recent contributions without the lowest and highest trim_percent of them
+    TrimmedMean { trim_percent: u8 },
+}
+
+impl Aggregation {
+    // Recent contributions only matter to the robust modes
+    pub fn window(&self, window: usize) -> usize {
+        match self {
+            Self::Ema { .. } => 0,
+            Self::Median | Self::TrimmedMean { .. } => window,
+        }
+    }
+
+    // Called for every contribution as it's merged in
+    pub fn contribute(
+        &self,
+        seed: &mut i64,
+        recent: &mut RecentSeeds,
+        contributor: u64,
+        value: i32,
+    ) {
+        match self {
+            Self::Ema { divisor } => *seed += (value as i64 - *seed) / divisor,
+            Self::Median | Self::TrimmedMean { .. } => recent.push(contributor, value),
+        }
+    }
+
+    // Called once after a batch of contributions, so the robust modes only sort the window once per merge
+    pub fn settle(&self, seed: &mut i64, recent: &RecentSeeds) {
+        let settled = match self {
+            Self::Ema { .. } => None,
+            Self::Median => recent.median(),
+            Self::TrimmedMean { trim_percent } => recent.trimmed_mean(*trim_percent),
+        };
+
+        if let Some(settled) = settled {
+            *seed = settled;
+        }
+    }
+}
+
+// The latest seed of each of the last `window` distinct contributors (a /24 or /48) to a country. A contributor that
+// sends again replaces its own entry instead of taking up another one, so flooding can't crowd out everyone else.
+#[derive(Clone, Debug)]
+pub struct RecentSeeds {
+    window: usize,
+    // Oldest first
+    entries: VecDeque<(u64, i32)>,
+}
+
+impl RecentSeeds {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            entries: VecDeque::with_capacity(window),
+        }
+    }
+
+    pub fn push(&mut self, contributor: u64, seed: i32) {
+        if self.window == 0 {
+            return;
+        }
+
+        if let Some(idx) = self.entries.iter().position(|&(id, _)| id == contributor) {
+            self.entries.remove(idx);
+        } else if self.entries.len() == self.window {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back((contributor, seed));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn sorted(&self) -> Vec<i64> {
+        let mut seeds: Vec<_> = self.entries.iter().map(|&(_, seed)| seed as i64).collect();
+        seeds.sort_unstable();
+        seeds
+    }
+
+    pub fn median(&self) -> Option<i64> {
+        let seeds = self.sorted();
+        let mid = seeds.len() / 2;
+
+        match seeds.len() {
+            0 => None,
+            len if len % 2 == 1 => Some(seeds[mid]),
+            _ => Some((seeds[mid - 1] + seeds[mid]) / 2),
+        }
+    }
+
+    pub fn trimmed_mean(&self, trim_percent: u8) -> Option<i64> {
+        let seeds = self.sorted();
+        let trim = seeds.len() * trim_percent as usize / 100;
+        let kept = &seeds[trim..seeds.len() - trim];
+
+        if kept.is_empty() {
+            return None;
+        }
+
+        Some(kept.iter().sum::<i64>() / kept.len() as i64)
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::oneshot,
+    time::{sleep, timeout},
+};
+
+use crate::{Cookie, Heartbeat, Message, Stats, StatsRequest};
+
+// Doubles after every failed attempt
+pub const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+pub enum Fetched {
+    Fresh(Stats),
+    // No stats from the server this time, these are the last ones it sent
+    Stale(Stats),
+    // No stats from the server this time, and never any for this country
+    Offline,
+}
+
+// Why a stats request came back without stats
+enum Failed {
+    // Worth trying again
+    NoReply,
+    // The server answered but won't send stats, like when the client is rate limited. Trying again right away
+    // doesn't help.
+    Refused,
+}
+
+// Requests waiting for a reply, by request id
+#[derive(Debug, Default)]
+struct Pending(Mutex<HashMap<u32, oneshot::Sender<Message>>>);
+
+impl Pending {
+    fn insert(&self, id: u32) -> oneshot::Receiver<Message> {
+        let (tx, rx) = oneshot::channel();
+        self.0.lock().unwrap().insert(id, tx);
+        rx
+    }
+
+    fn remove(&self, id: u32) -> Option<oneshot::Sender<Message>> {
+        self.0.lock().unwrap().remove(&id)
+    }
+}
+
+// Talks to a server over a connected socket. Replies only reach their requests while `dispatch` is running.
+#[derive(Debug)]
+pub struct Client {
+    sock: UdpSocket,
+    // Last cookie the server handed out, proves we can receive replies at our address
+    cookie: Mutex<Cookie>,
+    next_request_id: AtomicU32,
+    pending: Pending,
+    // Whether the server answered the last stats request
+    online: AtomicBool,
+    // Latest stats the server sent for each country, served while it's unreachable
+    last_stats: Mutex<HashMap<u8, Stats>>,
+    stats_timeout: Duration,
+    stats_retries: u32,
+}
+
+impl Client {
+    // `stats_retries` is how many more times to ask for stats before falling back to the last known ones
+    pub fn new(sock: UdpSocket, stats_timeout: Duration, stats_retries: u32) -> Self {
+        Self {
+            sock,
+            cookie: Mutex::new(Cookie::default()),
+            next_request_id: AtomicU32::new(1),
+            pending: Pending::default(),
+            online: AtomicBool::new(true),
+            last_stats: Mutex::new(HashMap::new()),
+            stats_timeout,
+            stats_retries,
+        }
+    }
+
+    fn next_request_id(&self) -> u32 {
+        // 0 is what the server answers with when it couldn't read the id
+        loop {
+            let id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+
+    fn heartbeat(&self, seed: i32) -> Heartbeat {
+        Heartbeat {
+            seed,
+            cookie: *self.cookie.lock().unwrap(),
+        }
+    }
+
+    // Sends a request and waits for the dispatcher to hand over the reply with the same id
+    async fn request(&self, request: StatsRequest) -> Option<Message> {
+        let mut buf = [0; 1200];
+        let id = request.id;
+
+        let reply = self.pending.insert(id);
+        let msg = Message::StatsRequest(request).encode(&mut buf).unwrap();
+        let _ = self.sock.send(msg).await;
+
+        let reply = timeout(self.stats_timeout, reply).await;
+        // Late replies are dropped by the dispatcher once nobody is waiting for them anymore
+        self.pending.remove(id);
+
+        reply.ok()?.ok()
+    }
+
+    // A heartbeat that doesn't wait for stats. A server that doesn't know us yet answers with a cookie, the dispatcher
+    // keeps it for the next one.
+    pub async fn send_heartbeat(&self, seed: i32) {
+        let mut buf = [0; 1200];
+        let msg = Message::Heartbeat(self.heartbeat(seed))
+            .encode(&mut buf)
+            .unwrap();
+        let _ = self.sock.send(msg).await;
+    }
+
+    async fn request_stats(&self, country: u8, seed: i32) -> Result<Stats, Failed> {
+        // The first request after startup (or after the server restarted) only gets a cookie back, so retry once with it
+        for _ in 0..2 {
+            let request = StatsRequest {
+                id: self.next_request_id(),
+                heartbeat: self.heartbeat(seed),
+                country,
+            };
+
+            match self.request(request).await.ok_or(Failed::NoReply)? {
+                Message::Stats { stats, .. } => return Ok(*stats),
+                Message::Cookie { cookie, .. } => *self.cookie.lock().unwrap() = cookie,
+                Message::Error { error, .. } => {
+                    eprintln!("server rejected request: {error}");
+                    return Err(Failed::Refused);
+                }
+                _ => return Err(Failed::NoReply),
+            }
+        }
+
+        Err(Failed::NoReply)
+    }
+
+    // Counts as a heartbeat with `seed` too
+    pub async fn fetch_stats(&self, country: u8, seed: i32) -> Fetched {
+        // Once the server stopped answering, don't make every page load sit through the whole backoff again
+        let attempts = if self.online.load(Ordering::Relaxed) {
+            self.stats_retries + 1
+        } else {
+            1
+        };
+
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                sleep(RETRY_BACKOFF * 2u32.saturating_pow(attempt - 1)).await;
+            }
+
+            match self.request_stats(country, seed).await {
+                Ok(stats) => {
+                    self.online.store(true, Ordering::Relaxed);
+                    self.last_stats
+                        .lock()
+                        .unwrap()
+                        .insert(country, stats.clone());
+                    return Fetched::Fresh(stats);
+                }
+                Err(Failed::NoReply) => self.online.store(false, Ordering::Relaxed),
+                // Still there, just not answering this one
+                Err(Failed::Refused) => {
+                    self.online.store(true, Ordering::Relaxed);
+                    break;
+                }
+            }
+        }
+
+        match self.last_stats.lock().unwrap().get(&country) {
+            Some(stats) => Fetched::Stale(stats.clone()),
+            None => Fetched::Offline,
+        }
+    }
+
+    // Owns the receiving side of the socket and routes every reply to whoever sent the request
+    pub async fn dispatch(&self) {
+        let mut buf = [0; 1200];
+
+        loop {
+            let Ok(n) = self.sock.recv(&mut buf).await else {
+                continue;
+            };
+
+            let msg = match Message::decode(&buf[..n]) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    eprintln!("bad reply from server: {err}");
+                    continue;
+                }
+            };
+            let Some(id) = msg.reply_id() else {
+                continue;
+            };
+
+            match self.pending.remove(id) {
+                Some(waiting) => {
+                    let _ = waiting.send(msg);
+                }
+                None => match msg {
+                    Message::Cookie { cookie, .. } => *self.cookie.lock().unwrap() = cookie,
+                    Message::Error { error, .. } => eprintln!("server rejected request: {error}"),
+                    _ => {}
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, SocketAddr},
+};
+
+use tokio::net::UdpSocket;
+
+use crate::{Cookie, Message};
+
+// How long a cookie stays valid, it's accepted for up to twice this long
+pub const COOKIE_EPOCH_SECS: u64 = 300;
+
+// Stateless return-routability check: a cookie is a keyed hash of the client's address, so only someone who received
+// a reply at that address can know it. The key is random per process, restarting the server invalidates every cookie.
+pub struct CookieJar(RandomState);
+
+impl CookieJar {
+    pub fn new() -> Self {
+        Self(RandomState::new())
+    }
+
+    fn cookie_for(&self, addr: IpAddr, epoch: u64) -> Cookie {
+        Cookie(self.0.hash_one((addr, epoch)).to_le_bytes())
+    }
+
+    pub fn issue(&self, addr: IpAddr, now: u64) -> Cookie {
+        self.cookie_for(addr, now / COOKIE_EPOCH_SECS)
+    }
+
+    pub fn verify(&self, addr: IpAddr, now: u64, cookie: Cookie) -> bool {
+        let epoch = now / COOKIE_EPOCH_SECS;
+
+        cookie == self.cookie_for(addr, epoch)
+            || cookie == self.cookie_for(addr, epoch.saturating_sub(1))
+    }
+}
+
+impl Default for CookieJar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// For replies to addresses that haven't proven they're real, never send back more than was received so the server
+// can't be used to amplify a spoofed flood. Returns whether the reply was sent.
+pub async fn reply_unverified(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+    addr: SocketAddr,
+    msg: &Message,
+    request_len: usize,
+) -> bool {
+    let msg = msg.encode(buf).expect("reply should always fit in buffer");
+    if msg.len() > request_len {
+        return false;
+    }
+
+    socket.send_to(msg, addr).await.is_ok()
+}
@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    harmony::TrackNote,
+    meter::{TimeSignature, sixteenths_to_beats},
+    notes::NoiseRng,
+};
+
+// General MIDI percussion keys, which is what the pitch of a drum note is
+pub const KICK: i8 = 36;
+pub const SNARE: i8 = 38;
+pub const CLOSED_HAT: i8 = 42;
+pub const OPEN_HAT: i8 = 46;
+pub const CRASH: i8 = 49;
+
+// Yet another keyboard mash
+const DRUM_NOISE: i32 = 564738291;
+
+// Bars between fills
+const PHRASE: u32 = 4;
+
+// Steps are sixteenths, the same grid as note durations, and these are all 4/4
+const KICKS: [&[u32]; 4] = [&[0, 8], &[0, 6, 8], &[0, 10], &[0, 3, 8, 11]];
+const SNARES: [&[u32]; 2] = [&[4, 12], &[4, 12, 15]];
+
+// The parts of the groove that stay the same for a whole melody
+struct Groove {
+    kicks: Vec<u32>,
+    snares: Vec<u32>,
+    hat_spacing: u32,
+    open_hat: bool,
+    // Where each felt beat of the bar starts
+    accents: Vec<u32>,
+    bar: u32,
+}
+
+impl Groove {
+    fn pick(rng: &mut NoiseRng, time_signature: TimeSignature) -> Self {
+        let groups = time_signature.groups();
+        let accents: Vec<_> = groups
+            .iter()
+            .scan(0, |start, &group| {
+                *start += group;
+                Some(*start - group)
+            })
+            .collect();
+
+        // Other meters get the kick on the one and the snare on every other beat
+        let (kicks, snares) = if time_signature == TimeSignature::COMMON {
+            (pick(rng, &KICKS).to_vec(), pick(rng, &SNARES).to_vec())
+        } else {
+            (vec![0], accents[1..].to_vec())
+        };
+        // Steps between hats: sixteenths, eighths or one a beat
+        let hat_spacing = pick(rng, &[1, 2, groups[0]]);
+
+        Self {
+            kicks,
+            snares,
+            hat_spacing,
+            open_hat: rng.sample_next() < 0.5,
+            accents,
+            bar: time_signature.bar_length(),
+        }
+    }
+}
+
+fn pick<T: Copy>(rng: &mut NoiseRng, choices: &[T]) -> T {
+    let idx = (rng.sample_next() * choices.len() as f32) as usize;
+    choices[idx.min(choices.len() - 1)]
+}
+
+pub struct Drummer {
+    rng: NoiseRng,
+    // Where the groove got picked, so a resumed drummer picks the same one
+    origin: NoiseRng,
+    groove: Groove,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct DrummerState {
+    rng: NoiseRng,
+    origin: NoiseRng,
+}
+
+impl Drummer {
+    pub fn new(start: u32, seed: i32, time_signature: TimeSignature) -> Self {
+        let origin = NoiseRng::with_stream(start, seed, DRUM_NOISE);
+        let mut rng = origin.clone();
+        let groove = Groove::pick(&mut rng, time_signature);
+
+        Self {
+            rng,
+            origin,
+            groove,
+        }
+    }
+
+    pub(crate) fn resume(state: &DrummerState, time_signature: TimeSignature) -> Self {
+        Self {
+            rng: state.rng.clone(),
+            origin: state.origin.clone(),
+            groove: Groove::pick(&mut state.origin.clone(), time_signature),
+        }
+    }
+
+    pub(crate) fn fits(&self, state: &DrummerState) -> bool {
+        self.rng.fits(&state.rng) && self.origin.fits(&state.origin)
+    }
+
+    pub(crate) fn state(&self) -> DrummerState {
+        DrummerState {
+            rng: self.rng.clone(),
+            origin: self.origin.clone(),
+        }
+    }
+
+    // Fills and pickups come from another seed's noise, the groove stays
+    pub(crate) fn reseed(&mut self, seed: i32) {
+        self.rng.reseed(seed);
+    }
+
+    pub(crate) fn crossfade(&mut self, seed: i32) {
+        self.rng.crossfade(seed);
+    }
+
+    pub(crate) fn set_fade(&mut self, left: f32) {
+        self.rng.set_fade(left);
+    }
+
+    // Bar number `bar` of the stream, starting `start` sixteenths in
+    pub(crate) fn play_bar(&mut self, notes: &mut Vec<TrackNote>, bar: u32, start: u32) {
+        let groove = &self.groove;
+        let mut hits = Vec::new();
+
+        // A crash to mark the start of every phrase after the first
+        if bar > 0 && bar.is_multiple_of(PHRASE) {
+            hits.push((start, CRASH, 0.7));
+        }
+        if bar % PHRASE == PHRASE - 1 {
+            fill_bar(&mut self.rng, groove, start, &mut hits);
+        } else {
+            groove_bar(&mut self.rng, groove, start, &mut hits);
+        }
+
+        hits.sort_by_key(|&(start, ..)| start);
+        notes.extend(hits.into_iter().map(|(start, pitch, velocity)| TrackNote {
+            start: sixteenths_to_beats(start),
+            pitch,
+            velocity,
+            duration: 1,
+        }));
+    }
+}
+
+fn groove_bar(rng: &mut NoiseRng, groove: &Groove, start: u32, hits: &mut Vec<(u32, i8, f32)>) {
+    for &step in &groove.kicks {
+        // Downbeats hit hardest
+        let velocity = if groove.accents.contains(&step) {
+            0.8
+        } else {
+            0.6
+        };
+        hits.push((start + step, KICK, velocity));
+    }
+    // Now and then a pickup kick into the next beat
+    if rng.sample_next() < 0.25 {
+        let bar = groove.bar;
+        hits.push((
+            start + pick(rng, &[bar / 2 - 1, bar - 2, bar - 1]),
+            KICK,
+            0.5,
+        ));
+    }
+
+    for &step in &groove.snares {
+        // The extra ones are ghost notes
+        let velocity = if groove.accents.contains(&step) {
+            0.7
+        } else {
+            0.3
+        };
+        hits.push((start + step, SNARE, velocity));
+    }
+
+    hats(groove, start, groove.bar, hits);
+}
+
+// The first half grooves as usual, then the snare takes over and builds up to the next bar
+fn fill_bar(rng: &mut NoiseRng, groove: &Groove, start: u32, hits: &mut Vec<(u32, i8, f32)>) {
+    let half = groove.bar / 2;
+    for &step in groove.kicks.iter().filter(|&&step| step < half) {
+        hits.push((start + step, KICK, 0.8));
+    }
+    // The backbeat, if it lands in the first half
+    if let Some(&step) = groove.snares.first().filter(|&&step| step < half) {
+        hits.push((start + step, SNARE, 0.7));
+    }
+    hats(groove, start, half, hits);
+
+    // Every eighth or every sixteenth
+    let spacing = pick(rng, &[1, 2]);
+    let steps: Vec<_> = (half..groove.bar).step_by(spacing).collect();
+    for (idx, &step) in steps.iter().enumerate() {
+        let velocity = 0.4 + 0.5 * idx as f32 / steps.len() as f32;
+        hits.push((start + step, SNARE, velocity));
+    }
+    hits.push((start + groove.bar - 2, KICK, 0.7));
+}
+
+fn hats(groove: &Groove, start: u32, until: u32, hits: &mut Vec<(u32, i8, f32)>) {
+    for step in (0..until).step_by(groove.hat_spacing as usize) {
+        let open = groove.open_hat && step == groove.bar - 2;
+        let (pitch, velocity) = match (open, groove.accents.contains(&step)) {
+            (true, _) => (OPEN_HAT, 0.45),
+            (false, true) => (CLOSED_HAT, 0.45),
+            _ => (CLOSED_HAT, 0.3),
+        };
+        hits.push((start + step, pitch, velocity));
+    }
+}
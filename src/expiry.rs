@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+
+// Timing wheel with one bucket per second, so cleanup only has to look at the clients that are due.
+// Entries are never updated in place when a client sends another heartbeat. Instead, whoever pops a key checks
+// the client's real last_seen and schedules it again if it's still alive, so every active client is visited about
+// once per liveness window no matter how often it sends heartbeats.
+#[derive(Debug)]
+pub struct ExpiryIndex<K> {
+    // buckets[i] holds the keys due at `start + i`
+    buckets: VecDeque<Vec<K>>,
+    start: u64,
+    // Everything due before this was popped already
+    next: u64,
+    len: usize,
+}
+
+impl<K> ExpiryIndex<K> {
+    pub fn new() -> Self {
+        Self {
+            buckets: VecDeque::new(),
+            start: 0,
+            next: 0,
+            len: 0,
+        }
+    }
+
+    pub fn schedule(&mut self, key: K, due: u64) {
+        // Anything already overdue is due at the next pop
+        let due = due.max(self.next);
+        if self.buckets.is_empty() {
+            self.start = due;
+        }
+
+        // Due before the first bucket, like when recovery schedules keys in whatever order it finds them in. Nothing
+        // is ever scheduled more than a liveness window ahead, so this never adds many buckets.
+        if due < self.start {
+            for _ in due..self.start {
+                self.buckets.push_front(Vec::new());
+            }
+            self.start = due;
+        }
+
+        let idx = (due - self.start) as usize;
+        if idx >= self.buckets.len() {
+            self.buckets.resize_with(idx + 1, Vec::new);
+        }
+
+        self.buckets[idx].push(key);
+        self.len += 1;
+    }
+
+    pub fn pop_due(&mut self, now: u64) -> Option<K> {
+        loop {
+            if self.start > now {
+                self.next = self.next.max(now + 1);
+                return None;
+            }
+
+            let Some(bucket) = self.buckets.front_mut() else {
+                self.next = self.next.max(now + 1);
+                return None;
+            };
+            if let Some(key) = bucket.pop() {
+                self.len -= 1;
+                return Some(key);
+            }
+
+            self.buckets.pop_front();
+            self.start += 1;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<K> Default for ExpiryIndex<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,290 @@
+use std::{
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use maxminddb::{MaxMindDbError, PathElement, Reader};
+use memmap2::Mmap;
+
+use crate::{WORLDWIDE, search_country};
+
+// A country a client can be in. AA is reserved and never a country, and XW is the worldwide total every client is
+// already counted towards, so a provider handing either out would count its clients twice.
+fn real_country(code: &str) -> Option<u8> {
+    search_country(code).filter(|&country| country != 0 && country != WORLDWIDE)
+}
+
+// Where a client's country comes from. A provider that doesn't know an address says so, and the next one gets asked.
+pub trait GeoProvider: Send + Sync {
+    fn lookup(&self, ip: IpAddr) -> Option<u8>;
+}
+
+#[derive(Debug)]
+pub enum GeoError {
+    MaxMind(PathBuf, MaxMindDbError),
+    Io(PathBuf, io::Error),
+    // Not a single line made sense. Number of the first, counting from 1.
+    Malformed(PathBuf, usize),
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MaxMind(path, err) => {
+                write!(
+                    f,
+                    "failed to open IP geo database {}: {err}",
+                    path.display()
+                )
+            }
+            Self::Io(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::Malformed(path, line) => {
+                write!(
+                    f,
+                    "{} has no IP ranges and countries, line {line} is the first that isn't one",
+                    path.display()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GeoError {}
+
+// Both families in one key space, IPv4 addresses as their IPv4-mapped IPv6 form
+fn range_key(ip: IpAddr) -> u128 {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().to_bits(),
+        IpAddr::V6(ip) => ip.to_bits(),
+    }
+}
+
+// A MaxMind country database, like GeoLite2-Country
+pub struct MaxMindDb(Reader<Mmap>);
+
+impl MaxMindDb {
+    pub fn open(path: &Path) -> Result<Self, GeoError> {
+        let db = unsafe { Reader::open_mmap(path) };
+        db.map(Self)
+            .map_err(|err| GeoError::MaxMind(path.to_owned(), err))
+    }
+}
+
+impl GeoProvider for MaxMindDb {
+    fn lookup(&self, ip: IpAddr) -> Option<u8> {
+        let res = self.0.lookup(ip).ok()?;
+        let code = res
+            .decode_path::<&str>(&[PathElement::Key("country"), PathElement::Key("iso_code")])
+            .ok()??;
+
+        real_country(code)
+    }
+}
+
+// Ranges of IPs and their countries from a CSV file, in the format of the free IP2Location and DB-IP lite databases:
+// first address, last address and country code, then whatever else. Addresses are either written out or decimal
+// numbers, and fields may be quoted.
+pub struct RangeTable {
+    ranges: Vec<(u128, u128, u8)>,
+    // How many lines didn't make sense, and the number of the first
+    skipped: Option<(usize, usize)>,
+}
+
+impl RangeTable {
+    pub fn load(path: &Path) -> Result<Self, GeoError> {
+        let csv = fs::read_to_string(path).map_err(|err| GeoError::Io(path.to_owned(), err))?;
+        let table = Self::parse(&csv).map_err(|line| GeoError::Malformed(path.to_owned(), line))?;
+
+        if let Some((count, first)) = table.skipped {
+            eprintln!(
+                "warning: skipped {count} lines of {} that aren't IP ranges and countries, the first is line {first}",
+                path.display()
+            );
+        }
+        Ok(table)
+    }
+
+    // Lines that don't make sense, like a header row, are skipped. Fails with the number of the first one if there's
+    // nothing but those.
+    pub fn parse(csv: &str) -> Result<Self, usize> {
+        let mut ranges = Vec::new();
+        let mut parsed = 0;
+        let mut skipped = None;
+
+        for (idx, line) in csv.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split(',').map(|field| field.trim().trim_matches('"'));
+            let first = fields.next().and_then(Self::parse_addr);
+            let last = fields.next().and_then(Self::parse_addr);
+            let (first, last, code) = match (first, last, fields.next()) {
+                (Some(first), Some(last), Some(code)) if first <= last => (first, last, code),
+                _ => {
+                    skipped.get_or_insert((0, idx + 1)).0 += 1;
+                    continue;
+                }
+            };
+            parsed += 1;
+
+            // "-" and anything else that's not a country we know stay unknown
+            if let Some(country) = real_country(&code.to_ascii_uppercase()) {
+                ranges.push((first, last, country));
+            }
+        }
+
+        if let (0, Some((_, first))) = (parsed, skipped) {
+            return Err(first);
+        }
+        ranges.sort_unstable_by_key(|&(first, ..)| first);
+        Ok(Self { ranges, skipped })
+    }
+
+    // How many lines were skipped for not making sense, and the number of the first
+    pub fn skipped(&self) -> Option<(usize, usize)> {
+        self.skipped
+    }
+
+    fn parse_addr(field: &str) -> Option<u128> {
+        if let Ok(ip) = field.parse::<IpAddr>() {
+            return Some(range_key(ip));
+        }
+
+        // IP2Location numbers IPv4 addresses as they are, and IPv6 ones with IPv4 in the mapped range already
+        let number = field.parse::<u128>().ok()?;
+        Some(match u32::try_from(number) {
+            Ok(v4) => range_key(IpAddr::V4(v4.into())),
+            Err(_) => number,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+impl GeoProvider for RangeTable {
+    fn lookup(&self, ip: IpAddr) -> Option<u8> {
+        let key = range_key(ip);
+        let idx = self.ranges.partition_point(|&(first, ..)| first <= key);
+        let &(_, last, country) = self.ranges.get(idx.checked_sub(1)?)?;
+
+        (key <= last).then_some(country)
+    }
+}
+
+#[derive(Debug)]
+pub struct BadOverride(pub String);
+
+impl fmt::Display for BadOverride {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid geo override {:?}, expected something like 10.1.0.0/16=DE",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for BadOverride {}
+
+// One subnet pinned to a country, written `10.1.0.0/16=DE`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Override {
+    net: u128,
+    // In the shared key space, so 96 more for IPv4 subnets
+    prefix_len: u32,
+    country: u8,
+}
+
+impl Override {
+    pub fn new(subnet: &str, country: &str) -> Result<Self, BadOverride> {
+        let bad = || BadOverride(format!("{subnet}={country}"));
+
+        let (addr, prefix_len) = match subnet.split_once('/') {
+            Some((addr, len)) => (addr, Some(len.parse::<u32>().map_err(|_| bad())?)),
+            None => (subnet, None),
+        };
+        let addr: IpAddr = addr.trim().parse().map_err(|_| bad())?;
+        let (bits, offset) = match addr {
+            IpAddr::V4(_) => (32, 96),
+            IpAddr::V6(_) => (128, 0),
+        };
+        let prefix_len = prefix_len.unwrap_or(bits);
+        if prefix_len > bits {
+            return Err(bad());
+        }
+
+        let prefix_len = prefix_len + offset;
+        Ok(Self {
+            net: range_key(addr) & Self::mask(prefix_len),
+            prefix_len,
+            country: real_country(&country.trim().to_ascii_uppercase()).ok_or_else(bad)?,
+        })
+    }
+
+    fn mask(prefix_len: u32) -> u128 {
+        u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0)
+    }
+
+    fn contains(&self, key: u128) -> bool {
+        key & Self::mask(self.prefix_len) == self.net
+    }
+}
+
+impl FromStr for Override {
+    type Err = BadOverride;
+
+    fn from_str(s: &str) -> Result<Self, BadOverride> {
+        let (subnet, country) = s.split_once('=').ok_or_else(|| BadOverride(s.to_owned()))?;
+        Self::new(subnet, country)
+    }
+}
+
+// Countries for subnets no database can know about, like an office's private ranges. The most specific subnet wins.
+pub struct Overrides(Vec<Override>);
+
+impl Overrides {
+    pub fn new(mut overrides: Vec<Override>) -> Self {
+        overrides.sort_by_key(|entry| std::cmp::Reverse(entry.prefix_len));
+        Self(overrides)
+    }
+}
+
+impl GeoProvider for Overrides {
+    fn lookup(&self, ip: IpAddr) -> Option<u8> {
+        let key = range_key(ip);
+        self.0
+            .iter()
+            .find(|entry| entry.contains(key))
+            .map(|entry| entry.country)
+    }
+}
+
+// Asks every provider in turn until one knows the address. With none at all, every client stays unknown.
+#[derive(Default)]
+pub struct GeoChain(Vec<Box<dyn GeoProvider>>);
+
+impl GeoChain {
+    pub fn push(&mut self, provider: impl GeoProvider + 'static) {
+        self.0.push(Box::new(provider));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl GeoProvider for GeoChain {
+    fn lookup(&self, ip: IpAddr) -> Option<u8> {
+        self.0.iter().find_map(|provider| provider.lookup(ip))
+    }
+}
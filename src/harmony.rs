@@ -0,0 +1,447 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drums::Drummer,
+    meter::{Meter, sixteenths_to_beats},
+    notes::{MarkovDistribution, NoiseRng, Note, NoteGenerator, Scale, TONIC, key_for_seed},
+    stream::{StateError, StreamState},
+};
+
+// Another keyboard mash, so the chords don't just follow the melody's numbers
+const HARMONY_NOISE: i32 = 918273645;
+
+const DEGREES: [usize; 7] = [0, 1, 2, 3, 4, 5, 6];
+// Chord roots as degrees of a 7 note scale, I ii iii IV V vi vii
+const CHORD_MARKOV: [[f32; 7]; 7] = [
+    [0.05, 0.15, 0.05, 0.30, 0.30, 0.15, 0.00],
+    [0.05, 0.05, 0.00, 0.10, 0.60, 0.10, 0.10],
+    [0.05, 0.05, 0.05, 0.25, 0.05, 0.55, 0.00],
+    [0.30, 0.15, 0.00, 0.05, 0.40, 0.05, 0.05],
+    [0.60, 0.00, 0.05, 0.05, 0.05, 0.25, 0.00],
+    [0.10, 0.35, 0.05, 0.30, 0.20, 0.00, 0.00],
+    [0.80, 0.00, 0.10, 0.00, 0.10, 0.00, 0.00],
+]; // Roughly common practice: away from the tonic, through the subdominant and dominant and back home
+// Chords of the new key to try as the pivot into it, the ones leading on to its dominant first: IV ii vi I V iii vii
+const PIVOTS: [usize; 7] = [3, 1, 5, 0, 4, 2, 6];
+// When the keys have no chord in common the new key's dominant announces it instead
+const DOMINANT: usize = 4;
+// Where those roots are in a major scale, for finding the nearest thing in scales that don't have 7 notes
+const MAJOR_ROOTS: [i8; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+const BASS_VELOCITY: f32 = 0.45;
+// Per note of the chord
+const PAD_VELOCITY: f32 = 0.15;
+const ARPEGGIO_VELOCITY: f32 = 0.2;
+// Eighth notes
+const ARPEGGIO_STEP: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Voice {
+    Bass,
+    Pad,
+    Arpeggio,
+    // Pitches are General MIDI percussion keys
+    Drums,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrackNote {
+    // Beats since the first note of the stream
+    pub start: f32,
+    pub pitch: i8,
+    pub velocity: f32,
+    // Sixteenths
+    pub duration: u8,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Track {
+    pub voice: Voice,
+    pub notes: Vec<TrackNote>,
+}
+
+// A triad, in semitones above the tonic without the key offset
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chord {
+    pub tones: [i8; 3],
+}
+
+impl Chord {
+    // The same chord, as seen from a tonic `semitones` lower
+    fn shifted(&self, semitones: i8) -> Self {
+        let [root, third, fifth] = self.tones;
+        let shifted = (root + semitones).rem_euclid(12);
+        Self {
+            tones: [shifted, shifted + third - root, shifted + fifth - root],
+        }
+    }
+}
+
+// A key change waiting for the next bar line, which gets a chord from both keys before the new one takes over
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub(crate) struct Modulation {
+    // Key offset to end up in
+    to: i8,
+    // Degree of the pivot chord in the new key, once it's played
+    pivot: Option<usize>,
+}
+
+// Chord progressions in the key the melody is in
+pub struct Harmony {
+    rng: NoiseRng,
+    roots: MarkovDistribution<usize>,
+    scale: Scale,
+    started: bool,
+    modulation: Option<Modulation>,
+}
+
+impl Harmony {
+    pub fn new(start: u32, seed: i32, scale: Scale) -> Self {
+        let mut roots = MarkovDistribution::new(DEGREES, CHORD_MARKOV);
+        roots.start_at(0);
+
+        Self {
+            rng: NoiseRng::with_stream(start, seed, HARMONY_NOISE),
+            roots,
+            scale,
+            started: false,
+            modulation: None,
+        }
+    }
+
+    pub(crate) fn state(&self) -> HarmonyState {
+        HarmonyState {
+            rng: self.rng.clone(),
+            roots: self.roots.history(),
+            started: self.started,
+            modulation: self.modulation,
+        }
+    }
+
+    pub fn next_chord(&mut self) -> Chord {
+        // Every progression starts from home
+        let root = if self.started {
+            self.roots.sample(&mut self.rng)
+        } else {
+            self.started = true;
+            0
+        };
+        self.chord_on(root)
+    }
+
+    // Moves to the key `to` semitones from the tonic, a bar after the next bar line
+    pub fn modulate(&mut self, to: i8) {
+        if self.modulation.is_none_or(|modulation| modulation.to != to) {
+            self.modulation = Some(Modulation { to, pivot: None });
+        }
+    }
+
+    // The chord for the next bar in `key`, and the key the bar is in, which is a new one the bar after a pivot
+    pub fn next_bar(&mut self, key: i8) -> (Chord, i8) {
+        match self.modulation.take() {
+            Some(Modulation { to, .. }) if to == key => (self.next_chord(), key),
+            Some(Modulation { to, pivot: None }) => {
+                let pivot = self.pivot(key, to);
+                self.modulation = Some(Modulation {
+                    to,
+                    pivot: Some(pivot),
+                });
+                (self.chord_on(pivot).shifted(to - key), key)
+            }
+            // The progression carries on from the pivot, as if it had always been in the new key
+            Some(Modulation {
+                to,
+                pivot: Some(pivot),
+            }) => {
+                self.roots.start_at(pivot);
+                self.started = true;
+                (self.next_chord(), to)
+            }
+            None => (self.next_chord(), key),
+        }
+    }
+
+    // A chord of the new key made only of notes the old one has, or its dominant if there's none
+    fn pivot(&self, from: i8, to: i8) -> usize {
+        let intervals = self.scale.intervals();
+        let in_old_key = |tone: i8| intervals.contains(&(tone + to - from).rem_euclid(12));
+
+        PIVOTS
+            .into_iter()
+            .find(|&degree| self.chord_on(degree).tones.into_iter().all(in_old_key))
+            .unwrap_or(DOMINANT)
+    }
+
+    fn chord_on(&self, root: usize) -> Chord {
+        let intervals = self.scale.intervals();
+
+        // Stacked thirds are every other note of a 7 note scale, other scales get the nearest root and stack the
+        // same way, which is as close as they come
+        let root = if intervals.len() == DEGREES.len() {
+            root
+        } else {
+            nearest_degree(intervals, MAJOR_ROOTS[root])
+        };
+
+        let tones = [0, 2, 4].map(|step| {
+            let idx = root + step;
+            intervals[idx % intervals.len()] + 12 * (idx / intervals.len()) as i8
+        });
+        Chord { tones }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct HarmonyState {
+    rng: NoiseRng,
+    roots: Vec<usize>,
+    started: bool,
+    modulation: Option<Modulation>,
+}
+
+fn nearest_degree(intervals: &[i8], semitones: i8) -> usize {
+    (0..intervals.len())
+        .min_by_key(|&degree| (intervals[degree] - semitones).abs())
+        .unwrap()
+}
+
+// A melody with the chords under it and the voices that play them
+#[derive(Clone, Debug)]
+pub struct Arrangement {
+    pub meter: Meter,
+    // Beats into the stream where the melody picks up, the accompaniment starts at the first bar line from there
+    pub start: f32,
+    pub melody: Vec<Note>,
+    // One for every bar that starts during the melody
+    pub chords: Vec<Chord>,
+    // Every bar is played out to its end, even when the melody stops partway through
+    pub tracks: Vec<Track>,
+    // Beats until the melody is over
+    pub length: f32,
+}
+
+// Lays out a pattern of (pitch, duration) from `start` sixteenths on
+pub(crate) fn push_pattern(
+    notes: &mut Vec<TrackNote>,
+    mut start: u32,
+    pattern: &[(i8, u8)],
+    velocity: f32,
+) {
+    for &(pitch, duration) in pattern {
+        notes.push(TrackNote {
+            start: sixteenths_to_beats(start),
+            pitch,
+            velocity,
+            duration,
+        });
+        start += duration as u32;
+    }
+}
+
+pub struct Arranger {
+    melody: NoteGenerator,
+    harmony: Harmony,
+    drummer: Drummer,
+    rng: NoiseRng,
+}
+
+impl Arranger {
+    pub fn new(melody: NoteGenerator, start: u32, seed: i32, scale: Scale) -> Self {
+        let time_signature = melody.meter().time_signature;
+
+        Self {
+            melody,
+            harmony: Harmony::new(start, seed, scale),
+            drummer: Drummer::new(start, seed, time_signature),
+            // Variations in how the voices play, separate from the chords themselves
+            rng: NoiseRng::with_stream(start, seed, HARMONY_NOISE.wrapping_add(1)),
+        }
+    }
+
+    pub fn state(&self) -> StreamState {
+        StreamState {
+            melody: self.melody.state(),
+            harmony: self.harmony.state(),
+            voicing: self.rng.clone(),
+            drums: self.drummer.state(),
+        }
+    }
+
+    // Carries on from the state another arranger with the same scale and model saved, as if it had played the chunks
+    // in between itself. Leaves this one as it was if the state doesn't fit.
+    pub fn resume(&mut self, state: &StreamState) -> Result<(), StateError> {
+        let roots = self
+            .harmony
+            .roots
+            .check_history(&state.harmony.roots)
+            .ok_or(StateError::Mismatched("chords"))?;
+        if matches!(state.harmony.modulation, Some(Modulation { to, pivot })
+            if !(-12..=12).contains(&to) || pivot.is_some_and(|pivot| pivot >= DEGREES.len()))
+        {
+            return Err(StateError::Mismatched("modulation"));
+        }
+        if !self.harmony.rng.fits(&state.harmony.rng) {
+            return Err(StateError::Mismatched("chord noise"));
+        }
+        if !self.rng.fits(&state.voicing) {
+            return Err(StateError::Mismatched("voicing"));
+        }
+        if !self.drummer.fits(&state.drums) {
+            return Err(StateError::Mismatched("drums"));
+        }
+        self.melody.resume(&state.melody)?;
+
+        self.harmony.rng = state.harmony.rng.clone();
+        self.harmony.roots.restore(roots);
+        self.harmony.started = state.harmony.started;
+        self.harmony.modulation = state.harmony.modulation;
+        self.rng = state.voicing.clone();
+        self.drummer = Drummer::resume(&state.drums, self.melody.meter().time_signature);
+        Ok(())
+    }
+
+    // The rest of the stream comes from another seed's noise
+    pub fn reseed(&mut self, seed: i32) {
+        self.melody.reseed(seed);
+        self.harmony.rng.reseed(seed);
+        self.rng.reseed(seed);
+        self.drummer.reseed(seed);
+        self.harmony.modulation = None;
+    }
+
+    // Eases into another seed over the next `notes` melody notes, the accompaniment following along bar by bar. When
+    // that seed's key is a different one, the music modulates to it through a pivot chord.
+    pub fn crossfade(&mut self, seed: i32, notes: u32) {
+        if notes == 0 {
+            return self.reseed(seed);
+        }
+
+        // The melody might still be fading into the last one, the rest catch up with it at the next bar line
+        self.melody.crossfade(seed, notes);
+    }
+
+    // The next `count` melody notes, with a new chord every bar. Calling it again carries on from there.
+    pub fn arrange(&mut self, count: usize) -> Arrangement {
+        let meter = self.melody.meter();
+        let bar = meter.bar_length();
+        let begin = self.melody.position();
+        let mut melody = Vec::with_capacity(count);
+        let mut chords = Vec::new();
+        // The key, seed and how far into a crossfade every bar is
+        let mut bars = Vec::new();
+
+        for _ in 0..count {
+            // Notes never run over a bar line, so every bar starts with one
+            if self.melody.position().is_multiple_of(bar) {
+                let seed = self.melody.seed();
+                if seed != self.harmony.rng.seed() {
+                    self.harmony.rng.crossfade(seed);
+                    self.harmony.modulate(key_for_seed(seed));
+                }
+                let fade = self.melody.fade();
+                self.harmony.rng.set_fade(fade);
+
+                let key = self.melody.key_offset();
+                let (chord, bar_key) = self.harmony.next_bar(key);
+                self.melody.transpose(bar_key - key);
+                self.melody.set_chord(&chord.tones);
+                chords.push(chord);
+                bars.push((TONIC + bar_key, seed, fade));
+            }
+
+            melody.push(self.melody.next().expect("melodies never end"));
+        }
+
+        let mut tracks =
+            [Voice::Bass, Voice::Pad, Voice::Arpeggio, Voice::Drums].map(|voice| Track {
+                voice,
+                notes: Vec::new(),
+            });
+        // Bar by bar, so the voices come out the same however the stream is split into chunks
+        for (idx, (chord, &(key, seed, fade))) in chords.iter().zip(&bars).enumerate() {
+            let start = (begin.div_ceil(bar) + idx as u32) * bar;
+            let [bass, pad, arpeggio, drums] = &mut tracks;
+            if seed != self.rng.seed() {
+                self.rng.crossfade(seed);
+                self.drummer.crossfade(seed);
+            }
+            self.rng.set_fade(fade);
+            self.drummer.set_fade(fade);
+
+            self.bass(&mut bass.notes, chord, key, meter, start);
+            pad_bar(&mut pad.notes, chord, key, meter, start);
+            self.arpeggio(&mut arpeggio.notes, chord, key, meter, start);
+            self.drummer.play_bar(&mut drums.notes, start / bar, start);
+        }
+
+        Arrangement {
+            meter,
+            start: sixteenths_to_beats(begin),
+            melody,
+            chords,
+            tracks: tracks.into(),
+            length: sixteenths_to_beats(self.melody.position() - begin),
+        }
+    }
+
+    fn bass(
+        &mut self,
+        notes: &mut Vec<TrackNote>,
+        chord: &Chord,
+        key: i8,
+        meter: Meter,
+        start: u32,
+    ) {
+        let bar = meter.bar_length() as u8;
+        let root = key - 24 + chord.tones[0];
+        let fifth = key - 24 + chord.tones[2];
+
+        // Held, root and fifth, or pulsing on every beat
+        let pattern: Vec<_> = match (self.rng.sample_next() * 3.) as u8 {
+            0 => vec![(root, bar)],
+            1 => vec![(root, bar / 2), (fifth, bar - bar / 2)],
+            _ => meter
+                .time_signature
+                .groups()
+                .iter()
+                .map(|&group| (root, group as u8))
+                .collect(),
+        };
+        push_pattern(notes, start, &pattern, BASS_VELOCITY);
+    }
+
+    fn arpeggio(
+        &mut self,
+        notes: &mut Vec<TrackNote>,
+        chord: &Chord,
+        key: i8,
+        meter: Meter,
+        start: u32,
+    ) {
+        let steps = meter.bar_length() / ARPEGGIO_STEP as u32;
+        let [root, third, fifth] = chord.tones.map(|tone| key - 12 + tone);
+        let up = [root, third, fifth, root + 12];
+        let down = [root + 12, fifth, third, root];
+        let shape = if self.rng.sample_next() < 0.5 {
+            up
+        } else {
+            down
+        };
+
+        let pattern: Vec<_> = shape
+            .iter()
+            .cycle()
+            .take(steps as usize)
+            .map(|&pitch| (pitch, ARPEGGIO_STEP))
+            .collect();
+        push_pattern(notes, start, &pattern, ARPEGGIO_VELOCITY);
+    }
+}
+
+fn pad_bar(notes: &mut Vec<TrackNote>, chord: &Chord, key: i8, meter: Meter, start: u32) {
+    let bar = meter.bar_length() as u8;
+    for tone in chord.tones {
+        push_pattern(notes, start, &[(key - 12 + tone, bar)], PAD_VELOCITY);
+    }
+}
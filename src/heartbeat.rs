@@ -0,0 +1,125 @@
+use std::net::IpAddr;
+
+use crate::{
+    StoredIpStats,
+    store::{StatsStore, contributor_id, shard_of},
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub burst: u16,
+    pub per_sec: u16,
+}
+
+// Where a heartbeat came from
+#[derive(Clone, Copy, Debug)]
+pub struct Source {
+    pub ip: IpAddr,
+    // Whether it came with a cookie the server handed out to that address
+    pub verified: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accepted,
+    RateLimited,
+    // Didn't prove it can receive replies at its address. Nothing was stored or counted for it, beyond the rate limit
+    // of a network that's already known.
+    Unverified,
+    // Reserved address or no room left in the store, there's no record to rate limit it with
+    Untracked,
+}
+
+// Refills the record's token bucket and takes a token out of it, returns false if there was none left
+pub fn take_token(record: &mut StoredIpStats, now: u64, limit: RateLimit) -> bool {
+    let now = now as u32;
+    let elapsed = now.wrapping_sub(record.refilled_at);
+
+    if elapsed > 0 {
+        let refilled = record.tokens as u64 + elapsed as u64 * limit.per_sec as u64;
+        record.tokens = refilled.min(limit.burst as u64) as u16;
+        record.refilled_at = now;
+    }
+
+    if record.tokens == 0 {
+        return false;
+    }
+
+    record.tokens -= 1;
+    true
+}
+
+// `locate` is only asked for the country of clients that were never seen before. Only verified heartbeats create
+// records, keep clients active or contribute seeds, so spoofed source addresses can neither fill up the store nor
+// vote as networks it already knows.
+pub fn register_heartbeat(
+    store: &impl StatsStore,
+    source: Source,
+    seed: i32,
+    now: u64,
+    liveness_window: u64,
+    limit: RateLimit,
+    locate: impl FnOnce(IpAddr) -> Option<u8>,
+) -> Verdict {
+    let Source {
+        ip: client_ip,
+        verified,
+    } = source;
+    if shard_of(client_ip).is_none() {
+        return Verdict::Untracked;
+    }
+
+    let verdict = store.with_record(client_ip, verified, |record| {
+        let bucket_info = &mut *record.stats;
+        let pending = &mut *record.pending;
+
+        if !take_token(bucket_info, now, limit) {
+            return Verdict::RateLimited;
+        }
+        if !verified {
+            return Verdict::Unverified;
+        }
+
+        let activated = if bucket_info.first_seen == 0 && bucket_info.last_seen == 0 {
+            bucket_info.first_seen = now;
+            bucket_info.last_seen = now;
+
+            let country = locate(client_ip).unwrap_or_default();
+            bucket_info.country = country;
+
+            pending.activate(country, true);
+            true
+        } else if bucket_info.first_seen != 0 && bucket_info.last_seen == 0 {
+            bucket_info.first_seen = now;
+            bucket_info.last_seen = now;
+
+            pending.activate(bucket_info.country, false);
+            true
+        } else {
+            false
+        };
+
+        if now - bucket_info.last_seen > liveness_window {
+            let diff = (now - bucket_info.last_seen) as u32;
+            bucket_info.cum_duration += diff;
+
+            bucket_info.last_seen = now;
+            bucket_info.hits += 1;
+
+            let contributor = contributor_id(client_ip);
+            pending.contribute(bucket_info.country, contributor, seed, diff);
+        }
+
+        if activated {
+            record.schedule_expiry(now + liveness_window + 1);
+        }
+
+        Verdict::Accepted
+    });
+
+    match verdict {
+        Some(verdict) => verdict,
+        None if verified => Verdict::Untracked,
+        None => Verdict::Unverified,
+    }
+}
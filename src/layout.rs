@@ -0,0 +1,520 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions, TryLockError},
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytemuck::{Pod, Zeroable, bytes_of, pod_read_unaligned};
+
+use crate::{
+    COUNTRIES, StoredCountryStats, StoredIpStats, StoredIpv6Stats,
+    store::{SHARDS, prefix_hash},
+};
+
+// The IPv4 database has a record for every /24 at `bucket * RECORD_SIZE`. Nothing in 0.0.0.0/8 is ever a client, so
+// that part of the file holds a header describing the layout and the country table instead.
+//
+// Files from before the header are version 0. Opening an older file runs every migration from its version up, a
+// change that needs more room per client can take it out of `_reserved` in place or rewrite the records bigger.
+
+pub const MAGIC: [u8; 8] = *b"MBIPV4DB";
+// Bump and add a migration whenever the header, `StoredIpStats` or `StoredCountryStats` change
+pub const FORMAT_VERSION: u32 = 2;
+
+pub const RECORD_SIZE: usize = size_of::<StoredIpStats>();
+pub const COUNTRY_SIZE: usize = size_of::<StoredCountryStats>();
+// Clear of where the headerless country table was, and a multiple of every page size it's mapped with
+pub const COUNTRY_TABLE_OFFSET: u64 = 1 << 16;
+pub const FILE_LEN: u64 = (1 << 24) * RECORD_SIZE as u64;
+
+// The IPv6 database is an open-addressed hash table of /64 prefixes with linear probing, split into one partition per
+// shard. 4M slots of 40 bytes, only the touched pages of the sparse file take up disk space.
+pub const IPV6_SLOTS_LOG2: u32 = 22;
+pub const IPV6_SLOTS_PER_SHARD: usize = (1 << IPV6_SLOTS_LOG2) / SHARDS;
+pub const IPV6_SLOT_SIZE: usize = size_of::<StoredIpv6Stats>();
+pub const IPV6_FILE_LEN: u64 = ((1 << IPV6_SLOTS_LOG2) * IPV6_SLOT_SIZE) as u64;
+// How far to probe before giving up on finding a free slot for a new prefix
+pub const IPV6_MAX_PROBE: usize = 64;
+// Left behind in the slot of a prefix that expired, so lookups keep probing past it and it can be claimed again.
+// ffff:ffff:ffff:ffff::/64 is multicast, never a client.
+pub const TOMBSTONE: u64 = u64::MAX;
+
+pub const SNAPSHOT_MAGIC: [u8; 8] = *b"MBSNAPSH";
+
+// Migrations[n] takes a file from version n to n + 1
+const MIGRATIONS: [fn(&File) -> io::Result<()>; FORMAT_VERSION as usize] =
+    [add_header, add_forgotten];
+
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct StoredHeader {
+    pub magic: [u8; 8],
+    pub version: u32,
+    pub country_table_offset: u32,
+    pub record_size: u32,
+    pub country_size: u32,
+    // Unix timestamp of when the file was created, or given a header
+    pub created_at: u64,
+    pub _reserved: [u8; 32],
+}
+
+impl StoredHeader {
+    fn new(version: u32) -> Self {
+        Self {
+            magic: MAGIC,
+            version,
+            country_table_offset: COUNTRY_TABLE_OFFSET as u32,
+            record_size: RECORD_SIZE as u32,
+            country_size: COUNTRY_SIZE as u32,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            _reserved: [0; 32],
+        }
+    }
+}
+
+// One partition of the IPv6 table. Slots are empty (prefix 0), taken, or a tombstone. Prefixes never move once they
+// have a slot, so slot numbers stay valid for as long as the prefix is there.
+pub struct Ipv6Slots<'a>(pub &'a mut [StoredIpv6Stats]);
+
+impl<'a> Ipv6Slots<'a> {
+    // Where probing for `prefix` starts, the partition's length has to be a power of two
+    pub fn home(&self, prefix: u64) -> usize {
+        (prefix_hash(prefix) >> (64 - IPV6_SLOTS_LOG2)) as usize & (self.0.len() - 1)
+    }
+
+    fn probe(&self, prefix: u64) -> impl Iterator<Item = usize> + use<> {
+        let (start, mask) = (self.home(prefix), self.0.len() - 1);
+        (0..IPV6_MAX_PROBE.min(self.0.len())).map(move |probe| (start + probe) & mask)
+    }
+
+    pub fn find(&self, prefix: u64) -> Option<u32> {
+        for idx in self.probe(prefix) {
+            match self.0[idx].prefix {
+                found if found == prefix => return Some(idx as u32),
+                0 => return None,
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // The prefix's slot, taking the first free one if it doesn't have one yet. None if the whole neighbourhood is
+    // taken.
+    pub fn claim(&mut self, prefix: u64) -> Option<u32> {
+        let mut free = None;
+        for idx in self.probe(prefix) {
+            match self.0[idx].prefix {
+                found if found == prefix => return Some(idx as u32),
+                0 => {
+                    free = free.or(Some(idx));
+                    break;
+                }
+                TOMBSTONE => free = free.or(Some(idx)),
+                _ => {}
+            }
+        }
+
+        let idx = free?;
+        self.0[idx] = StoredIpv6Stats {
+            prefix,
+            ..Default::default()
+        };
+        Some(idx as u32)
+    }
+
+    pub fn remove(&mut self, slot: u32) {
+        self.0[slot as usize] = StoredIpv6Stats {
+            prefix: TOMBSTONE,
+            ..Default::default()
+        };
+    }
+
+    // The record in a slot, unless the slot is free
+    pub fn record(self, slot: u32) -> Option<&'a mut StoredIpStats> {
+        let slot = &mut self.0[slot as usize];
+        (!matches!(slot.prefix, 0 | TOMBSTONE)).then_some(&mut slot.stats)
+    }
+
+    // Slots that hold a prefix
+    pub fn taken(self) -> impl Iterator<Item = (u32, &'a mut StoredIpv6Stats)> {
+        self.0
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, slot)| !matches!(slot.prefix, 0 | TOMBSTONE))
+            .map(|(idx, slot)| (idx as u32, slot))
+    }
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(PathBuf, io::Error),
+    // Another process has it open, most likely a running server
+    InUse(PathBuf),
+    // Neither has a header nor is the size of a database from before headers
+    Unrecognized(PathBuf),
+    // Written by a newer server
+    TooNew(PathBuf, u32),
+    // Written by an older server, and opened somewhere that doesn't upgrade it
+    Outdated(PathBuf, u32),
+    // The header describes a layout this build doesn't use
+    Mismatched {
+        path: PathBuf,
+        field: &'static str,
+        found: u32,
+        expected: u32,
+    },
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, err) => {
+                write!(f, "failed to open ip database {}: {err}", path.display())
+            }
+            Self::InUse(path) => write!(
+                f,
+                "{} is in use, is a server still running on it?",
+                path.display()
+            ),
+            Self::Unrecognized(path) => write!(
+                f,
+                "{} isn't an ip database, refusing to touch it",
+                path.display()
+            ),
+            Self::TooNew(path, version) => write!(
+                f,
+                "{} is format version {version}, this server only knows up to {FORMAT_VERSION}",
+                path.display()
+            ),
+            Self::Outdated(path, version) => write!(
+                f,
+                "{} is format version {version}, start the server on it once to upgrade it to {FORMAT_VERSION}",
+                path.display()
+            ),
+            Self::Mismatched {
+                path,
+                field,
+                found,
+                expected,
+            } => write!(
+                f,
+                "{} has a {field} of {found}, this server expects {expected}",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+pub fn read_header(file: &File) -> io::Result<Option<StoredHeader>> {
+    let mut bytes = [0; size_of::<StoredHeader>()];
+    match file.read_exact_at(&mut bytes, 0) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let header: StoredHeader = pod_read_unaligned(&bytes);
+    Ok((header.magic == MAGIC).then_some(header))
+}
+
+// Opens the IPv4 database, creating it if there's none or bringing an older one up to date first. The file stays
+// locked until it's closed, so only one process ever writes to it.
+pub fn open_v4(path: &Path) -> Result<File, LayoutError> {
+    let io_error = |err| LayoutError::Io(path.to_owned(), err);
+
+    let file = OpenOptions::new()
+        .write(true)
+        .read(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(io_error)?;
+    lock(&file, path)?;
+    let len = file.metadata().map_err(io_error)?.len();
+
+    let mut version = if len == 0 {
+        file.set_len(FILE_LEN).map_err(io_error)?;
+        file.write_all_at(bytes_of(&StoredHeader::new(FORMAT_VERSION)), 0)
+            .map_err(io_error)?;
+        FORMAT_VERSION
+    } else {
+        match read_header(&file).map_err(io_error)? {
+            Some(header) => header.version,
+            None if len == FILE_LEN => 0,
+            None => return Err(LayoutError::Unrecognized(path.to_owned())),
+        }
+    };
+
+    if version > FORMAT_VERSION {
+        return Err(LayoutError::TooNew(path.to_owned(), version));
+    }
+    while version < FORMAT_VERSION {
+        MIGRATIONS[version as usize](&file).map_err(io_error)?;
+        version += 1;
+        eprintln!("upgraded {} to format version {version}", path.display());
+    }
+
+    let header = read_header(&file)
+        .map_err(io_error)?
+        .ok_or_else(|| LayoutError::Unrecognized(path.to_owned()))?;
+    check(&header, path)?;
+
+    if len < FILE_LEN {
+        file.set_len(FILE_LEN).map_err(io_error)?;
+    }
+    Ok(file)
+}
+
+// Opens an IPv4 database that's already there, for tools that shouldn't leave one behind where there was none. Only
+// writable when asked to be, and never upgraded, so one an older server wrote is refused.
+pub fn open_v4_existing(path: &Path, writable: bool) -> Result<File, LayoutError> {
+    let io_error = |err| LayoutError::Io(path.to_owned(), err);
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .map_err(io_error)?;
+    lock(&file, path)?;
+    let len = file.metadata().map_err(io_error)?.len();
+
+    let header = match read_header(&file).map_err(io_error)? {
+        Some(header) => header,
+        None if len == FILE_LEN => return Err(LayoutError::Outdated(path.to_owned(), 0)),
+        None => return Err(LayoutError::Unrecognized(path.to_owned())),
+    };
+    if header.version > FORMAT_VERSION {
+        return Err(LayoutError::TooNew(path.to_owned(), header.version));
+    }
+    if header.version < FORMAT_VERSION {
+        return Err(LayoutError::Outdated(path.to_owned(), header.version));
+    }
+    check(&header, path)?;
+
+    // Mapping past the end of it would crash on the first read
+    if len < FILE_LEN {
+        return Err(LayoutError::Unrecognized(path.to_owned()));
+    }
+    Ok(file)
+}
+
+// Keeps every other process off the file at `path` until it's closed
+pub fn lock(file: &File, path: &Path) -> Result<(), LayoutError> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(LayoutError::InUse(path.to_owned())),
+        Err(TryLockError::Error(err)) => Err(LayoutError::Io(path.to_owned(), err)),
+    }
+}
+
+fn check(header: &StoredHeader, path: &Path) -> Result<(), LayoutError> {
+    for (field, found, expected) in [
+        (
+            "country table offset",
+            header.country_table_offset,
+            COUNTRY_TABLE_OFFSET as u32,
+        ),
+        ("record size", header.record_size, RECORD_SIZE as u32),
+        (
+            "country record size",
+            header.country_size,
+            COUNTRY_SIZE as u32,
+        ),
+    ] {
+        if found != expected {
+            return Err(LayoutError::Mismatched {
+                path: path.to_owned(),
+                field,
+                found,
+                expected,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Version 0 had no header and the country table right at the start
+fn add_header(file: &File) -> io::Result<()> {
+    let mut table = vec![0; COUNTRIES.len() * COUNTRY_SIZE];
+    file.read_exact_at(&mut table, 0)?;
+    file.write_all_at(&table, COUNTRY_TABLE_OFFSET)?;
+    // Only once the table is safely in its new place, a crash before that leaves a file that still looks headerless
+    // and gets migrated again
+    file.sync_data()?;
+
+    let header = StoredHeader::new(1);
+    file.write_all_at(bytes_of(&header), 0)?;
+    // What's left of the old table
+    let header_len = size_of::<StoredHeader>();
+    file.write_all_at(&vec![0; table.len() - header_len], header_len as u64)?;
+    file.sync_data()
+}
+
+// Version 2 keeps a tally of forgotten clients in what was reserved space, so far always zeroes, meaning none
+fn add_forgotten(file: &File) -> io::Result<()> {
+    file.write_all_at(bytes_of(&StoredHeader::new(2)), 0)?;
+    file.sync_data()
+}
+
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+struct SnapshotHeader {
+    magic: [u8; 8],
+    // Format version of the databases the records came from, they're copied as they are
+    version: u32,
+    countries: u32,
+    v4_records: u32,
+    v6_records: u32,
+    created_at: u64,
+}
+
+// A compact copy of both databases with just the records that were ever used
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub created_at: u64,
+    pub countries: Vec<StoredCountryStats>,
+    // /24 bucket and its record
+    pub v4: Vec<(u32, StoredIpStats)>,
+    // Prefixes find their slot again when they're restored
+    pub v6: Vec<StoredIpv6Stats>,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(PathBuf, io::Error),
+    // Cut short, corrupted, or from a different format version
+    Malformed(PathBuf),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, err) => write!(f, "failed to access snapshot {}: {err}", path.display()),
+            Self::Malformed(path) => write!(f, "{} isn't a usable snapshot", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// FNV-1a, only there to catch files that got cut short or damaged
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xCBF2_9CE4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (head, rest) = bytes.split_at_checked(len)?;
+    *bytes = rest;
+    Some(head)
+}
+
+// A bucket followed by its record, both unaligned
+fn take_keyed<T: Pod>(bytes: &mut &[u8], count: u32) -> Option<Vec<(u32, T)>> {
+    (0..count)
+        .map(|_| {
+            let key = pod_read_unaligned(take(bytes, size_of::<u32>())?);
+            let record = pod_read_unaligned(take(bytes, size_of::<T>())?);
+            Some((key, record))
+        })
+        .collect()
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let header = SnapshotHeader {
+            magic: SNAPSHOT_MAGIC,
+            version: FORMAT_VERSION,
+            countries: self.countries.len() as u32,
+            v4_records: self.v4.len() as u32,
+            v6_records: self.v6.len() as u32,
+            created_at: self.created_at,
+        };
+
+        let mut bytes = bytes_of(&header).to_vec();
+        for country in &self.countries {
+            bytes.extend_from_slice(bytes_of(country));
+        }
+        for (bucket, record) in &self.v4 {
+            bytes.extend_from_slice(&bucket.to_ne_bytes());
+            bytes.extend_from_slice(bytes_of(record));
+        }
+        for record in &self.v6 {
+            bytes.extend_from_slice(bytes_of(record));
+        }
+        bytes.extend_from_slice(&checksum(&bytes).to_ne_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let (mut body, sum) = bytes.split_last_chunk::<8>()?;
+        if checksum(body) != u64::from_ne_bytes(*sum) {
+            return None;
+        }
+
+        let header: SnapshotHeader =
+            pod_read_unaligned(take(&mut body, size_of::<SnapshotHeader>())?);
+        if header.magic != SNAPSHOT_MAGIC || header.version != FORMAT_VERSION {
+            return None;
+        }
+
+        let countries = (0..header.countries)
+            .map(|_| Some(pod_read_unaligned(take(&mut body, COUNTRY_SIZE)?)))
+            .collect::<Option<_>>()?;
+        let v4 = take_keyed(&mut body, header.v4_records)?;
+        let v6 = (0..header.v6_records)
+            .map(|_| {
+                Some(pod_read_unaligned(take(
+                    &mut body,
+                    size_of::<StoredIpv6Stats>(),
+                )?))
+            })
+            .collect::<Option<_>>()?;
+
+        body.is_empty().then_some(Self {
+            created_at: header.created_at,
+            countries,
+            v4,
+            v6,
+        })
+    }
+
+    // Goes to a temporary file next to `path` first and is renamed over it, so there's always either the old file or
+    // all of the new one
+    pub fn write(&self, path: &Path) -> Result<(), SnapshotError> {
+        let io_error = |err| SnapshotError::Io(path.to_owned(), err);
+
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let file = File::create(&temp).map_err(io_error)?;
+        file.write_all_at(&self.encode(), 0).map_err(io_error)?;
+        file.sync_all().map_err(io_error)?;
+        fs::rename(&temp, path).map_err(io_error)?;
+        sync_dir(path).map_err(io_error)
+    }
+
+    pub fn read(path: &Path) -> Result<Self, SnapshotError> {
+        let bytes = fs::read(path).map_err(|err| SnapshotError::Io(path.to_owned(), err))?;
+        Self::decode(&bytes).ok_or_else(|| SnapshotError::Malformed(path.to_owned()))
+    }
+}
+
+// Makes a rename in the directory holding `path` stick
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
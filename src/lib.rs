@@ -1,13 +1,65 @@
+use std::fmt;
+
 use bytemuck::{Pod, Zeroable};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+pub mod aggregate;
+pub mod client;
+pub mod cookie;
+pub mod drums;
+pub mod expiry;
+pub mod geo;
+pub mod harmony;
+pub mod heartbeat;
+pub mod layout;
+pub mod meter;
+pub mod midi;
+pub mod model;
+pub mod notes;
+pub mod store;
+pub mod stream;
+pub mod synth;
+
+// First byte of every packet, lets the server tell garbage apart from an outdated client
+pub const MAGIC: u8 = 0x6D;
+// Bump whenever `Message` (or anything inside of it) changes its wire layout
+pub const PROTOCOL_VERSION: u8 = 5;
+
+// Every packet is `[MAGIC, PROTOCOL_VERSION]` followed by a postcard-encoded `Message`.
+// The two header bytes are the only part of the format guaranteed to never change.
+// Replies carry the id of the request they answer, so a client can tell them apart when several are in flight. The id
+// is 0 when the request couldn't be decoded far enough to know it, clients never use 0 themselves.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Message {
+    Heartbeat(Heartbeat),
+    StatsRequest(StatsRequest),
+    Stats { id: u32, stats: Box<Stats> },
+    Error { id: u32, error: ProtocolError },
+    // Sent instead of stats until the client proves it can receive packets at its address, echo it back in the next
+    // heartbeat. Id 0 when it answers a plain heartbeat.
+    Cookie { id: u32, cookie: Cookie },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Heartbeat {
     pub seed: i32,
-    pub wants_country: u8,
+    // The server only starts tracking a network once one of its heartbeats comes with a cookie it handed out
+    pub cookie: Cookie,
+}
+
+// Counts as a heartbeat too, so there's no need to send both
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StatsRequest {
+    pub id: u32,
+    pub heartbeat: Heartbeat,
+    pub country: u8,
 }
 
-#[derive(Serialize, Deserialize)]
+// Fixed size rather than a varint, so that a heartbeat is never smaller than the cookie sent back for it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cookie(pub [u8; 8]);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Stats {
     pub connected: u32,
     pub seed: i32,
@@ -15,6 +67,90 @@ pub struct Stats {
     pub country_heatmap: [f32; COUNTRIES.len()],
 }
 
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum ProtocolError {
+    UnsupportedVersion { supported: u8 },
+    Malformed,
+    UnknownCountry,
+    // Too many packets from the client's network, retrying right away only makes it worse
+    RateLimited,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedVersion { supported } => {
+                write!(
+                    f,
+                    "unsupported protocol version, server speaks v{supported}"
+                )
+            }
+            Self::Malformed => f.write_str("malformed message"),
+            Self::UnknownCountry => f.write_str("unknown country"),
+            Self::RateLimited => f.write_str("rate limited"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum DecodeError {
+    // Not one of our packets at all
+    BadMagic,
+    UnsupportedVersion(u8),
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic => f.write_str("not a MelodyBrain packet"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "peer speaks protocol v{version}, but v{PROTOCOL_VERSION} is supported"
+            ),
+            Self::Malformed => f.write_str("malformed message"),
+        }
+    }
+}
+
+impl Message {
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        let [magic, version, body @ ..] = buf else {
+            return Err(postcard::Error::SerializeBufferFull);
+        };
+        *magic = MAGIC;
+        *version = PROTOCOL_VERSION;
+
+        let len = postcard::to_slice(self, body)?.len();
+
+        Ok(&mut buf[..len + 2])
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let [magic, version, body @ ..] = bytes else {
+            return Err(DecodeError::BadMagic);
+        };
+
+        if *magic != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+
+        if *version != PROTOCOL_VERSION {
+            return Err(DecodeError::UnsupportedVersion(*version));
+        }
+
+        postcard::from_bytes(body).map_err(|_| DecodeError::Malformed)
+    }
+
+    // The id of the request this answers, None if it isn't a reply
+    pub fn reply_id(&self) -> Option<u32> {
+        match self {
+            Self::Stats { id, .. } | Self::Error { id, .. } | Self::Cookie { id, .. } => Some(*id),
+            Self::Heartbeat(_) | Self::StatsRequest(_) => None,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Pod, Zeroable, Default)]
 #[repr(C)]
 pub struct StoredIpStats {
@@ -23,7 +159,10 @@ pub struct StoredIpStats {
     pub hits: u32,
     pub cum_duration: u32,
     pub country: u8,
-    pub _reserved: [u8; 7],
+    pub _reserved: [u8; 1],
+    // Token bucket for rate limiting, refilled_at is the low 32 bits of a unix timestamp
+    pub tokens: u16,
+    pub refilled_at: u32,
 }
 
 #[derive(Clone, Copy, Debug, Pod, Zeroable)]
@@ -33,7 +172,19 @@ pub struct StoredCountryStats {
     pub active: u32,
     pub unique: u32,
     pub cum_duration: u32,
-    pub _reserved: [u8; 12],
+    // Clients whose records were given back, and how long they were around. They still count towards unique and
+    // cum_duration, this is so those can be checked against the records that are left.
+    pub forgotten: u32,
+    pub forgotten_duration: u32,
+    pub _reserved: [u8; 4],
+}
+
+// A slot in the IPv6 table, keyed by the client's /64 prefix. A prefix of 0 marks an empty slot, since ::/64 is never a client.
+#[derive(Clone, Copy, Debug, Pod, Zeroable, Default)]
+#[repr(C)]
+pub struct StoredIpv6Stats {
+    pub prefix: u64,
+    pub stats: StoredIpStats,
 }
 
 pub fn search_country(code: &str) -> Option<u8> {
@@ -0,0 +1,102 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::notes::NoiseRng;
+
+// Same keyboard, different mash
+const METER_NOISE: i32 = 192837465;
+
+// Quarter notes per minute, around the 75 the page always used to play at
+const BPM: Range<f32> = 60.0..100.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub beats: u8,
+    // 4 for quarter notes, 8 for eighths
+    pub unit: u8,
+}
+
+impl TimeSignature {
+    pub const COMMON: Self = Self { beats: 4, unit: 4 };
+    pub const WALTZ: Self = Self { beats: 3, unit: 4 };
+    pub const SIX_EIGHT: Self = Self { beats: 6, unit: 8 };
+
+    // In sixteenths, the unit note durations are counted in
+    pub fn bar_length(self) -> u32 {
+        self.beats as u32 * 16 / self.unit as u32
+    }
+
+    // Sixteenths in each group of the bar that gets felt as one beat, so 6/8 goes in two dotted quarters
+    pub fn groups(self) -> Vec<u32> {
+        if self.unit == 8 && self.beats.is_multiple_of(3) {
+            return vec![6; self.beats as usize / 3];
+        }
+
+        vec![16 / self.unit as u32; self.beats as usize]
+    }
+}
+
+// Most music is in 4/4, so most seeds are too
+const TIME_SIGNATURES: [(TimeSignature, f32); 3] = [
+    (TimeSignature::COMMON, 0.6),
+    (TimeSignature::WALTZ, 0.25),
+    (TimeSignature::SIX_EIGHT, 0.15),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meter {
+    pub bpm: u16,
+    pub time_signature: TimeSignature,
+}
+
+impl Meter {
+    // Only depends on the seed, so every part of a melody has the same tempo wherever it starts
+    pub fn for_seed(seed: i32) -> Self {
+        let mut rng = NoiseRng::with_stream(0, seed, METER_NOISE);
+        let bpm = rng.sample_range(BPM).round() as u16;
+
+        let pick = rng.sample_next();
+        let mut acc = 0.;
+        let time_signature = TIME_SIGNATURES
+            .iter()
+            .find(|(_, weight)| {
+                acc += weight;
+                pick < acc
+            })
+            .map_or(TimeSignature::COMMON, |&(time_signature, _)| time_signature);
+
+        Self {
+            bpm,
+            time_signature,
+        }
+    }
+
+    pub fn bar_length(self) -> u32 {
+        self.time_signature.bar_length()
+    }
+
+    // Beats into the stream where the bar that's playing `beats` in started
+    pub fn bar_start(self, beats: f32) -> f32 {
+        let bar = sixteenths_to_beats(self.bar_length());
+        (beats / bar).floor() * bar
+    }
+
+    // Whether some seed could have this meter, anything else might not even have bars
+    pub fn is_valid(self) -> bool {
+        let bpm = BPM.start as u16..=BPM.end as u16;
+        bpm.contains(&self.bpm)
+            && TIME_SIGNATURES
+                .iter()
+                .any(|&(time_signature, _)| time_signature == self.time_signature)
+    }
+
+    pub fn seconds_per_beat(self) -> f32 {
+        60. / self.bpm.max(1) as f32
+    }
+}
+
+// Beats are quarter notes, durations are sixteenths
+pub fn sixteenths_to_beats(sixteenths: u32) -> f32 {
+    sixteenths as f32 / 4.
+}
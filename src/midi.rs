@@ -0,0 +1,472 @@
+use std::fmt;
+
+use crate::{
+    harmony::{Arrangement, TrackNote, Voice},
+    meter::TimeSignature,
+    notes::Note,
+};
+
+// The tempo the page played every melody at before seeds got a meter of their own
+pub const DEFAULT_BPM: u16 = 75;
+pub const TICKS_PER_BEAT: u16 = 480;
+// A note's duration is counted in sixteenth notes
+const TICKS_PER_DURATION: u32 = TICKS_PER_BEAT as u32 / 4;
+
+const CHANNEL: u8 = 0;
+const BASS_CHANNEL: u8 = 1;
+const PAD_CHANNEL: u8 = 2;
+const ARPEGGIO_CHANNEL: u8 = 3;
+// Channel 10, counting from 0
+const PERCUSSION_CHANNEL: u8 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmfFormat {
+    // Tempo, time signature and notes in one track
+    SingleTrack,
+    // A tempo and time signature track followed by a note track, what most DAWs write themselves
+    MultiTrack,
+}
+
+pub fn duration_ticks(duration: u8) -> u32 {
+    duration as u32 * TICKS_PER_DURATION
+}
+
+pub fn velocity_to_midi(velocity: f32) -> u8 {
+    // A note on with velocity 0 is a note off, so never go below 1
+    (velocity * 127.).round().clamp(1., 127.) as u8
+}
+
+fn push_vlq(out: &mut Vec<u8>, mut value: u32) {
+    let mut bytes = [0; 4];
+    let mut len = 0;
+
+    loop {
+        bytes[len] = (value & 0x7F) as u8;
+        len += 1;
+        value >>= 7;
+        if value == 0 {
+            break;
+        }
+    }
+
+    for idx in (0..len).rev() {
+        let continued = if idx > 0 { 0x80 } else { 0 };
+        out.push(bytes[idx] | continued);
+    }
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(kind);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn push_tempo(track: &mut Vec<u8>, bpm: u16) {
+    let micros_per_beat = 60_000_000 / bpm.max(1) as u32;
+
+    push_vlq(track, 0);
+    track.extend_from_slice(&[0xFF, 0x51, 0x03]);
+    track.extend_from_slice(&micros_per_beat.to_be_bytes()[1..]);
+}
+
+fn push_time_signature(track: &mut Vec<u8>, time_signature: TimeSignature) {
+    // MIDI clocks per metronome click, 24 to a quarter note, clicking once a felt beat
+    let clocks = 24 * time_signature.groups()[0] / 4;
+
+    push_vlq(track, 0);
+    track.extend_from_slice(&[0xFF, 0x58, 0x04]);
+    track.extend_from_slice(&[
+        time_signature.beats,
+        time_signature.unit.trailing_zeros() as u8,
+        clocks as u8,
+        // Thirty-second notes per quarter note
+        8,
+    ]);
+}
+
+fn push_notes(track: &mut Vec<u8>, notes: &[Note]) {
+    for note in notes {
+        let pitch = note.pitch.clamp(0, 127) as u8;
+
+        push_vlq(track, 0);
+        track.extend_from_slice(&[0x90 | CHANNEL, pitch, velocity_to_midi(note.velocity)]);
+        push_vlq(track, duration_ticks(note.duration));
+        track.extend_from_slice(&[0x80 | CHANNEL, pitch, 0]);
+    }
+}
+
+// General MIDI programs, counting from 0
+fn voice_channel(voice: Voice) -> (u8, Option<u8>, &'static str) {
+    match voice {
+        // Fingered electric bass
+        Voice::Bass => (BASS_CHANNEL, Some(33), "Bass"),
+        // Warm pad
+        Voice::Pad => (PAD_CHANNEL, Some(89), "Pad"),
+        Voice::Arpeggio => (ARPEGGIO_CHANNEL, Some(46), "Arpeggio"),
+        // The percussion channel has a drum for every key instead of programs
+        Voice::Drums => (PERCUSSION_CHANNEL, None, "Drums"),
+    }
+}
+
+struct Event {
+    tick: u32,
+    message: [u8; 3],
+}
+
+// `origin` is the beat in the stream the file starts at
+fn note_events(events: &mut Vec<Event>, channel: u8, note: &TrackNote, origin: f32) {
+    let pitch = note.pitch.clamp(0, 127) as u8;
+    let on = ((note.start - origin) * TICKS_PER_BEAT as f32).round() as u32;
+
+    events.push(Event {
+        tick: on,
+        message: [0x90 | channel, pitch, velocity_to_midi(note.velocity)],
+    });
+    events.push(Event {
+        tick: on + duration_ticks(note.duration),
+        message: [0x80 | channel, pitch, 0],
+    });
+}
+
+fn push_name(track: &mut Vec<u8>, name: &str) {
+    push_vlq(track, 0);
+    track.extend_from_slice(&[0xFF, 0x03]);
+    push_vlq(track, name.len() as u32);
+    track.extend_from_slice(name.as_bytes());
+}
+
+fn push_program(track: &mut Vec<u8>, channel: u8, program: u8) {
+    push_vlq(track, 0);
+    track.extend_from_slice(&[0xC0 | channel, program]);
+}
+
+fn push_events(track: &mut Vec<u8>, mut events: Vec<Event>) {
+    // Note offs first when something ends just as something else starts, or a repeated pitch would cut itself off
+    events.sort_by_key(|event| (event.tick, event.message[0] & 0xF0 == 0x90));
+
+    let mut now = 0;
+    for event in events {
+        push_vlq(track, event.tick - now);
+        track.extend_from_slice(&event.message);
+        now = event.tick;
+    }
+}
+
+fn push_end_of_track(track: &mut Vec<u8>) {
+    push_vlq(track, 0);
+    track.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+}
+
+// Writes the notes as a Standard MIDI File, one after the other with no rests in between
+pub fn write_smf(
+    notes: &[Note],
+    time_signature: TimeSignature,
+    bpm: u16,
+    format: SmfFormat,
+) -> Vec<u8> {
+    let mut tracks = Vec::new();
+
+    match format {
+        SmfFormat::SingleTrack => {
+            let mut track = Vec::new();
+            push_tempo(&mut track, bpm);
+            push_time_signature(&mut track, time_signature);
+            push_notes(&mut track, notes);
+            push_end_of_track(&mut track);
+            tracks.push(track);
+        }
+        SmfFormat::MultiTrack => {
+            let mut tempo = Vec::new();
+            push_tempo(&mut tempo, bpm);
+            push_time_signature(&mut tempo, time_signature);
+            push_end_of_track(&mut tempo);
+            tracks.push(tempo);
+
+            let mut melody = Vec::new();
+            push_notes(&mut melody, notes);
+            push_end_of_track(&mut melody);
+            tracks.push(melody);
+        }
+    }
+
+    finish_smf(&tracks, format)
+}
+
+// The melody and its accompaniment, each voice on a channel of its own. Arrangements from the middle of a stream
+// start at the bar line before their first note, so bars stay where they were.
+pub fn write_arrangement(arrangement: &Arrangement, bpm: u16, format: SmfFormat) -> Vec<u8> {
+    let origin = arrangement.meter.bar_start(arrangement.start);
+    let mut voices = Vec::new();
+
+    let mut melody = Vec::new();
+    for note in &arrangement.melody {
+        let note = TrackNote {
+            start: note.start,
+            pitch: note.pitch,
+            velocity: note.velocity,
+            duration: note.duration,
+        };
+        note_events(&mut melody, CHANNEL, &note, origin);
+    }
+    voices.push(("Melody", CHANNEL, None, melody));
+
+    for track in &arrangement.tracks {
+        let (channel, program, name) = voice_channel(track.voice);
+        let mut events = Vec::new();
+        for note in &track.notes {
+            note_events(&mut events, channel, note, origin);
+        }
+        voices.push((name, channel, program, events));
+    }
+
+    let mut tracks = Vec::new();
+    match format {
+        SmfFormat::SingleTrack => {
+            let mut track = Vec::new();
+            push_tempo(&mut track, bpm);
+            push_time_signature(&mut track, arrangement.meter.time_signature);
+            let mut events = Vec::new();
+            for (_, channel, program, voice) in voices {
+                if let Some(program) = program {
+                    push_program(&mut track, channel, program);
+                }
+                events.extend(voice);
+            }
+            push_events(&mut track, events);
+            push_end_of_track(&mut track);
+            tracks.push(track);
+        }
+        SmfFormat::MultiTrack => {
+            let mut tempo = Vec::new();
+            push_tempo(&mut tempo, bpm);
+            push_time_signature(&mut tempo, arrangement.meter.time_signature);
+            push_end_of_track(&mut tempo);
+            tracks.push(tempo);
+
+            for (name, channel, program, events) in voices {
+                let mut track = Vec::new();
+                push_name(&mut track, name);
+                if let Some(program) = program {
+                    push_program(&mut track, channel, program);
+                }
+                push_events(&mut track, events);
+                push_end_of_track(&mut track);
+                tracks.push(track);
+            }
+        }
+    }
+
+    finish_smf(&tracks, format)
+}
+
+fn finish_smf(tracks: &[Vec<u8>], format: SmfFormat) -> Vec<u8> {
+    let format_id: u16 = match format {
+        SmfFormat::SingleTrack => 0,
+        SmfFormat::MultiTrack => 1,
+    };
+
+    let mut header = Vec::with_capacity(6);
+    header.extend_from_slice(&format_id.to_be_bytes());
+    header.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+    header.extend_from_slice(&TICKS_PER_BEAT.to_be_bytes());
+
+    let mut out = Vec::new();
+    push_chunk(&mut out, b"MThd", &header);
+    for track in tracks {
+        push_chunk(&mut out, b"MTrk", track);
+    }
+
+    out
+}
+
+#[derive(Debug)]
+pub enum MidiError {
+    NotMidi,
+    Truncated,
+    // Timecode based timing, which doesn't map to beats
+    SmpteTiming,
+    UnexpectedByte(u8),
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotMidi => f.write_str("not a standard MIDI file"),
+            Self::Truncated => f.write_str("file ends in the middle of a chunk"),
+            Self::SmpteTiming => f.write_str("SMPTE timing is not supported"),
+            Self::UnexpectedByte(byte) => write!(f, "unexpected byte {byte:#04x} in track"),
+        }
+    }
+}
+
+impl std::error::Error for MidiError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedNote {
+    pub start: u32,
+    pub pitch: u8,
+    pub velocity: u8,
+    pub ticks: u32,
+}
+
+#[derive(Debug)]
+pub struct SmfNotes {
+    pub ticks_per_beat: u16,
+    // One list per track and channel, sorted by start. Percussion on channel 10 is left out.
+    pub voices: Vec<Vec<TimedNote>>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MidiError> {
+        if self.0.len() < len {
+            return Err(MidiError::Truncated);
+        }
+
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MidiError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn vlq(&mut self) -> Result<u32, MidiError> {
+        let mut value = 0u32;
+        let mut byte = 0;
+        // At most 4 bytes long
+        for _ in 0..4 {
+            byte = self.u8()?;
+            value = value << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(MidiError::UnexpectedByte(byte))
+    }
+}
+
+fn read_track(mut track: Reader, voices: &mut Vec<Vec<TimedNote>>) -> Result<(), MidiError> {
+    // Notes still sounding and finished notes, per channel
+    let mut open: [Vec<(u8, u8, u32)>; 16] = Default::default();
+    let mut notes: [Vec<TimedNote>; 16] = Default::default();
+    let mut now = 0u32;
+    let mut running_status = None;
+
+    while !track.0.is_empty() {
+        now = now.saturating_add(track.vlq()?);
+
+        let mut status = track.u8()?;
+        let first_data = if status < 0x80 {
+            // Running status, this was already the first data byte
+            let data = status;
+            status = running_status.ok_or(MidiError::UnexpectedByte(status))?;
+            Some(data)
+        } else {
+            None
+        };
+
+        match status {
+            0xFF => {
+                let kind = track.u8()?;
+                let len = track.vlq()? as usize;
+                track.take(len)?;
+                if kind == 0x2F {
+                    break;
+                }
+                continue;
+            }
+            0xF0 | 0xF7 => {
+                let len = track.vlq()? as usize;
+                track.take(len)?;
+                continue;
+            }
+            0x80..=0xEF => running_status = Some(status),
+            _ => return Err(MidiError::UnexpectedByte(status)),
+        }
+
+        let channel = (status & 0x0F) as usize;
+        let data1 = match first_data {
+            Some(data) => data,
+            None => track.u8()?,
+        };
+        // Program change and channel pressure only have one data byte
+        let data2 = match status & 0xF0 {
+            0xC0 | 0xD0 => 0,
+            _ => track.u8()?,
+        };
+
+        match status & 0xF0 {
+            0x90 if data2 > 0 => open[channel].push((data1, data2, now)),
+            // A note on with velocity 0 is a note off too
+            0x80 | 0x90 => {
+                if let Some(idx) = open[channel].iter().position(|&(pitch, ..)| pitch == data1) {
+                    let (pitch, velocity, start) = open[channel].remove(idx);
+                    notes[channel].push(TimedNote {
+                        start,
+                        pitch,
+                        velocity,
+                        ticks: now - start,
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (channel, mut notes) in notes.into_iter().enumerate() {
+        if channel == PERCUSSION_CHANNEL as usize || notes.is_empty() {
+            continue;
+        }
+
+        notes.sort_by_key(|note| note.start);
+        voices.push(notes);
+    }
+
+    Ok(())
+}
+
+// Reads every note out of a Standard MIDI File of any format
+pub fn read_smf(bytes: &[u8]) -> Result<SmfNotes, MidiError> {
+    let mut reader = Reader(bytes);
+
+    if reader.take(4).map_err(|_| MidiError::NotMidi)? != b"MThd" {
+        return Err(MidiError::NotMidi);
+    }
+    let header_len = reader.u32()? as usize;
+    let mut header = Reader(reader.take(header_len)?);
+    let _format = header.u16()?;
+    let _tracks = header.u16()?;
+    let division = header.u16()?;
+    if division & 0x8000 != 0 {
+        return Err(MidiError::SmpteTiming);
+    }
+
+    let mut voices = Vec::new();
+    while !reader.0.is_empty() {
+        let kind = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let body = reader.take(len)?;
+
+        // Unknown chunks have to be skipped
+        if kind == b"MTrk" {
+            read_track(Reader(body), &mut voices)?;
+        }
+    }
+
+    Ok(SmfNotes {
+        ticks_per_beat: division,
+        voices,
+    })
+}
@@ -0,0 +1,304 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    midi::{SmfNotes, TimedNote},
+    notes::{DURATIONS, OCTAVES, Scale},
+};
+
+// Next-state probabilities after one particular context
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransitionRow {
+    // The last states, oldest first
+    pub context: Vec<usize>,
+    // How many transitions this row was estimated from
+    pub total: u32,
+    pub weights: Vec<f32>,
+}
+
+// Transitions between `states` states, for every context up to `order` states long that showed up in training.
+// Contexts that never showed up aren't stored at all.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TransitionTable {
+    pub states: usize,
+    pub order: usize,
+    pub rows: Vec<TransitionRow>,
+}
+
+impl TransitionTable {
+    pub fn estimate(sequences: &[Vec<usize>], states: usize, order: usize) -> Self {
+        let mut counts: HashMap<Vec<usize>, Vec<u32>> = HashMap::new();
+
+        for sequence in sequences {
+            for len in 1..=order {
+                for window in sequence.windows(len + 1) {
+                    let (context, next) = window.split_at(len);
+                    counts
+                        .entry(context.to_vec())
+                        .or_insert_with(|| vec![0; states])[next[0]] += 1;
+                }
+            }
+        }
+
+        let mut rows: Vec<_> = counts
+            .into_iter()
+            .map(|(context, counts)| {
+                let total: u32 = counts.iter().sum();
+                TransitionRow {
+                    context,
+                    total,
+                    weights: counts.iter().map(|&n| n as f32 / total as f32).collect(),
+                }
+            })
+            .collect();
+        rows.sort_by(|a, b| (a.context.len(), &a.context).cmp(&(b.context.len(), &b.context)));
+
+        Self {
+            states,
+            order,
+            rows,
+        }
+    }
+
+    // Dense matrix of the first order rows, states that were never seen move anywhere with equal probability
+    pub fn first_order(&self) -> Vec<Vec<f32>> {
+        let mut matrix = vec![vec![1. / self.states as f32; self.states]; self.states];
+
+        for row in self.rows.iter().filter(|row| row.context.len() == 1) {
+            matrix[row.context[0]].clone_from(&row.weights);
+        }
+
+        matrix
+    }
+
+    fn validate(&self, name: &'static str, states: usize) -> Result<(), ModelError> {
+        let valid = self.states == states
+            && self.order >= 1
+            && self.rows.iter().all(|row| {
+                (1..=self.order).contains(&row.context.len())
+                    && row.context.iter().all(|&state| state < states)
+                    && row.weights.len() == states
+                    && row
+                        .weights
+                        .iter()
+                        .all(|weight| weight.is_finite() && *weight >= 0.)
+                    && row.weights.iter().sum::<f32>() > 0.
+            });
+
+        if !valid {
+            return Err(ModelError::Invalid(name));
+        }
+
+        Ok(())
+    }
+}
+
+// Transition tables for each part of a note, like the built in ones but learned from a corpus
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MarkovModel {
+    // What the pitch states are degrees of
+    pub scale: Scale,
+    pub pitch: TransitionTable,
+    pub octave: TransitionTable,
+    pub duration: TransitionTable,
+    // Pitch and duration as one state, degree * DURATIONS.len() + duration, if trained with it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub joint: Option<TransitionTable>,
+}
+
+#[derive(Debug)]
+pub enum ModelError {
+    Read(io::Error),
+    Parse(serde_json::Error),
+    // Which table doesn't fit the states it should have
+    Invalid(&'static str),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(err) => write!(f, "failed to read model: {err}"),
+            Self::Parse(err) => write!(f, "invalid model: {err}"),
+            Self::Invalid(table) => write!(f, "invalid model: bad {table} transition table"),
+        }
+    }
+}
+
+impl std::error::Error for ModelError {}
+
+impl MarkovModel {
+    pub fn load(path: &Path) -> Result<Self, ModelError> {
+        let contents = fs::read(path).map_err(ModelError::Read)?;
+        let model: Self = serde_json::from_slice(&contents).map_err(ModelError::Parse)?;
+
+        model
+            .pitch
+            .validate("pitch", model.scale.intervals().len())?;
+        model.octave.validate("octave", OCTAVES.len())?;
+        model.duration.validate("duration", DURATIONS.len())?;
+        if let Some(joint) = &model.joint {
+            joint.validate("joint", model.pitch.states * DURATIONS.len())?;
+        }
+
+        Ok(model)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(self).map_err(io::Error::other)?;
+        fs::write(path, json)
+    }
+}
+
+// Turns MIDI files into the state sequences the tables are estimated from
+pub struct Trainer {
+    scale: Scale,
+    order: usize,
+    pitches: Vec<Vec<usize>>,
+    octaves: Vec<Vec<usize>>,
+    durations: Vec<Vec<usize>>,
+    // Only collected when a joint table was asked for
+    joint: Option<Vec<Vec<usize>>>,
+    notes: usize,
+}
+
+impl Trainer {
+    pub fn new(scale: Scale, order: usize, joint: bool) -> Self {
+        Self {
+            scale,
+            order: order.max(1),
+            pitches: Vec::new(),
+            octaves: Vec::new(),
+            durations: Vec::new(),
+            joint: joint.then(Vec::new),
+            notes: 0,
+        }
+    }
+
+    // Notes used so far
+    pub fn notes(&self) -> usize {
+        self.notes
+    }
+
+    pub fn add(&mut self, smf: &SmfNotes) {
+        for voice in &smf.voices {
+            let melody = top_line(voice);
+            if melody.len() < 2 {
+                continue;
+            }
+
+            let tonic = estimate_tonic(&melody, self.scale);
+            // Octave 0 is the one around the middle of the melody
+            let mut sorted: Vec<_> = melody.iter().map(|note| note.pitch as i32).collect();
+            sorted.sort_unstable();
+            let middle = sorted[sorted.len() / 2];
+            let base = middle - (middle - tonic).rem_euclid(12);
+
+            let mut pitches = Vec::new();
+            let mut octaves = Vec::new();
+            let mut durations = Vec::new();
+            let mut joint = Vec::new();
+            for note in &melody {
+                let (degree, octave) = quantize_pitch(note.pitch as i32 - base, self.scale);
+                pitches.push(degree);
+                octaves.push((octave.clamp(-1, 1) + 1) as usize);
+
+                if let Some(duration) = quantize_duration(note.ticks, smf.ticks_per_beat) {
+                    durations.push(duration);
+                    joint.push(degree * DURATIONS.len() + duration);
+                }
+            }
+
+            self.notes += melody.len();
+            self.pitches.push(pitches);
+            self.octaves.push(octaves);
+            self.durations.push(durations);
+            if let Some(sequences) = &mut self.joint {
+                sequences.push(joint);
+            }
+        }
+    }
+
+    pub fn finish(self) -> MarkovModel {
+        let degrees = self.scale.intervals().len();
+
+        MarkovModel {
+            scale: self.scale,
+            pitch: TransitionTable::estimate(&self.pitches, degrees, self.order),
+            octave: TransitionTable::estimate(&self.octaves, OCTAVES.len(), self.order),
+            duration: TransitionTable::estimate(&self.durations, DURATIONS.len(), self.order),
+            joint: self.joint.map(|sequences| {
+                TransitionTable::estimate(&sequences, degrees * DURATIONS.len(), self.order)
+            }),
+        }
+    }
+}
+
+// The highest note of everything that starts at the same time, which is usually the melody
+fn top_line(voice: &[TimedNote]) -> Vec<TimedNote> {
+    let mut melody: Vec<TimedNote> = Vec::new();
+
+    for note in voice {
+        match melody.last_mut() {
+            Some(last) if last.start == note.start => {
+                if note.pitch > last.pitch {
+                    *last = *note;
+                }
+            }
+            _ => melody.push(*note),
+        }
+    }
+
+    melody
+}
+
+// The pitch class that puts the most notes inside the scale
+fn estimate_tonic(melody: &[TimedNote], scale: Scale) -> i32 {
+    (0..12)
+        .max_by_key(|&tonic| {
+            let fits = melody
+                .iter()
+                .filter(|note| {
+                    let class = (note.pitch as i32 - tonic).rem_euclid(12);
+                    scale.intervals().contains(&(class as i8))
+                })
+                .count();
+            // Ties go to the lowest tonic
+            (fits, -tonic)
+        })
+        .unwrap()
+}
+
+// Snaps a pitch relative to the tonic to the nearest scale degree, which can be in the next octave up or down
+fn quantize_pitch(relative: i32, scale: Scale) -> (usize, i32) {
+    let octave = relative.div_euclid(12);
+    let class = relative.rem_euclid(12);
+
+    scale
+        .intervals()
+        .iter()
+        .enumerate()
+        .flat_map(|(degree, &interval)| {
+            [-1, 0, 1].map(|shift| (degree, shift, interval as i32 + shift * 12))
+        })
+        .min_by_key(|&(_, _, pitch)| (pitch - class).abs())
+        .map(|(degree, shift, _)| (degree, octave + shift))
+        .unwrap()
+}
+
+// Nearest duration in sixteenth notes, going by ratio rather than difference
+fn quantize_duration(ticks: u32, ticks_per_beat: u16) -> Option<usize> {
+    let sixteenths = ticks as f32 * 4. / ticks_per_beat as f32;
+    if sixteenths <= 0. {
+        return None;
+    }
+
+    DURATIONS
+        .iter()
+        .enumerate()
+        .min_by(|(_, a), (_, b)| {
+            let distance = |d: u8| (sixteenths / d as f32).ln().abs();
+            distance(**a).total_cmp(&distance(**b))
+        })
+        .map(|(idx, _)| idx)
+}
@@ -0,0 +1,810 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    ops::Range,
+    str::FromStr,
+};
+
+use noise_functions::{OpenSimplex2, Sample};
+use serde::{Deserialize, Serialize, de::IntoDeserializer};
+
+use crate::{
+    WORLDWIDE,
+    meter::{Meter, sixteenths_to_beats},
+    model::{MarkovModel, TransitionTable},
+    stream::StateError,
+};
+
+pub(crate) const TONIC: i8 = 60; // Middle C
+// Markov chain rules for scale degrees, so they work for any mode with 7 notes
+const MARKOV_SCALE: [[f32; 7]; 7] = [
+    [0.3781, 0.1806, 0.0823, 0.0398, 0.0872, 0.0893, 0.1428],
+    [0.2310, 0.3489, 0.1973, 0.0418, 0.0718, 0.0497, 0.0596],
+    [0.0998, 0.2476, 0.3369, 0.1331, 0.1063, 0.0543, 0.0219],
+    [0.0545, 0.0824, 0.2558, 0.3531, 0.1737, 0.0693, 0.0113],
+    [0.0943, 0.0639, 0.1080, 0.1287, 0.3880, 0.1731, 0.0441],
+    [0.0895, 0.0585, 0.0587, 0.0461, 0.2352, 0.3753, 0.1367],
+    [0.2324, 0.0759, 0.0352, 0.0135, 0.0915, 0.2230, 0.3284],
+]; // Markov chain rules for notes based off of mono-midi-transposition-dataset
+
+// Shape of the generated matrices for scales that don't have 7 notes, roughly fit to MARKOV_SCALE
+const GENERATED_REPEAT: f32 = 0.36;
+const GENERATED_FALLOFF: f32 = 0.45;
+
+pub const OCTAVES: [i8; 3] = [-12, 0, 12]; // Semitones shift for an octave
+const OCTAVE_MARKOV: [[f32; 3]; 3] = [
+    [0.7f32, 0.3f32, 0.0f32],
+    [0.2f32, 0.6f32, 0.2f32],
+    [0.0f32, 0.3f32, 0.7f32],
+]; // Arbitrarily determined
+
+pub const DURATIONS: [u8; 4] = [1, 2, 4, 8];
+const DURATION_MARKOV: [[f32; 4]; 4] = [
+    [0.8065f32, 0.1581f32, 0.0307f32, 0.0047f32],
+    [0.2546f32, 0.6458f32, 0.0874f32, 0.0122f32],
+    [0.2233f32, 0.4628f32, 0.2864f32, 0.0275f32],
+    [0.1376f32, 0.2615f32, 0.1796f32, 0.4213f32],
+]; // Based off of mono-midi-transposition-dataset
+
+// How much more likely the melody is to land on a note of the chord playing under it
+const CHORD_TONE_BIAS: f32 = 3.;
+
+// Longer contexts seen fewer times than this in training back off to shorter ones, a handful of examples is just noise
+const MIN_CONTEXT_COUNT: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scale {
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Mixolydian,
+    Pentatonic,
+    Blues,
+    WholeTone,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 9] = [
+        Self::Major,
+        Self::NaturalMinor,
+        Self::HarmonicMinor,
+        Self::MelodicMinor,
+        Self::Dorian,
+        Self::Mixolydian,
+        Self::Pentatonic,
+        Self::Blues,
+        Self::WholeTone,
+    ];
+
+    // Semitones above the tonic
+    pub fn intervals(self) -> &'static [i8] {
+        match self {
+            Self::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Self::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            Self::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            // Ascending form, the one used in jazz
+            Self::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Self::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Self::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Self::Pentatonic => &[0, 2, 4, 7, 9],
+            Self::Blues => &[0, 3, 5, 6, 7, 10],
+            Self::WholeTone => &[0, 2, 4, 6, 8, 10],
+        }
+    }
+
+    pub fn pitches(self) -> Vec<i8> {
+        self.intervals()
+            .iter()
+            .map(|interval| TONIC + interval)
+            .collect()
+    }
+
+    pub fn transitions(self) -> Vec<Vec<f32>> {
+        let len = self.intervals().len();
+        if len == MARKOV_SCALE.len() {
+            return MARKOV_SCALE.iter().map(|row| row.to_vec()).collect();
+        }
+
+        // Mostly repeat the note or step to a neighbour, wrapping around the octave like the dataset does
+        (0..len)
+            .map(|from| {
+                let row: Vec<f32> = (0..len)
+                    .map(|to| {
+                        let distance = from.abs_diff(to).min(len - from.abs_diff(to));
+                        if distance == 0 {
+                            GENERATED_REPEAT
+                        } else {
+                            GENERATED_FALLOFF.powi(distance as i32)
+                        }
+                    })
+                    .collect();
+                let total: f32 = row.iter().sum();
+                row.into_iter().map(|weight| weight / total).collect()
+            })
+            .collect()
+    }
+
+    // Arbitrary but stable, so every country has a sound of its own. The whole world stays in major.
+    pub fn for_country(country: u8) -> Self {
+        const MODES: [Scale; 6] = [
+            Scale::Major,
+            Scale::NaturalMinor,
+            Scale::HarmonicMinor,
+            Scale::MelodicMinor,
+            Scale::Dorian,
+            Scale::Mixolydian,
+        ];
+
+        if country == WORLDWIDE {
+            return Self::Major;
+        }
+        MODES[country as usize % MODES.len()]
+    }
+}
+
+// Same snake_case names as in query strings
+impl FromStr for Scale {
+    type Err = serde::de::value::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(name.into_deserializer())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Note {
+    // Beats since the first note of the stream
+    pub start: f32,
+    pub pitch: i8,
+    pub velocity: f32,
+    // Sixteenths
+    pub duration: u8,
+}
+
+// Seed chosen by keyboard mash, guaranteed to be random
+const MELODY_NOISE: i32 = 207482365;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct NoiseRng {
+    x: u32,
+    y: i32,
+    // Noise seed, so other parts of the music can have numbers of their own from the same seed
+    stream: i32,
+    // The seed being faded out and how much of its noise is left in the mix
+    fade: Option<(i32, f32)>,
+}
+
+impl NoiseRng {
+    pub(crate) fn new(start: u32, seed: i32) -> Self {
+        Self::with_stream(start, seed, MELODY_NOISE)
+    }
+
+    pub(crate) fn with_stream(start: u32, seed: i32, stream: i32) -> Self {
+        Self {
+            x: start,
+            y: seed,
+            stream,
+            fade: None,
+        }
+    }
+
+    pub(crate) fn sample_next(&mut self) -> f32 {
+        let x = self.x as f32;
+        let sample =
+            |seed: i32| OpenSimplex2.sample_with_seed([x, seed as f32 / 256.], self.stream);
+        let mut res = sample(self.y);
+        if let Some((from, left)) = self.fade {
+            res = res * (1. - left) + sample(from) * left;
+        }
+        // Nothing lasts long enough to get here, but a resumed state could start anywhere
+        self.x = self.x.wrapping_add(1);
+        (res + 1.) / 2.
+    }
+
+    pub(crate) fn sample_range(&mut self, range: Range<f32>) -> f32 {
+        self.sample_next() * (range.end - range.start) + range.start
+    }
+
+    // Carries on from the same place in another seed's noise
+    pub(crate) fn reseed(&mut self, seed: i32) {
+        self.y = seed;
+        self.fade = None;
+    }
+
+    pub(crate) fn seed(&self) -> i32 {
+        self.y
+    }
+
+    // Like reseed, but all of the old seed's noise stays in the mix until `set_fade` turns it down
+    pub(crate) fn crossfade(&mut self, seed: i32) {
+        if seed != self.y {
+            self.fade = Some((self.y, 1.));
+            self.y = seed;
+        }
+    }
+
+    // How much of the old seed's noise is left, from 1 for all of it to 0 for none
+    pub(crate) fn set_fade(&mut self, left: f32) {
+        self.fade = match self.fade {
+            Some((from, _)) if left > 0. => Some((from, left.min(1.))),
+            _ => None,
+        };
+    }
+
+    pub(crate) fn fade(&self) -> f32 {
+        self.fade.map_or(0., |(_, left)| left)
+    }
+
+    // Whether a saved rng could have come from this one, on the same stream with a fade `set_fade` would have left
+    pub(crate) fn fits(&self, saved: &NoiseRng) -> bool {
+        saved.stream == self.stream && saved.fade.is_none_or(|(_, left)| left > 0. && left <= 1.)
+    }
+}
+
+fn cumulative(mut weights: Vec<f32>) -> Vec<f32> {
+    let mut acc = 0.;
+    for x in weights.iter_mut() {
+        acc += *x;
+        *x = acc;
+    }
+    weights
+}
+
+// Conditions on up to the last `order` states, using the longest context it has weights for
+pub(crate) struct MarkovDistribution<T> {
+    // Cumulative weights, keyed by the context they follow, oldest state first. Every single state has a row.
+    rows: HashMap<Vec<usize>, Vec<f32>>,
+    slice: Vec<T>,
+    order: usize,
+    history: VecDeque<usize>,
+}
+
+impl<T: Copy> MarkovDistribution<T> {
+    pub(crate) fn new<const N: usize>(slice: [T; N], weights: [[f32; N]; N]) -> Self {
+        Self::from_rows(
+            slice.to_vec(),
+            weights.iter().map(|row| row.to_vec()).collect(),
+        )
+    }
+
+    pub(crate) fn from_rows(slice: Vec<T>, weights: Vec<Vec<f32>>) -> Self {
+        let rows = weights
+            .into_iter()
+            .enumerate()
+            .map(|(idx, row)| (vec![idx], cumulative(row)))
+            .collect();
+
+        Self {
+            rows,
+            history: VecDeque::from([slice.len() / 2]),
+            slice,
+            order: 1,
+        }
+    }
+
+    fn from_table(slice: Vec<T>, table: &TransitionTable, order: usize) -> Self {
+        let mut distribution = Self::from_rows(slice, table.first_order());
+        distribution.order = order.clamp(1, table.order);
+        // Start where the corpus spent most of its time, the middle state might never have shown up in it
+        if let Some(row) = table
+            .rows
+            .iter()
+            .filter(|row| row.context.len() == 1)
+            .max_by_key(|row| row.total)
+        {
+            distribution.start_at(row.context[0]);
+        }
+
+        let longer = table.rows.iter().filter(|row| {
+            (2..=distribution.order).contains(&row.context.len()) && row.total >= MIN_CONTEXT_COUNT
+        });
+        for row in longer {
+            distribution
+                .rows
+                .insert(row.context.clone(), cumulative(row.weights.clone()));
+        }
+
+        distribution
+    }
+
+    pub(crate) fn sample(&mut self, rng: &mut NoiseRng) -> T {
+        let row = current_row(&self.rows, &mut self.history);
+
+        let probability = rng.sample_next();
+
+        // Rows can add up to a hair under 1 after rounding
+        let next = row
+            .iter()
+            .position(|&x| probability < x)
+            .unwrap_or(row.len() - 1);
+
+        self.advance(next)
+    }
+
+    // Like sample, with the weight of every state multiplied by what `favour` says about it
+    pub(crate) fn sample_favouring(&mut self, rng: &mut NoiseRng, favour: impl Fn(T) -> f32) -> T {
+        let row = current_row(&self.rows, &mut self.history);
+
+        let mut below = 0.;
+        let weights: Vec<f32> = row
+            .iter()
+            .zip(&self.slice)
+            .map(|(&acc, &state)| {
+                let weight = (acc - below) * favour(state);
+                below = acc;
+                weight
+            })
+            .collect();
+        let total: f32 = weights.iter().sum();
+
+        let probability = rng.sample_next() * total;
+        let next = cumulative(weights)
+            .iter()
+            .position(|&x| probability < x)
+            .unwrap_or(row.len() - 1);
+
+        self.advance(next)
+    }
+
+    // Next sample follows this state, with no history before it
+    pub(crate) fn start_at(&mut self, state: usize) {
+        self.history = VecDeque::from([state]);
+    }
+
+    pub(crate) fn history(&self) -> Vec<usize> {
+        self.history.iter().copied().collect()
+    }
+
+    // A history saved from a distribution over the same states, if it could have come from one
+    pub(crate) fn check_history(&self, history: &[usize]) -> Option<VecDeque<usize>> {
+        if history.is_empty() || history.iter().any(|&state| state >= self.slice.len()) {
+            return None;
+        }
+
+        // A lower order than whoever saved it just forgets the oldest notes
+        let keep = history.len().min(self.order);
+        Some(history[history.len() - keep..].iter().copied().collect())
+    }
+
+    pub(crate) fn restore(&mut self, history: VecDeque<usize>) {
+        self.history = history;
+    }
+
+    fn advance(&mut self, next: usize) -> T {
+        if self.history.len() == self.order {
+            self.history.pop_front();
+        }
+        self.history.push_back(next);
+
+        self.slice[next]
+    }
+}
+
+// Weights after the longest context there are any for
+fn current_row<'a>(
+    rows: &'a HashMap<Vec<usize>, Vec<f32>>,
+    history: &mut VecDeque<usize>,
+) -> &'a [f32] {
+    let history = history.make_contiguous();
+    (1..=history.len())
+        .rev()
+        .find_map(|len| rows.get(&history[history.len() - len..]))
+        .expect("every state has a first order row")
+}
+
+fn sample_toward_chord<T: Copy>(
+    distribution: &mut MarkovDistribution<T>,
+    rng: &mut NoiseRng,
+    chord: &[i8],
+    pitch: impl Fn(T) -> i8,
+) -> T {
+    // Without a chord this has to stay exactly the plain sample, so melodies don't change
+    if chord.is_empty() {
+        return distribution.sample(rng);
+    }
+
+    distribution.sample_favouring(rng, |state| {
+        if chord.contains(&(pitch(state) - TONIC).rem_euclid(12)) {
+            CHORD_TONE_BIAS
+        } else {
+            1.
+        }
+    })
+}
+
+// How the pitch and duration of each note are picked
+enum Steps {
+    Separate {
+        pitch: MarkovDistribution<i8>,
+        duration: MarkovDistribution<u8>,
+    },
+    // Both at once, so rhythms stick to the notes they came with and motifs and cadences survive
+    Joint(MarkovDistribution<(i8, u8)>),
+}
+
+// Velocity drifts up and down between notes
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Dynamics {
+    velocity: f32,
+    deltav: f32,
+}
+
+impl Dynamics {
+    fn next(&mut self, rng: &mut NoiseRng) -> f32 {
+        self.deltav = if self.deltav.is_sign_negative() {
+            rng.sample_range(-0.03..0.1)
+        } else {
+            rng.sample_range(-0.1..0.03)
+        };
+
+        self.velocity += self.deltav;
+
+        if self.velocity < 0.3 {
+            self.velocity = 0.3;
+            self.deltav = 1.0;
+        } else if self.velocity > 0.8 {
+            self.velocity = 0.8;
+            self.deltav = -1.0;
+        }
+
+        self.velocity
+    }
+}
+
+// Histories of whichever Markov chains pick the steps
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum StepsState {
+    Separate {
+        pitch: Vec<usize>,
+        duration: Vec<usize>,
+    },
+    Joint(Vec<usize>),
+}
+
+// Semitones the key can be away from the tonic
+const KEY_OFFSETS: Range<f32> = -6.0..6.0;
+
+// The key a stream of `seed` from the start would be in
+pub fn key_for_seed(seed: i32) -> i8 {
+    NoiseRng::new(0, seed).sample_range(KEY_OFFSETS) as i8
+}
+
+// Sixteenths a resumed stream can be into it, which leaves years of notes before the position could overflow
+const MAX_POSITION: u32 = u32::MAX / 2;
+
+// Notes into a crossfade to another seed's noise, out of how many it takes
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct Crossfade {
+    notes: u32,
+    played: u32,
+    // The seed asked for while this one was still fading in, and over how many notes, which starts when it's done
+    next: Option<(i32, u32)>,
+}
+
+// Where a generator has got to, so one built with the same scale and model can carry on from there
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GeneratorState {
+    rng: NoiseRng,
+    steps: StepsState,
+    octave: Vec<usize>,
+    dynamics: Dynamics,
+    key_offset: i8,
+    chord: Vec<i8>,
+    meter: Meter,
+    position: u32,
+    crossfade: Option<Crossfade>,
+}
+
+pub struct NoteGenerator {
+    rng: NoiseRng,
+    steps: Steps,
+    octave: MarkovDistribution<i8>,
+
+    dynamics: Dynamics,
+    key_offset: i8,
+    // Pitch classes above the tonic of the chord under the melody, if there is one
+    chord: Vec<i8>,
+    meter: Meter,
+    // Sixteenths since the first note
+    position: u32,
+    crossfade: Option<Crossfade>,
+}
+
+impl NoteGenerator {
+    pub fn new(start: u32, seed: i32) -> Self {
+        Self::with_scale(start, seed, Scale::Major)
+    }
+
+    pub fn with_scale(start: u32, seed: i32, scale: Scale) -> Self {
+        Self::from_distributions(
+            start,
+            seed,
+            Steps::Separate {
+                pitch: MarkovDistribution::from_rows(scale.pitches(), scale.transitions()),
+                duration: MarkovDistribution::new(DURATIONS, DURATION_MARKOV),
+            },
+            MarkovDistribution::new(OCTAVES, OCTAVE_MARKOV),
+        )
+    }
+
+    // Conditions on up to `order` previous notes, as far as the model was trained for. A trained model only replaces
+    // the built in pitch transitions for scales with as many notes as the one it was trained on, its octave and
+    // duration transitions work with any scale. Models with a joint table pick pitch and duration together.
+    pub fn with_model(
+        start: u32,
+        seed: i32,
+        scale: Scale,
+        model: &MarkovModel,
+        order: usize,
+    ) -> Self {
+        let fits = model.pitch.states == scale.intervals().len();
+
+        let steps = match &model.joint {
+            Some(joint) if fits => {
+                let pairs = scale
+                    .pitches()
+                    .into_iter()
+                    .flat_map(|pitch| DURATIONS.map(|duration| (pitch, duration)))
+                    .collect();
+                Steps::Joint(MarkovDistribution::from_table(pairs, joint, order))
+            }
+            _ => Steps::Separate {
+                pitch: if fits {
+                    MarkovDistribution::from_table(scale.pitches(), &model.pitch, order)
+                } else {
+                    MarkovDistribution::from_rows(scale.pitches(), scale.transitions())
+                },
+                duration: MarkovDistribution::from_table(
+                    DURATIONS.to_vec(),
+                    &model.duration,
+                    order,
+                ),
+            },
+        };
+
+        Self::from_distributions(
+            start,
+            seed,
+            steps,
+            MarkovDistribution::from_table(OCTAVES.to_vec(), &model.octave, order),
+        )
+    }
+
+    fn from_distributions(
+        start: u32,
+        seed: i32,
+        steps: Steps,
+        octave: MarkovDistribution<i8>,
+    ) -> Self {
+        let mut rng = NoiseRng::new(start, seed);
+        let key_offset = rng.sample_range(KEY_OFFSETS) as i8;
+
+        Self {
+            rng,
+            steps,
+            octave,
+            dynamics: Dynamics {
+                velocity: 0.5,
+                deltav: 0.0,
+            },
+            key_offset,
+            chord: Vec::new(),
+            meter: Meter::for_seed(seed),
+            position: 0,
+            crossfade: None,
+        }
+    }
+
+    fn next_note(&mut self) -> Note {
+        // Every note of a crossfade has a little less of the old seed in it, the one after has none
+        if let Some(crossfade) = &mut self.crossfade {
+            crossfade.played += 1;
+            self.rng
+                .set_fade(1. - crossfade.played as f32 / (crossfade.notes + 1) as f32);
+            if crossfade.played > crossfade.notes {
+                let next = crossfade.next;
+                self.crossfade = None;
+                if let Some((seed, notes)) = next {
+                    self.crossfade(seed, notes);
+                }
+            }
+        }
+
+        // Same order of draws from the rng either way: pitch, octave, velocity, then duration if it's separate
+        let (pitch, velocity, duration) = match &mut self.steps {
+            Steps::Separate { pitch, duration } => (
+                sample_toward_chord(pitch, &mut self.rng, &self.chord, |pitch| pitch)
+                    + self.octave.sample(&mut self.rng)
+                    + self.key_offset,
+                self.dynamics.next(&mut self.rng),
+                duration.sample(&mut self.rng),
+            ),
+            Steps::Joint(joint) => {
+                let (pitch, duration) =
+                    sample_toward_chord(joint, &mut self.rng, &self.chord, |(pitch, _)| pitch);
+                (
+                    pitch + self.octave.sample(&mut self.rng) + self.key_offset,
+                    self.dynamics.next(&mut self.rng),
+                    duration,
+                )
+            }
+        };
+
+        // Notes that would run over the bar line stop at it, so every bar adds up
+        let bar = self.meter.bar_length();
+        let room = bar - self.position % bar;
+        let duration = duration.min(room.min(u8::MAX as u32) as u8);
+
+        let start = sixteenths_to_beats(self.position);
+        self.position += duration as u32;
+
+        Note {
+            start,
+            pitch,
+            velocity,
+            duration,
+        }
+    }
+
+    pub fn meter(&self) -> Meter {
+        self.meter
+    }
+
+    // Sixteenths since the first note
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    pub fn state(&self) -> GeneratorState {
+        let steps = match &self.steps {
+            Steps::Separate { pitch, duration } => StepsState::Separate {
+                pitch: pitch.history(),
+                duration: duration.history(),
+            },
+            Steps::Joint(joint) => StepsState::Joint(joint.history()),
+        };
+
+        GeneratorState {
+            rng: self.rng.clone(),
+            steps,
+            octave: self.octave.history(),
+            dynamics: self.dynamics,
+            key_offset: self.key_offset,
+            chord: self.chord.clone(),
+            meter: self.meter,
+            position: self.position,
+            crossfade: self.crossfade,
+        }
+    }
+
+    // Picks up where the generator that saved the state left off. Leaves this one as it was if the state doesn't fit.
+    pub fn resume(&mut self, state: &GeneratorState) -> Result<(), StateError> {
+        let mismatched = |part| move || StateError::Mismatched(part);
+
+        let steps = match (&self.steps, &state.steps) {
+            (
+                Steps::Separate { pitch, duration },
+                StepsState::Separate {
+                    pitch: p,
+                    duration: d,
+                },
+            ) => (
+                pitch.check_history(p).ok_or_else(mismatched("pitch"))?,
+                Some(
+                    duration
+                        .check_history(d)
+                        .ok_or_else(mismatched("duration"))?,
+                ),
+            ),
+            (Steps::Joint(joint), StepsState::Joint(history)) => (
+                joint
+                    .check_history(history)
+                    .ok_or_else(mismatched("joint"))?,
+                None,
+            ),
+            _ => return Err(StateError::Mismatched("steps")),
+        };
+        let octave = self
+            .octave
+            .check_history(&state.octave)
+            .ok_or_else(mismatched("octave"))?;
+        let Dynamics { velocity, deltav } = state.dynamics;
+        if !velocity.is_finite() || !deltav.is_finite() {
+            return Err(StateError::Mismatched("dynamics"));
+        }
+        if !self.rng.fits(&state.rng) {
+            return Err(StateError::Mismatched("noise"));
+        }
+        if !(-12..=12).contains(&state.key_offset) {
+            return Err(StateError::Mismatched("key"));
+        }
+        if !state.meter.is_valid() {
+            return Err(StateError::Mismatched("meter"));
+        }
+        if state.position > MAX_POSITION {
+            return Err(StateError::Mismatched("position"));
+        }
+        if matches!(state.crossfade, Some(Crossfade { notes, played, next })
+            if notes == 0 || played > notes || next.is_some_and(|(_, notes)| notes == 0))
+        {
+            return Err(StateError::Mismatched("crossfade"));
+        }
+
+        match (&mut self.steps, steps) {
+            (Steps::Separate { pitch, duration }, (p, Some(d))) => {
+                pitch.restore(p);
+                duration.restore(d);
+            }
+            (Steps::Joint(joint), (history, None)) => joint.restore(history),
+            _ => unreachable!("checked above"),
+        }
+        self.octave.restore(octave);
+        self.rng = state.rng.clone();
+        self.dynamics = Dynamics {
+            velocity: velocity.clamp(0.3, 0.8),
+            deltav,
+        };
+        self.key_offset = state.key_offset;
+        self.set_chord(&state.chord);
+        self.meter = state.meter;
+        self.position = state.position;
+        self.crossfade = state.crossfade;
+        Ok(())
+    }
+
+    // The rest of the melody comes from another seed's noise, in the same key and time
+    pub fn reseed(&mut self, seed: i32) {
+        self.rng.reseed(seed);
+        self.crossfade = None;
+    }
+
+    // Like reseed, but eases into the new seed's noise over the next `notes` notes. One asked for partway into
+    // another waits for it to finish, so the noise never jumps back to all of the seed being faded out.
+    pub fn crossfade(&mut self, seed: i32, notes: u32) {
+        if notes == 0 {
+            self.reseed(seed);
+        } else if let Some(crossfade) = &mut self.crossfade {
+            crossfade.next = (seed != self.rng.seed()).then_some((seed, notes));
+        } else if seed != self.rng.seed() {
+            self.rng.crossfade(seed);
+            self.crossfade = Some(Crossfade {
+                notes,
+                played: 0,
+                next: None,
+            });
+        }
+    }
+
+    // The seed the noise is coming from, or going to in a crossfade. One waiting its turn isn't it yet.
+    pub fn seed(&self) -> i32 {
+        self.rng.seed()
+    }
+
+    // How much of the old seed's noise the last note had in it, 0 outside a crossfade
+    pub fn fade(&self) -> f32 {
+        self.rng.fade()
+    }
+
+    pub fn key_offset(&self) -> i8 {
+        self.key_offset
+    }
+
+    // Semitones above the tonic, in any octave. An empty chord stops the melody leaning towards anything.
+    pub fn set_chord(&mut self, tones: &[i8]) {
+        self.chord = tones.iter().map(|tone| tone.rem_euclid(12)).collect();
+    }
+
+    pub fn transpose(&mut self, semitones: i8) {
+        self.key_offset += semitones;
+    }
+}
+
+impl Iterator for NoteGenerator {
+    type Item = Note;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_note())
+    }
+}
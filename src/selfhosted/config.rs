@@ -0,0 +1,273 @@
+use std::{
+    ffi::OsString,
+    fmt, fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand};
+use melodybrain::notes::Scale;
+use serde::Deserialize;
+
+const DEFAULT_SERVER: &str = "ravenclaw900.duckdns.org:2026";
+const DEFAULT_HTTP_PORT: u16 = 33445;
+// Has to stay under the server's liveness window, 10 seconds by default, or the client drops out between heartbeats
+const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 5;
+const DEFAULT_STATS_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_STATS_RETRIES: u32 = 3;
+// A new seed takes over at the start of the next chunk
+const DEFAULT_CROSSFADE_NOTES: u32 = 0;
+
+// Every option can come from a flag, an environment variable or the config file, in that order of priority
+#[derive(Debug, Parser)]
+#[command(version, about = "Hear the world's melody")]
+struct Args {
+    /// TOML config file, defaults to ~/.melodybrain/config.toml if it exists
+    #[arg(long, env = "MELODYBRAIN_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address of the upstream melodybrain-server
+    #[arg(long, env = "MELODYBRAIN_SERVER")]
+    server: Option<String>,
+
+    /// Address to serve the web page on
+    #[arg(long, env = "MELODYBRAIN_HTTP_ADDR")]
+    http_addr: Option<IpAddr>,
+
+    /// Port to serve the web page on
+    #[arg(long, env = "MELODYBRAIN_HTTP_PORT")]
+    http_port: Option<u16>,
+
+    /// Seconds between background heartbeats, keep it below the server's liveness window
+    #[arg(long, env = "MELODYBRAIN_HEARTBEAT_INTERVAL_SECS")]
+    heartbeat_interval_secs: Option<u64>,
+
+    /// Milliseconds to wait for the server to answer a stats request
+    #[arg(long, env = "MELODYBRAIN_STATS_TIMEOUT_MS")]
+    stats_timeout_ms: Option<u64>,
+
+    /// How many more times to ask for stats before falling back to the last known ones
+    #[arg(long, env = "MELODYBRAIN_STATS_RETRIES")]
+    stats_retries: Option<u32>,
+
+    /// Markov model file written by the train command, replaces the built in transition tables
+    #[arg(long, env = "MELODYBRAIN_MODEL")]
+    model: Option<PathBuf>,
+
+    /// How many previous notes the model's transitions depend on, defaults to as many as it was trained with
+    #[arg(long, env = "MELODYBRAIN_MODEL_ORDER")]
+    model_order: Option<usize>,
+
+    /// Eases into a new seed over this many notes instead of switching at the next chunk, changing key through a
+    /// pivot chord. 0 switches straight away.
+    #[arg(long, env = "MELODYBRAIN_CROSSFADE_NOTES")]
+    crossfade_notes: Option<u32>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Render a melody to a WAV file instead of starting the player
+    Render(RenderArgs),
+    /// Learn transition tables from a directory of MIDI files and write them to a model file
+    Train(TrainArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct RenderArgs {
+    /// Seed to generate the melody from
+    #[arg(long, allow_negative_numbers = true)]
+    pub seed: i32,
+
+    /// Index of the first note
+    #[arg(long, default_value_t = 0)]
+    pub start: u32,
+
+    /// Number of notes to render
+    #[arg(long, default_value_t = 128)]
+    pub count: usize,
+
+    /// major, natural_minor, harmonic_minor, melodic_minor, dorian, mixolydian, pentatonic, blues or whole_tone
+    #[arg(long, default_value = "major")]
+    pub scale: Scale,
+
+    /// Leave out the bass, pad and arpeggio
+    #[arg(long)]
+    pub melody_only: bool,
+
+    /// WAV file to write, defaults to melodybrain-<seed>.wav
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct TrainArgs {
+    /// Directory to search for .mid and .midi files, including subdirectories
+    pub dir: PathBuf,
+
+    /// Model file to write
+    #[arg(long, short, default_value = "model.json")]
+    pub output: PathBuf,
+
+    /// How many previous notes the transitions depend on
+    #[arg(long, default_value_t = 1)]
+    pub order: usize,
+
+    /// Also learn pitch and duration together, so rhythms stay attached to the notes they came with
+    #[arg(long)]
+    pub joint: bool,
+
+    /// Scale the melodies are quantized to, the model only replaces pitch transitions for scales with as many notes
+    #[arg(long, default_value = "major")]
+    pub scale: Scale,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: Option<String>,
+    http_addr: Option<IpAddr>,
+    http_port: Option<u16>,
+    heartbeat_interval_secs: Option<u64>,
+    stats_timeout_ms: Option<u64>,
+    stats_retries: Option<u32>,
+    model: Option<PathBuf>,
+    model_order: Option<usize>,
+    crossfade_notes: Option<u32>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub server: String,
+    pub http_addr: SocketAddr,
+    pub heartbeat_interval: Duration,
+    pub stats_timeout: Duration,
+    pub stats_retries: u32,
+    pub model: Option<PathBuf>,
+    pub model_order: Option<usize>,
+    pub crossfade_notes: u32,
+    pub command: Option<Command>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Zero(&'static str),
+    // Not a host and port
+    Server(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::Parse(path, err) => write!(f, "invalid config file {}: {err}", path.display()),
+            Self::Zero(option) => write!(f, "{option} must be greater than zero"),
+            Self::Server(server) => {
+                write!(
+                    f,
+                    "invalid server {server:?}, expected something like example.com:2026"
+                )
+            }
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::parse_from(std::env::args_os())
+    }
+
+    // Like `load` with other command line arguments, the environment still counts
+    pub fn parse_from(
+        args: impl IntoIterator<Item = impl Into<OsString> + Clone>,
+    ) -> Result<Self, ConfigError> {
+        let args = Args::parse_from(args);
+
+        let file = match args.config {
+            Some(path) => read_file(path)?,
+            None => match std::env::home_dir() {
+                Some(home) => {
+                    let path = home.join(".melodybrain/config.toml");
+                    if path.exists() {
+                        read_file(path)?
+                    } else {
+                        FileConfig::default()
+                    }
+                }
+                None => FileConfig::default(),
+            },
+        };
+
+        let http_addr = args
+            .http_addr
+            .or(file.http_addr)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let http_port = args
+            .http_port
+            .or(file.http_port)
+            .unwrap_or(DEFAULT_HTTP_PORT);
+
+        let heartbeat_interval = args
+            .heartbeat_interval_secs
+            .or(file.heartbeat_interval_secs)
+            .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL_SECS);
+        if heartbeat_interval == 0 {
+            return Err(ConfigError::Zero("heartbeat_interval_secs"));
+        }
+
+        let stats_timeout = args
+            .stats_timeout_ms
+            .or(file.stats_timeout_ms)
+            .unwrap_or(DEFAULT_STATS_TIMEOUT_MS);
+        if stats_timeout == 0 {
+            return Err(ConfigError::Zero("stats_timeout_ms"));
+        }
+
+        let server = args
+            .server
+            .or(file.server)
+            .unwrap_or_else(|| DEFAULT_SERVER.to_owned());
+        // Resolved once the client starts, this only catches what could never work
+        match server.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(ConfigError::Server(server)),
+        }
+
+        let model_order = args.model_order.or(file.model_order);
+        if model_order == Some(0) {
+            return Err(ConfigError::Zero("model_order"));
+        }
+
+        Ok(Self {
+            server,
+            http_addr: SocketAddr::new(http_addr, http_port),
+            heartbeat_interval: Duration::from_secs(heartbeat_interval),
+            stats_timeout: Duration::from_millis(stats_timeout),
+            stats_retries: args
+                .stats_retries
+                .or(file.stats_retries)
+                .unwrap_or(DEFAULT_STATS_RETRIES),
+            model: args.model.or(file.model),
+            model_order,
+            crossfade_notes: args
+                .crossfade_notes
+                .or(file.crossfade_notes)
+                .unwrap_or(DEFAULT_CROSSFADE_NOTES),
+            command: args.command,
+        })
+    }
+}
+
+fn read_file(path: PathBuf) -> Result<FileConfig, ConfigError> {
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => return Err(ConfigError::Read(path, err)),
+    };
+
+    toml::from_str(&contents).map_err(|err| ConfigError::Parse(path, err))
+}
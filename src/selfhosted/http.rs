@@ -1,23 +1,25 @@
-use std::sync::{
-    Arc,
-    atomic::{AtomicI32, Ordering},
-};
+use std::sync::{Arc, atomic::Ordering};
 
-use axum::{
-    Form, Json, Router,
-    extract::State,
-    http::header,
-    response::{IntoResponse, Response},
-    routing::get,
+use axum::{Form, Json, Router, extract::State, http::header, response::Response, routing::get};
+use melodybrain::{
+    COUNTRIES, WORLDWIDE,
+    client::Fetched,
+    harmony::Track,
+    meter::Meter,
+    midi::{self, SmfFormat},
+    notes::{Note, Scale},
+    search_country,
+    stream::StreamState,
+    synth::{self, Synth},
 };
-use melodybrain::{COUNTRIES, WORLDWIDE, search_country};
 use serde::{Deserialize, Serialize};
-use tokio::net::UdpSocket;
 
-use crate::{
-    generate_seed,
-    notes::{Note, NoteGenerator},
-};
+use crate::generate_seed;
+
+// Enough for about half an hour at a typical tempo
+const MAX_EXPORT_NOTES: usize = 10_000;
+// Rendered audio is a lot bigger, this is a few minutes or about 30MB
+const MAX_RENDER_NOTES: usize = 1000;
 
 pub type ArcState = Arc<crate::State>;
 
@@ -25,6 +27,8 @@ pub fn router(state: ArcState) -> Router {
     Router::new()
         .route("/", get(index))
         .route("/data", get(data))
+        .route("/export.mid", get(export_midi))
+        .route("/render.wav", get(render_wav))
         .with_state(state)
 }
 
@@ -37,18 +41,43 @@ async fn index() -> Response {
 
 #[derive(Debug, Serialize)]
 pub struct Data {
+    // Tempo and time signature the notes are timed in, starts are in beats since the first note
+    meter: Meter,
     notes: Vec<Note>,
+    tracks: Vec<Track>,
     seed: i32,
+    scale: Scale,
     connected: u32,
     heatmap: Vec<f32>,
+    // The server didn't answer, so connected and heatmap are from its last reply
+    stale: bool,
+    // The server didn't answer and never has, so there are no stats at all and the global seed is the local one
+    offline: bool,
+    // Pass it back for the next chunk, which carries on right where this one stops
+    state: String,
+}
+
+// A state token from an earlier chunk, if there is one that decodes
+fn stream_state(token: &str) -> Option<StreamState> {
+    if token.is_empty() {
+        return None;
+    }
+
+    StreamState::from_token(token)
+        .inspect_err(|err| eprintln!("ignoring stream state: {err}"))
+        .ok()
 }
 
 #[derive(Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct DataForm {
+    // Where a new stream starts, when there's no state to carry on from
     idx: u32,
+    state: String,
     seed: SeedType,
     country: String,
+    // Defaults to the country's own mode
+    scale: Option<Scale>,
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -64,15 +93,19 @@ enum SeedType {
 async fn data(State(state): State<ArcState>, Form(form): Form<DataForm>) -> Json<Data> {
     let country_code = search_country(&form.country.to_ascii_uppercase()).unwrap_or(WORLDWIDE);
 
-    let stats = loop {
-        if let Some(stats) = state.send_heartbeat(country_code).await {
-            break stats;
-        }
+    let local_seed = state.local_seed.load(Ordering::Relaxed);
+    let (stats, stale) = match state.client.fetch_stats(country_code, local_seed).await {
+        Fetched::Fresh(stats) => (Some(stats), false),
+        Fetched::Stale(stats) => (Some(stats), true),
+        Fetched::Offline => (None, false),
     };
 
     let seed = match form.seed {
         SeedType::Local => state.local_seed.load(Ordering::Relaxed),
-        SeedType::Global => stats.seed,
+        SeedType::Global => match &stats {
+            Some(stats) => stats.seed,
+            None => state.local_seed.load(Ordering::Relaxed),
+        },
         SeedType::NewLocal => {
             // This probably violates some rule of atomics, but at least it won't cause UB
             let new = generate_seed();
@@ -82,17 +115,162 @@ async fn data(State(state): State<ArcState>, Form(form): Form<DataForm>) -> Json
     };
 
     let heatmap: Vec<f32> = stats
-        .country_heatmap
+        .as_ref()
+        .map_or([0.0; COUNTRIES.len()], |stats| stats.country_heatmap)
         .into_iter()
         .enumerate()
         .filter_map(|(idx, x)| COUNTRIES[idx].1.then_some(x))
         .collect();
 
-    let notes: Vec<_> = NoteGenerator::new(form.idx, seed).take(128).collect();
+    let scale = form
+        .scale
+        .unwrap_or_else(|| Scale::for_country(country_code));
+    let mut arranger = state.resume(stream_state(&form.state).as_ref(), form.idx, seed, scale);
+    let arrangement = arranger.arrange(128);
     Json(Data {
-        notes,
+        meter: arrangement.meter,
+        notes: arrangement.melody,
+        tracks: arrangement.tracks,
         seed,
-        connected: stats.connected,
+        scale,
+        connected: stats.as_ref().map_or(0, |stats| stats.connected),
         heatmap,
+        stale,
+        offline: stats.is_none(),
+        state: arranger.state().to_token(),
     })
 }
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct ExportForm {
+    // Defaults to the local seed
+    seed: Option<i32>,
+    scale: Scale,
+    idx: u32,
+    // Starts where the chunk this state came from did, instead of at idx
+    state: String,
+    count: usize,
+    // Defaults to the seed's own tempo
+    bpm: Option<u16>,
+    // SMF format, 0 or 1
+    format: u8,
+    // Leave out the accompaniment
+    melody_only: bool,
+}
+
+impl Default for ExportForm {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            scale: Scale::Major,
+            idx: 0,
+            state: String::new(),
+            count: 128,
+            bpm: None,
+            format: 1,
+            melody_only: false,
+        }
+    }
+}
+
+async fn export_midi(State(state): State<ArcState>, Form(form): Form<ExportForm>) -> Response {
+    let seed = form
+        .seed
+        .unwrap_or_else(|| state.local_seed.load(Ordering::Relaxed));
+    let format = if form.format == 0 {
+        SmfFormat::SingleTrack
+    } else {
+        SmfFormat::MultiTrack
+    };
+
+    let count = form.count.min(MAX_EXPORT_NOTES);
+    let stream = stream_state(&form.state);
+    let smf = if form.melody_only {
+        let generator = state.resume_melody(stream.as_ref(), form.idx, seed, form.scale);
+        let meter = generator.meter();
+        let bpm = form.bpm.unwrap_or(meter.bpm);
+        let notes: Vec<_> = generator.take(count).collect();
+        midi::write_smf(&notes, meter.time_signature, bpm, format)
+    } else {
+        let arrangement = state
+            .resume(stream.as_ref(), form.idx, seed, form.scale)
+            .arrange(count);
+        let bpm = form.bpm.unwrap_or(arrangement.meter.bpm);
+        midi::write_arrangement(&arrangement, bpm, format)
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "audio/midi")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"melodybrain-{seed}.mid\""),
+        )
+        .body(smf.into())
+        .unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct RenderForm {
+    // Defaults to the local seed
+    seed: Option<i32>,
+    scale: Scale,
+    idx: u32,
+    // Starts where the chunk this state came from did, instead of at idx
+    state: String,
+    count: usize,
+    // Leave out the accompaniment
+    melody_only: bool,
+}
+
+impl Default for RenderForm {
+    fn default() -> Self {
+        Self {
+            seed: None,
+            scale: Scale::Major,
+            idx: 0,
+            state: String::new(),
+            count: 128,
+            melody_only: false,
+        }
+    }
+}
+
+async fn render_wav(State(state): State<ArcState>, Form(form): Form<RenderForm>) -> Response {
+    let seed = form
+        .seed
+        .unwrap_or_else(|| state.local_seed.load(Ordering::Relaxed));
+
+    let count = form.count.min(MAX_RENDER_NOTES);
+    let synth = Synth::default();
+    // Rendering takes a while for long melodies, keep it off the runtime
+    let stream = stream_state(&form.state);
+    let wav = if form.melody_only {
+        let generator = state.resume_melody(stream.as_ref(), form.idx, seed, form.scale);
+        let meter = generator.meter();
+        let notes = generator.take(count);
+        tokio::task::spawn_blocking(move || {
+            synth::write_wav(&synth.render(notes, meter, seed), synth.sample_rate)
+        })
+    } else {
+        let arrangement = state
+            .resume(stream.as_ref(), form.idx, seed, form.scale)
+            .arrange(count);
+        tokio::task::spawn_blocking(move || {
+            let samples = synth.render_arrangement(&arrangement, seed);
+            synth::write_wav(&samples, synth.sample_rate)
+        })
+    }
+    .await
+    .expect("render task panicked");
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "audio/wav")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"melodybrain-{seed}.wav\""),
+        )
+        .body(wav.into())
+        .unwrap()
+}
@@ -1,14 +1,105 @@
-use std::sync::{Arc, atomic::AtomicI32};
-use tokio::net::{TcpListener, UdpSocket};
+use std::{
+    fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{Arc, atomic::AtomicI32},
+};
 
+use melodybrain::{
+    client::Client,
+    harmony::Arranger,
+    midi,
+    model::{MarkovModel, Trainer},
+    notes::{NoteGenerator, Scale},
+    stream::StreamState,
+    synth::{self, Synth},
+};
+use tokio::net::{TcpListener, UdpSocket, lookup_host};
+
+use crate::config::{Command, Config, RenderArgs, TrainArgs};
+
+mod config;
 mod http;
-mod notes;
 mod udp;
 
 #[derive(Debug)]
 pub struct State {
-    pub sock: UdpSocket,
+    pub client: Client,
     pub local_seed: AtomicI32,
+    // Trained transition tables to use instead of the built in ones
+    pub model: Option<MarkovModel>,
+    pub config: Config,
+}
+
+impl State {
+    pub fn generator(&self, start: u32, seed: i32, scale: Scale) -> NoteGenerator {
+        new_generator(
+            start,
+            seed,
+            scale,
+            self.model.as_ref(),
+            self.config.model_order,
+        )
+    }
+
+    pub fn arranger(&self, start: u32, seed: i32, scale: Scale) -> Arranger {
+        Arranger::new(self.generator(start, seed, scale), start, seed, scale)
+    }
+
+    // Carries on from where the last chunk left off, or starts over at `start` if there's no state or it doesn't fit,
+    // like one from before the model changed. A different seed steers the rest of the stream, straight away or over
+    // the configured number of notes.
+    pub fn resume(
+        &self,
+        state: Option<&StreamState>,
+        start: u32,
+        seed: i32,
+        scale: Scale,
+    ) -> Arranger {
+        let mut arranger = self.arranger(start, seed, scale);
+        if let Some(state) = state {
+            match arranger.resume(state) {
+                Ok(()) => arranger.crossfade(seed, self.config.crossfade_notes),
+                Err(err) => eprintln!("starting a new stream: {err}"),
+            }
+        }
+        arranger
+    }
+
+    // Same as resume, for just the melody
+    pub fn resume_melody(
+        &self,
+        state: Option<&StreamState>,
+        start: u32,
+        seed: i32,
+        scale: Scale,
+    ) -> NoteGenerator {
+        let mut generator = self.generator(start, seed, scale);
+        if let Some(state) = state {
+            match generator.resume(state.melody()) {
+                Ok(()) => generator.crossfade(seed, self.config.crossfade_notes),
+                Err(err) => eprintln!("starting a new stream: {err}"),
+            }
+        }
+        generator
+    }
+}
+
+fn new_generator(
+    start: u32,
+    seed: i32,
+    scale: Scale,
+    model: Option<&MarkovModel>,
+    order: Option<usize>,
+) -> NoteGenerator {
+    match model {
+        Some(model) => {
+            let order = order.unwrap_or(model.pitch.order);
+            NoteGenerator::with_model(start, seed, scale, model, order)
+        }
+        None => NoteGenerator::with_scale(start, seed, scale),
+    }
 }
 
 fn generate_seed() -> i32 {
@@ -17,27 +108,156 @@ fn generate_seed() -> i32 {
     i32::from_ne_bytes(bytes)
 }
 
+fn fail(msg: impl std::fmt::Display) -> ! {
+    eprintln!("error: {msg}");
+    exit(1)
+}
+
+fn load_model(config: &Config) -> Option<MarkovModel> {
+    let path = config.model.as_ref()?;
+    let model = MarkovModel::load(path)
+        .unwrap_or_else(|err| fail(format_args!("{}: {err}", path.display())));
+    Some(model)
+}
+
+fn render(args: &RenderArgs, model: Option<&MarkovModel>, order: Option<usize>) {
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| format!("melodybrain-{}.wav", args.seed).into());
+
+    let generator = new_generator(args.start, args.seed, args.scale, model, order);
+    let synth = Synth::default();
+    let samples = if args.melody_only {
+        let meter = generator.meter();
+        synth.render(generator.take(args.count), meter, args.seed)
+    } else {
+        let arrangement =
+            Arranger::new(generator, args.start, args.seed, args.scale).arrange(args.count);
+        synth.render_arrangement(&arrangement, args.seed)
+    };
+
+    fs::write(&output, synth::write_wav(&samples, synth.sample_rate))
+        .unwrap_or_else(|err| fail(format_args!("failed to write {}: {err}", output.display())));
+    println!(
+        "wrote {:.1}s of audio to {}",
+        samples.len() as f32 / synth.sample_rate as f32,
+        output.display()
+    );
+}
+
+fn find_midi_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_midi_files(&path, files)?;
+        } else if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+        {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+fn train(args: &TrainArgs) {
+    let mut files = Vec::new();
+    find_midi_files(&args.dir, &mut files)
+        .unwrap_or_else(|err| fail(format_args!("failed to read {}: {err}", args.dir.display())));
+    files.sort();
+
+    let mut trainer = Trainer::new(args.scale, args.order, args.joint);
+    let mut used = 0;
+    for path in &files {
+        // One broken file shouldn't throw away the rest of the corpus
+        let smf = match fs::read(path) {
+            Ok(bytes) => midi::read_smf(&bytes).map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        match smf {
+            Ok(smf) => {
+                trainer.add(&smf);
+                used += 1;
+            }
+            Err(err) => eprintln!("warning: skipping {}: {err}", path.display()),
+        }
+    }
+
+    if trainer.notes() == 0 {
+        fail(format_args!("no notes found in {}", args.dir.display()));
+    }
+
+    println!("learned from {} notes in {used} files", trainer.notes());
+    trainer.finish().save(&args.output).unwrap_or_else(|err| {
+        fail(format_args!(
+            "failed to write {}: {err}",
+            args.output.display()
+        ))
+    });
+    println!("wrote model to {}", args.output.display());
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
-    let listener = TcpListener::bind("0.0.0.0:33445")
-        .await
-        .expect("failed to bind listener to port 33445");
+    let config = Config::load().unwrap_or_else(|err| fail(err));
+
+    // Training doesn't need a model, and the configured one might be what it's about to write
+    if let Some(Command::Train(args)) = &config.command {
+        train(args);
+        return;
+    }
+
+    let model = load_model(&config);
 
-    let connector = UdpSocket::bind("0.0.0.0:0")
+    if let Some(Command::Render(args)) = &config.command {
+        render(args, model.as_ref(), config.model_order);
+        return;
+    }
+
+    let listener = TcpListener::bind(config.http_addr)
         .await
-        .expect("failed to bind UDP socket");
+        .unwrap_or_else(|err| {
+            fail(format_args!(
+                "failed to bind to {}: {err}",
+                config.http_addr
+            ))
+        });
+
+    let server = match lookup_host(&config.server).await {
+        Ok(mut addrs) => addrs.next(),
+        Err(err) => fail(format_args!(
+            "failed to resolve server {}: {err}",
+            config.server
+        )),
+    }
+    .unwrap_or_else(|| fail(format_args!("server {} has no addresses", config.server)));
 
-    connector
-        .connect("ravenclaw900.duckdns.org:2026")
-        // .connect("localhost:2026")
+    // Bind to the same address family as the server, now that it can be reached over IPv6
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let connector = UdpSocket::bind(local)
         .await
-        .expect("failed to connect to main server - run your own perhaps ;)");
+        .unwrap_or_else(|err| fail(format_args!("failed to bind UDP socket: {err}")));
+
+    connector.connect(server).await.unwrap_or_else(|err| {
+        fail(format_args!(
+            "failed to connect to main server {} - run your own perhaps ;) ({err})",
+            config.server
+        ))
+    });
 
     let state = Arc::new(State {
-        sock: connector,
+        client: Client::new(connector, config.stats_timeout, config.stats_retries),
         local_seed: AtomicI32::new(generate_seed()),
+        model,
+        config,
     });
 
+    tokio::spawn(udp::dispatch(Arc::clone(&state)));
     tokio::spawn(udp::heartbeats(Arc::clone(&state)));
 
     axum::serve(listener, http::router(state))
@@ -0,0 +1,399 @@
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    thread::available_parallelism,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use melodybrain::{
+    aggregate::Aggregation,
+    geo::{BadOverride, Override},
+    heartbeat::RateLimit,
+};
+use serde::Deserialize;
+
+const DEFAULT_PORT: u16 = 2026;
+const DEFAULT_GEOIP_DB: &str = "./GeoLite2-Country.mmdb";
+const DEFAULT_IPV4_DB: &str = "./ipv4.bin";
+const DEFAULT_IPV6_DB: &str = "./ipv6.bin";
+const DEFAULT_LIVENESS_WINDOW_SECS: u64 = 10;
+const DEFAULT_CLEANUP_PERIOD_SECS: u64 = 20;
+const DEFAULT_AVERAGING_DIVISOR: i64 = 2000;
+const DEFAULT_AGGREGATION_WINDOW: usize = 101;
+const DEFAULT_TRIM_PERCENT: u8 = 20;
+const DEFAULT_MERGE_INTERVAL_MS: u64 = 200;
+const DEFAULT_CHECKPOINT_PERIOD_SECS: u64 = 60;
+// Per /24 or /64, which can easily be a whole office or university behind one NAT
+const DEFAULT_RATE_LIMIT_BURST: u16 = 100;
+const DEFAULT_RATE_LIMIT_PER_SEC: u16 = 10;
+
+// Flags take priority over the config file
+#[derive(Debug, Parser)]
+#[command(version, about = "Central server for MelodyBrain clients")]
+struct Args {
+    /// TOML config file
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Address to listen for heartbeats on
+    #[arg(long)]
+    bind_addr: Option<IpAddr>,
+
+    /// UDP port to listen for heartbeats on
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// MaxMind country database, clients stay unknown if it's missing
+    #[arg(long)]
+    geoip_db: Option<PathBuf>,
+
+    /// CSV of IP ranges and country codes in the IP2Location or DB-IP lite format, asked after the MaxMind database
+    #[arg(long)]
+    geoip_csv: Option<PathBuf>,
+
+    /// Country for a subnet that overrides the databases, can be repeated
+    #[arg(long = "geo-override", value_name = "SUBNET=COUNTRY")]
+    geo_overrides: Vec<Override>,
+
+    /// Sparse database of IPv4 clients and country stats, created if missing
+    #[arg(long)]
+    ipv4_db: Option<PathBuf>,
+
+    /// Sparse database of IPv6 clients, created if missing
+    #[arg(long)]
+    ipv6_db: Option<PathBuf>,
+
+    /// Where client records and country stats are kept, memory forgets everything on restart
+    #[arg(long, value_enum)]
+    store: Option<StoreKind>,
+
+    /// Seconds without a heartbeat before a client stops counting as active
+    #[arg(long)]
+    liveness_window_secs: Option<u64>,
+
+    /// Seconds between sweeps for inactive clients
+    #[arg(long)]
+    cleanup_period_secs: Option<u64>,
+
+    /// How country seeds are derived from the seeds of their clients
+    #[arg(long, value_enum)]
+    aggregation: Option<AggregationMode>,
+
+    /// How slowly seeds move towards each heartbeat with the ema aggregation, a heartbeat moves them by 1/divisor of the difference
+    #[arg(long)]
+    averaging_divisor: Option<i64>,
+
+    /// How many recent contributors per country the median and trimmed-mean aggregations look at
+    #[arg(long)]
+    aggregation_window: Option<usize>,
+
+    /// Percentage of the lowest and of the highest seeds the trimmed-mean aggregation ignores, below 50
+    #[arg(long)]
+    trim_percent: Option<u8>,
+
+    /// Threads receiving heartbeats, defaults to the number of cores
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// Milliseconds between folding every shard's changes into the country stats sent to clients
+    #[arg(long)]
+    merge_interval_ms: Option<u64>,
+
+    /// Packets a single /24 or /64 can send in a burst
+    #[arg(long)]
+    rate_limit_burst: Option<u16>,
+
+    /// Packets per second a single /24 or /64 can keep sending
+    #[arg(long)]
+    rate_limit_per_sec: Option<u16>,
+
+    /// Seconds between flushing the databases to disk, a crash of the whole machine loses at most this much
+    #[arg(long)]
+    checkpoint_period_secs: Option<u64>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Write a compact copy of the databases' used records, the server must not be running
+    Snapshot {
+        /// Snapshot file to write, replaced in one go
+        output: PathBuf,
+    },
+    /// Replace the databases with the records from a snapshot, the server must not be running
+    Restore {
+        /// Snapshot file written by the snapshot command
+        input: PathBuf,
+    },
+    /// Check every country's active, unique and duration counts against the client records, the server must not be
+    /// running
+    Fsck {
+        /// Write the recounted values over the ones that are off
+        #[arg(long)]
+        repair: bool,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind_addr: Option<IpAddr>,
+    port: Option<u16>,
+    geoip_db: Option<PathBuf>,
+    geoip_csv: Option<PathBuf>,
+    // Subnet to country code
+    geo_overrides: BTreeMap<String, String>,
+    ipv4_db: Option<PathBuf>,
+    ipv6_db: Option<PathBuf>,
+    store: Option<StoreKind>,
+    liveness_window_secs: Option<u64>,
+    cleanup_period_secs: Option<u64>,
+    aggregation: Option<AggregationMode>,
+    averaging_divisor: Option<i64>,
+    aggregation_window: Option<usize>,
+    trim_percent: Option<u8>,
+    workers: Option<usize>,
+    merge_interval_ms: Option<u64>,
+    rate_limit_burst: Option<u16>,
+    rate_limit_per_sec: Option<u16>,
+    checkpoint_period_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub addr: SocketAddr,
+    pub geoip_db: PathBuf,
+    pub geoip_csv: Option<PathBuf>,
+    pub geo_overrides: Vec<Override>,
+    pub ipv4_db: PathBuf,
+    pub ipv6_db: PathBuf,
+    pub store: StoreKind,
+    pub update: UpdateConfig,
+    pub cleanup_period: u64,
+    pub workers: usize,
+    pub merge_interval: Duration,
+    pub rate_limit: RateLimit,
+    pub checkpoint_period: u64,
+    pub command: Option<Command>,
+}
+
+// The knobs of the per-heartbeat update logic
+#[derive(Clone, Copy, Debug)]
+pub struct UpdateConfig {
+    pub liveness_window: u64,
+    pub aggregation: Aggregation,
+    pub aggregation_window: usize,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+enum AggregationMode {
+    Ema,
+    Median,
+    TrimmedMean,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreKind {
+    // The sparse database files
+    #[default]
+    Mmap,
+    Memory,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    NotPositive(&'static str),
+    TrimTooHigh(u8),
+    Override(BadOverride),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Read(path, err) => write!(f, "failed to read {}: {err}", path.display()),
+            Self::Parse(path, err) => write!(f, "invalid config file {}: {err}", path.display()),
+            Self::NotPositive(option) => write!(f, "{option} must be greater than zero"),
+            Self::TrimTooHigh(percent) => {
+                write!(f, "trim_percent must be below 50, got {percent}")
+            }
+            Self::Override(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::parse_from(std::env::args_os())
+    }
+
+    // Like `load` with other command line arguments
+    pub fn parse_from(
+        args: impl IntoIterator<Item = impl Into<OsString> + Clone>,
+    ) -> Result<Self, ConfigError> {
+        let args = Args::parse_from(args);
+
+        let file = match args.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+
+        let bind_addr = args
+            .bind_addr
+            .or(file.bind_addr)
+            .unwrap_or(IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        let port = args.port.or(file.port).unwrap_or(DEFAULT_PORT);
+
+        let liveness_window = args
+            .liveness_window_secs
+            .or(file.liveness_window_secs)
+            .unwrap_or(DEFAULT_LIVENESS_WINDOW_SECS);
+        if liveness_window == 0 {
+            return Err(ConfigError::NotPositive("liveness_window_secs"));
+        }
+
+        let cleanup_period = args
+            .cleanup_period_secs
+            .or(file.cleanup_period_secs)
+            .unwrap_or(DEFAULT_CLEANUP_PERIOD_SECS);
+        if cleanup_period == 0 {
+            return Err(ConfigError::NotPositive("cleanup_period_secs"));
+        }
+
+        let averaging_divisor = args
+            .averaging_divisor
+            .or(file.averaging_divisor)
+            .unwrap_or(DEFAULT_AVERAGING_DIVISOR);
+        if averaging_divisor <= 0 {
+            return Err(ConfigError::NotPositive("averaging_divisor"));
+        }
+
+        let aggregation_window = args
+            .aggregation_window
+            .or(file.aggregation_window)
+            .unwrap_or(DEFAULT_AGGREGATION_WINDOW);
+        if aggregation_window == 0 {
+            return Err(ConfigError::NotPositive("aggregation_window"));
+        }
+
+        let trim_percent = args
+            .trim_percent
+            .or(file.trim_percent)
+            .unwrap_or(DEFAULT_TRIM_PERCENT);
+        if trim_percent >= 50 {
+            return Err(ConfigError::TrimTooHigh(trim_percent));
+        }
+
+        let aggregation = match args
+            .aggregation
+            .or(file.aggregation)
+            .unwrap_or(AggregationMode::Ema)
+        {
+            AggregationMode::Ema => Aggregation::Ema {
+                divisor: averaging_divisor,
+            },
+            AggregationMode::Median => Aggregation::Median,
+            AggregationMode::TrimmedMean => Aggregation::TrimmedMean { trim_percent },
+        };
+
+        let workers = args
+            .workers
+            .or(file.workers)
+            .unwrap_or_else(|| available_parallelism().map_or(1, |n| n.get()));
+        if workers == 0 {
+            return Err(ConfigError::NotPositive("workers"));
+        }
+
+        let merge_interval = args
+            .merge_interval_ms
+            .or(file.merge_interval_ms)
+            .unwrap_or(DEFAULT_MERGE_INTERVAL_MS);
+        if merge_interval == 0 {
+            return Err(ConfigError::NotPositive("merge_interval_ms"));
+        }
+
+        let rate_limit = RateLimit {
+            burst: args
+                .rate_limit_burst
+                .or(file.rate_limit_burst)
+                .unwrap_or(DEFAULT_RATE_LIMIT_BURST),
+            per_sec: args
+                .rate_limit_per_sec
+                .or(file.rate_limit_per_sec)
+                .unwrap_or(DEFAULT_RATE_LIMIT_PER_SEC),
+        };
+        if rate_limit.burst == 0 {
+            return Err(ConfigError::NotPositive("rate_limit_burst"));
+        }
+        if rate_limit.per_sec == 0 {
+            return Err(ConfigError::NotPositive("rate_limit_per_sec"));
+        }
+
+        let checkpoint_period = args
+            .checkpoint_period_secs
+            .or(file.checkpoint_period_secs)
+            .unwrap_or(DEFAULT_CHECKPOINT_PERIOD_SECS);
+        if checkpoint_period == 0 {
+            return Err(ConfigError::NotPositive("checkpoint_period_secs"));
+        }
+
+        // The flags replace the file's table rather than adding to it
+        let geo_overrides = if args.geo_overrides.is_empty() {
+            file.geo_overrides
+                .iter()
+                .map(|(subnet, country)| Override::new(subnet, country))
+                .collect::<Result<_, _>>()
+                .map_err(ConfigError::Override)?
+        } else {
+            args.geo_overrides
+        };
+
+        Ok(Self {
+            addr: SocketAddr::new(bind_addr, port),
+            geoip_db: args
+                .geoip_db
+                .or(file.geoip_db)
+                .unwrap_or_else(|| DEFAULT_GEOIP_DB.into()),
+            geoip_csv: args.geoip_csv.or(file.geoip_csv),
+            geo_overrides,
+            ipv4_db: args
+                .ipv4_db
+                .or(file.ipv4_db)
+                .unwrap_or_else(|| DEFAULT_IPV4_DB.into()),
+            ipv6_db: args
+                .ipv6_db
+                .or(file.ipv6_db)
+                .unwrap_or_else(|| DEFAULT_IPV6_DB.into()),
+            store: args.store.or(file.store).unwrap_or_default(),
+            update: UpdateConfig {
+                liveness_window,
+                aggregation,
+                aggregation_window,
+            },
+            cleanup_period,
+            workers,
+            merge_interval: Duration::from_millis(merge_interval),
+            rate_limit,
+            checkpoint_period,
+            command: args.command,
+        })
+    }
+}
+
+fn read_file(path: PathBuf) -> Result<FileConfig, ConfigError> {
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(err) => return Err(ConfigError::Read(path, err)),
+    };
+
+    toml::from_str(&contents).map_err(|err| ConfigError::Parse(path, err))
+}
@@ -1,126 +1,357 @@
 use std::{
-    fs::OpenOptions,
-    net::{IpAddr, Ipv4Addr},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
 };
 
-use bytemuck::{cast_slice_mut, from_bytes, from_bytes_mut};
-use maxminddb::{PathElement, Reader};
-use melodybrain::{COUNTRIES, StoredCountryStats, StoredIpStats, WORLDWIDE, search_country};
-use memmap2::{Mmap, MmapMut};
+use bytemuck::{bytes_of, cast_slice, cast_slice_mut};
+use melodybrain::{
+    COUNTRIES, StoredCountryStats, StoredIpStats,
+    layout::{
+        self, COUNTRY_SIZE, COUNTRY_TABLE_OFFSET, IPV6_FILE_LEN, IPV6_SLOT_SIZE,
+        IPV6_SLOTS_PER_SHARD, Ipv6Slots, LayoutError, RECORD_SIZE, Snapshot,
+    },
+    store::{
+        CountryStorage, FIRST_V4_BUCKET, LAST_V4_BUCKET, Records, SHARDS, ShardedStore,
+        V4_BUCKETS_PER_SHARD,
+    },
+};
+use memmap2::MmapMut;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClientKey {
+    // The /24 bucket in the IPv4 table
+    V4(u32),
+    // The slot in the shard's partition of the IPv6 table
+    V6(u32),
+}
+
+fn open_sparse(path: &Path, len: u64) -> io::Result<File> {
+    let db = OpenOptions::new()
+        .write(true)
+        .read(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    db.set_len(len)?;
 
-pub struct GeoIpDb(Reader<Mmap>);
+    Ok(db)
+}
+
+// Opens the IPv6 database and locks it like the IPv4 one, since a server that only got the IPv4 lock would still
+// write into the other's slots
+fn open_v6(path: &Path) -> Result<File, LayoutError> {
+    let v6 =
+        open_sparse(path, IPV6_FILE_LEN).map_err(|err| LayoutError::Io(path.to_owned(), err))?;
+    layout::lock(&v6, path)?;
+    Ok(v6)
+}
 
-impl GeoIpDb {
-    pub fn new() -> Self {
-        let db = unsafe {
-            maxminddb::Reader::open_mmap("./GeoLite2-Country.mmdb")
-                .expect("failed to open IP geo database")
-        };
+// Opens an IPv6 database that's already there, like `layout::open_v4_existing`
+fn open_v6_existing(path: &Path, writable: bool) -> Result<File, LayoutError> {
+    let io_error = |err| LayoutError::Io(path.to_owned(), err);
 
-        Self(db)
+    let v6 = OpenOptions::new()
+        .read(true)
+        .write(writable)
+        .open(path)
+        .map_err(io_error)?;
+    layout::lock(&v6, path)?;
+    if v6.metadata().map_err(io_error)?.len() < IPV6_FILE_LEN {
+        return Err(LayoutError::Unrecognized(path.to_owned()));
     }
+    Ok(v6)
+}
 
-    pub fn lookup_ip(&self, ip: IpAddr) -> Option<u8> {
-        self.0
-            .lookup(ip)
-            .ok()
-            .and_then(|res| {
-                res.decode_path::<&str>(&[
-                    PathElement::Key("country"),
-                    PathElement::Key("iso_code"),
-                ])
-                .unwrap()
-            })
-            .and_then(search_country)
+// A private mapping keeps whatever is written to it out of the file
+fn map_region(file: &File, offset: u64, len: usize, private: bool) -> MmapMut {
+    let mut options = memmap2::MmapOptions::new();
+    options.offset(offset).len(len).no_reserve_swap();
+    unsafe {
+        if private {
+            options.map_copy(file)
+        } else {
+            options.map_mut(file)
+        }
+        .expect("failed to mmap sparse db")
     }
 }
 
-pub struct GeneralIpDb(MmapMut);
+// One shard's slice of the IPv6 table
+pub struct Ipv6Partition(MmapMut);
 
-impl GeneralIpDb {
-    pub fn new() -> Self {
-        let db = OpenOptions::new()
-            .write(true)
-            .read(true)
-            .create(true)
-            .truncate(false)
-            .open("./ipv4.bin")
-            .expect("failed to create/open ip database");
+impl Ipv6Partition {
+    fn slots(&mut self) -> Ipv6Slots<'_> {
+        Ipv6Slots(cast_slice_mut(&mut self.0))
+    }
+}
 
-        db.set_len((1 << 24) * 32).expect("failed to sparsify db");
+pub struct ShardRecords {
+    // None for the shards that only cover reserved IPv4 ranges
+    v4: Option<MmapMut>,
+    v4_first: u32,
+    v6: Ipv6Partition,
+}
+
+impl ShardRecords {
+    fn v4_records(&mut self) -> &mut [StoredIpStats] {
+        self.v4
+            .as_deref_mut()
+            .map(cast_slice_mut)
+            .unwrap_or_default()
+    }
+}
 
-        let db = unsafe {
-            memmap2::MmapOptions::new()
-                .no_reserve_swap()
-                .map_mut(&db)
-                .expect("failed to mmap sparse db")
-        };
+impl Records for ShardRecords {
+    type Key = ClientKey;
 
-        Self(db)
+    // IPv4 records never go away, but one that was never touched doesn't count as one
+    fn key(&mut self, addr: IpAddr) -> Option<ClientKey> {
+        match addr {
+            IpAddr::V4(addr) => {
+                let key = ClientKey::V4(addr.to_bits() >> 8);
+                self.get_mut(key)
+                    .filter(|record| record.first_seen != 0)
+                    .map(|_| key)
+            }
+            IpAddr::V6(addr) => self
+                .v6
+                .slots()
+                .find((addr.to_bits() >> 64) as u64)
+                .map(ClientKey::V6),
+        }
     }
 
-    fn split(&mut self) -> (&mut [StoredIpStats], &mut [StoredCountryStats]) {
-        let start = const { Ipv4Addr::new(1, 0, 0, 0).to_bits() >> 8 } as usize;
-        let end = const { Ipv4Addr::new(223, 255, 255, 255).to_bits() >> 8 } as usize;
+    fn insert(&mut self, addr: IpAddr) -> Option<ClientKey> {
+        match addr {
+            IpAddr::V4(addr) => Some(ClientKey::V4(addr.to_bits() >> 8)),
+            IpAddr::V6(addr) => self
+                .v6
+                .slots()
+                .claim((addr.to_bits() >> 64) as u64)
+                .map(ClientKey::V6),
+        }
+    }
 
-        let (countries, ips) = self.0.split_at_mut(start);
-        let ips: &mut [StoredIpStats] = cast_slice_mut(&mut ips[start..=end]);
-        let countries: &mut [StoredCountryStats] = cast_slice_mut(&mut countries[0..start]);
+    fn get_mut(&mut self, key: ClientKey) -> Option<&mut StoredIpStats> {
+        match key {
+            ClientKey::V4(bucket) => {
+                let idx = (bucket - self.v4_first) as usize;
+                self.v4_records().get_mut(idx)
+            }
+            ClientKey::V6(slot) => self.v6.slots().record(slot),
+        }
+    }
 
-        (ips, countries)
+    // Only IPv6 slots are given back, the IPv4 table has room for every /24 anyway
+    fn remove(&mut self, key: ClientKey) -> bool {
+        match key {
+            ClientKey::V4(_) => false,
+            ClientKey::V6(slot) => {
+                self.v6.slots().remove(slot);
+                true
+            }
+        }
     }
 
-    pub fn cleanup(&mut self, now: u64) {
-        let (records, countries) = self.split();
+    fn iter_mut(&mut self) -> impl Iterator<Item = (ClientKey, &mut StoredIpStats)> {
+        let v4_first = self.v4_first;
+        let v4_records = self
+            .v4
+            .as_deref_mut()
+            .map(cast_slice_mut::<_, StoredIpStats>)
+            .unwrap_or_default()
+            .iter_mut()
+            .zip(v4_first..)
+            .map(|(record, bucket)| (ClientKey::V4(bucket), record));
+        let v6_records = self
+            .v6
+            .slots()
+            .taken()
+            .map(|(slot, record)| (ClientKey::V6(slot), &mut record.stats));
 
-        for record in records {
-            if record.first_seen != 0 {
-                let diff = now - record.last_seen;
-                if diff > 10 {
-                    record.cum_duration += diff as u32;
-                    record.last_seen = 0;
+        v4_records.chain(v6_records)
+    }
 
-                    let country = &mut countries[record.country as usize];
-                    country.active = country.active.saturating_sub(1);
-                    let global = &mut countries[WORLDWIDE as usize];
-                    global.active = global.active.saturating_sub(1);
-                }
-            }
+    fn snapshot(&mut self, snapshot: &mut Snapshot) {
+        let v4_first = self.v4_first;
+        let v4 = (v4_first..)
+            .zip(self.v4_records().iter())
+            .filter(|(_, record)| bytes_of(*record).iter().any(|&byte| byte != 0))
+            .map(|(bucket, record)| (bucket, *record));
+        snapshot.v4.extend(v4);
+
+        snapshot
+            .v6
+            .extend(self.v6.slots().taken().map(|(_, slot)| *slot));
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if let Some(v4) = &self.v4 {
+            v4.flush()?;
         }
+        self.v6.0.flush()
     }
+}
 
-    pub fn lookup_ip_mut(&mut self, addr: Ipv4Addr) -> &mut StoredIpStats {
-        let ip_bucket = addr.to_bits() >> 8;
-        let start_idx = ip_bucket as usize * 32;
-        let end_idx = start_idx + 32;
+pub struct CountryTable {
+    stats: MmapMut,
+    // Hold the locks that keep other servers and commands off the databases
+    _files: [File; 2],
+}
 
-        from_bytes_mut(&mut self.0[start_idx..end_idx])
+impl CountryStorage for CountryTable {
+    fn stats(&self) -> &[StoredCountryStats] {
+        cast_slice(&self.stats)
     }
 
-    pub fn lookup_country_mut(&mut self, country: u8) -> &mut StoredCountryStats {
-        let start_idx = country as usize * 32;
-        let end_idx = start_idx + 32;
+    fn stats_mut(&mut self) -> &mut [StoredCountryStats] {
+        cast_slice_mut(&mut self.stats)
+    }
 
-        from_bytes_mut(&mut self.0[start_idx..end_idx])
+    fn flush(&self) -> io::Result<()> {
+        self.stats.flush()
     }
+}
+
+// The IPv4 table has a record for every /24 and the country table, the IPv6 table a slot for every /64 that was
+// ever seen. Both are sparse files, only the pages that were touched take up disk space.
+pub type GeneralIpDb = ShardedStore<ShardRecords, CountryTable>;
+
+// recent_window is how many contributors per country to keep around for the robust aggregations. Fails if the IPv4
+// database has a layout this build can't read.
+pub fn open(
+    v4_path: &Path,
+    v6_path: &Path,
+    recent_window: usize,
+) -> Result<GeneralIpDb, LayoutError> {
+    let v4 = layout::open_v4(v4_path)?;
+    let v6 = open_v6(v6_path)?;
+    Ok(map(v4, v6, recent_window, false))
+}
+
+// For the subcommands that look at a stopped server's databases. Fails if either is missing instead of making new
+// ones, and unless `writable` nothing done to the store reaches the files.
+pub fn open_existing(
+    v4_path: &Path,
+    v6_path: &Path,
+    writable: bool,
+) -> Result<GeneralIpDb, LayoutError> {
+    let v4 = layout::open_v4_existing(v4_path, writable)?;
+    let v6 = open_v6_existing(v6_path, writable)?;
+    Ok(map(v4, v6, 1, !writable))
+}
+
+fn map(v4: File, v6: File, recent_window: usize, private: bool) -> GeneralIpDb {
+    let shards = (0..SHARDS)
+        .map(|idx| {
+            // The first /8 is where the header and country table live
+            let first = (idx as u32 * V4_BUCKETS_PER_SHARD).max(FIRST_V4_BUCKET);
+            let last = ((idx as u32 + 1) * V4_BUCKETS_PER_SHARD - 1).min(LAST_V4_BUCKET);
+            let v4 = (first <= last).then(|| {
+                map_region(
+                    &v4,
+                    first as u64 * RECORD_SIZE as u64,
+                    (last - first + 1) as usize * RECORD_SIZE,
+                    private,
+                )
+            });
+
+            let v6_offset = (idx * IPV6_SLOTS_PER_SHARD * IPV6_SLOT_SIZE) as u64;
+            let v6 = map_region(
+                &v6,
+                v6_offset,
+                IPV6_SLOTS_PER_SHARD * IPV6_SLOT_SIZE,
+                private,
+            );
+
+            ShardRecords {
+                v4,
+                v4_first: first,
+                v6: Ipv6Partition(v6),
+            }
+        })
+        .collect();
+
+    let countries = CountryTable {
+        stats: map_region(
+            &v4,
+            COUNTRY_TABLE_OFFSET,
+            COUNTRIES.len() * COUNTRY_SIZE,
+            private,
+        ),
+        _files: [v4, v6],
+    };
+    ShardedStore::new(shards, countries, recent_window)
+}
 
-    pub fn lookup_country(&self, country: u8) -> &StoredCountryStats {
-        let start_idx = country as usize * 32;
-        let end_idx = start_idx + 32;
+#[derive(Debug)]
+pub enum RestoreError {
+    Layout(LayoutError),
+    Io(PathBuf, io::Error),
+    // Records that don't have a place in these databases, so it's not a snapshot of this server's
+    OutOfPlace,
+}
 
-        from_bytes(&self.0[start_idx..end_idx])
+impl fmt::Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Layout(err) => err.fmt(f),
+            Self::Io(path, err) => write!(f, "failed to write {}: {err}", path.display()),
+            Self::OutOfPlace => write!(f, "snapshot has records this server has no place for"),
+        }
     }
+}
+
+impl std::error::Error for RestoreError {}
 
-    pub fn get_country_heatmap(&self) -> [f32; COUNTRIES.len()] {
-        let world_total = self.lookup_country(WORLDWIDE).active as f32;
+// Writes a snapshot's records into fresh databases and swaps them in for the ones at these paths, which nothing else
+// can have open. Active counts are left for the recovery at the next start to fix.
+pub fn restore(v4_path: &Path, v6_path: &Path, snapshot: &Snapshot) -> Result<(), RestoreError> {
+    // Only to make sure no server is using them
+    let _current = layout::open_v4(v4_path).map_err(RestoreError::Layout)?;
+    let _current_v6 = open_v6(v6_path).map_err(RestoreError::Layout)?;
 
-        std::array::from_fn(|idx| self.lookup_country(idx as u8).active as f32 / world_total)
+    let temp_path = |path: &Path| {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".restore");
+        PathBuf::from(temp)
+    };
+    let (v4_temp, v6_temp) = (temp_path(v4_path), temp_path(v6_path));
+    let remove_temps = || {
+        for temp in [&v4_temp, &v6_temp] {
+            match fs::remove_file(temp) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(RestoreError::Io(temp.clone(), err));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    };
+    remove_temps()?;
+
+    let db = open(&v4_temp, &v6_temp, 1).map_err(RestoreError::Layout)?;
+    let restored = db.restore(snapshot);
+    // Unmapped, so whatever is still dirty gets written out by the syncs below
+    drop(db);
+    if !restored {
+        remove_temps()?;
+        return Err(RestoreError::OutOfPlace);
     }
 
-    // pub fn lookup_ip_and_country(
-    //     &mut self,
-    //     addr: Ipv4Addr,
-    // ) -> (&mut StoredIpStats, &mut StoredCountryStats) {
-    //     self.0.split_at_mut(mid)
-    // }
+    for temp in [&v4_temp, &v6_temp] {
+        File::open(temp)
+            .and_then(|file| file.sync_all())
+            .map_err(|err| RestoreError::Io(temp.clone(), err))?;
+    }
+
+    for (temp, path) in [(&v6_temp, v6_path), (&v4_temp, v4_path)] {
+        let error = |err| RestoreError::Io(path.to_owned(), err);
+        fs::rename(temp, path).map_err(error)?;
+        layout::sync_dir(path).map_err(error)?;
+    }
+    Ok(())
 }
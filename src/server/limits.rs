@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub received: AtomicU64,
+    // Garbage and outdated clients
+    pub dropped: AtomicU64,
+    pub rate_limited: AtomicU64,
+    // Reserved addresses and networks there was no room for
+    pub untracked: AtomicU64,
+    // Heartbeats and stats requests answered with a cookie instead
+    pub challenged: AtomicU64,
+}
+
+impl Counters {
+    pub fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn log(&self) {
+        eprintln!(
+            "received {} packets, dropped {}, rate limited {}, untracked {}, sent {} cookie challenges",
+            self.received.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.rate_limited.load(Ordering::Relaxed),
+            self.untracked.load(Ordering::Relaxed),
+            self.challenged.load(Ordering::Relaxed),
+        );
+    }
+}
@@ -1,121 +1,355 @@
 use std::{
-    collections::HashMap,
-    fs::OpenOptions,
-    net::{IpAddr, Ipv6Addr, UdpSocket},
-    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    net::SocketAddr,
+    path::Path,
+    process::exit,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use maxminddb::PathElement;
-use melodybrain::{Heartbeat, Stats, StoredIpStats, WORLDWIDE, search_country};
+use melodybrain::{
+    COUNTRIES, DecodeError, Message, PROTOCOL_VERSION, ProtocolError,
+    cookie::{CookieJar, reply_unverified},
+    geo::{GeoChain, GeoProvider, MaxMindDb, Overrides, RangeTable},
+    heartbeat::{Source, Verdict, register_heartbeat},
+    layout::Snapshot,
+    store::{CountrySnapshot, Discrepancy, MemoryStore, StatsStore},
+};
+use tokio::{
+    net::UdpSocket,
+    time::{MissedTickBehavior, interval},
+};
 
-use crate::dbs::{GeneralIpDb, GeoIpDb};
+use crate::{
+    config::{Command, Config, StoreKind},
+    limits::Counters,
+};
 
+mod config;
 mod dbs;
+mod limits;
 
-#[derive(Debug)]
-struct AddrInfo {
-    last_seen: Instant,
-    country: Option<u16>,
+struct Server<S> {
+    socket: UdpSocket,
+    geo: GeoChain,
+    db: S,
+    config: Config,
+    // Swapped out wholesale after every merge, so replies never wait on the country table
+    snapshot: RwLock<Arc<CountrySnapshot>>,
+    cookies: CookieJar,
+    counters: Counters,
 }
 
-fn main() {
-    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 2026)).unwrap();
-    let geoip = GeoIpDb::new();
-    let mut db = GeneralIpDb::new();
-
-    let mut buf = [0; 1200];
-
-    let mut start = SystemTime::now()
+fn unix_now() -> u64 {
+    SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .unwrap()
-        .as_secs();
+        .as_secs()
+}
 
-    // Initial cleanup run in case server was shut down
-    db.cleanup(start);
+async fn reply(socket: &UdpSocket, buf: &mut [u8], addr: SocketAddr, msg: &Message) {
+    let msg = msg.encode(buf).expect("reply should always fit in buffer");
+    let _ = socket.send_to(msg, addr).await;
+}
+
+async fn worker<S: StatsStore>(server: Arc<Server<S>>) {
+    let mut buf = [0; 1200];
 
     loop {
-        let Ok((n, addr)) = socket.recv_from(&mut buf) else {
+        let Ok((n, addr)) = server.socket.recv_from(&mut buf).await else {
             continue;
         };
 
-        // Only IPv4 for now, consider using a more advanced structure in the future as a DB
-        let IpAddr::V4(addr_v4) = addr.ip().to_canonical() else {
-            continue;
+        Counters::bump(&server.counters.received);
+
+        let (heartbeat, stats_request) = match Message::decode(&buf[..n]) {
+            Ok(Message::Heartbeat(heartbeat)) => (heartbeat, None),
+            Ok(Message::StatsRequest(request)) => {
+                (request.heartbeat, Some((request.id, request.country)))
+            }
+            // Replies only ever flow from the server to clients
+            Ok(Message::Stats { .. } | Message::Error { .. } | Message::Cookie { .. })
+            | Err(DecodeError::BadMagic) => {
+                Counters::bump(&server.counters.dropped);
+                continue;
+            }
+            Err(DecodeError::UnsupportedVersion(_)) => {
+                Counters::bump(&server.counters.dropped);
+                let error = ProtocolError::UnsupportedVersion {
+                    supported: PROTOCOL_VERSION,
+                };
+                let msg = Message::Error { id: 0, error };
+                reply_unverified(&server.socket, &mut buf, addr, &msg, n).await;
+                continue;
+            }
+            Err(DecodeError::Malformed) => {
+                Counters::bump(&server.counters.dropped);
+                let error = ProtocolError::Malformed;
+                let msg = Message::Error { id: 0, error };
+                reply_unverified(&server.socket, &mut buf, addr, &msg, n).await;
+                continue;
+            }
+        };
+
+        let client_ip = addr.ip().to_canonical();
+        let now = unix_now();
+        // Proves the client got our last reply at this address
+        let verified = server.cookies.verify(client_ip, now, heartbeat.cookie);
+        // Replies that aren't stats still answer the request they're for
+        let id = stats_request.map_or(0, |(id, _)| id);
+        let challenge = || Message::Cookie {
+            id,
+            cookie: server.cookies.issue(client_ip, now),
         };
 
-        let Ok(heartbeat) = postcard::from_bytes::<Heartbeat>(&buf[..n]) else {
+        let verdict = register_heartbeat(
+            &server.db,
+            Source {
+                ip: client_ip,
+                verified,
+            },
+            heartbeat.seed,
+            now,
+            server.config.update.liveness_window,
+            server.config.rate_limit,
+            |ip| server.geo.lookup(ip),
+        );
+        match verdict {
+            Verdict::Accepted => {}
+            Verdict::RateLimited => {
+                Counters::bump(&server.counters.rate_limited);
+                let error = ProtocolError::RateLimited;
+                let msg = Message::Error { id, error };
+                reply_unverified(&server.socket, &mut buf, addr, &msg, n).await;
+                continue;
+            }
+            Verdict::Unverified => {
+                Counters::bump(&server.counters.challenged);
+                reply_unverified(&server.socket, &mut buf, addr, &challenge(), n).await;
+                continue;
+            }
+            // Not counted, but it may still see the stats
+            Verdict::Untracked => Counters::bump(&server.counters.untracked),
+        }
+
+        let Some((_, country)) = stats_request else {
             continue;
         };
 
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
+        // Stats are much bigger than the request, only send them to clients that got our last reply
+        if !verified {
+            Counters::bump(&server.counters.challenged);
+            reply_unverified(&server.socket, &mut buf, addr, &challenge(), n).await;
+            continue;
+        }
+
+        let msg = if (country as usize) < COUNTRIES.len() {
+            let snapshot = Arc::clone(&server.snapshot.read().unwrap());
+            Message::Stats {
+                id,
+                stats: Box::new(snapshot.stats(country)),
+            }
+        } else {
+            Message::Error {
+                id,
+                error: ProtocolError::UnknownCountry,
+            }
+        };
 
-        let bucket_info = db.lookup_ip_mut(addr_v4);
+        reply(&server.socket, &mut buf, addr, &msg).await;
+    }
+}
 
-        if bucket_info.first_seen == 0 && bucket_info.last_seen == 0 {
-            bucket_info.first_seen = now;
-            bucket_info.last_seen = now;
+async fn cleanup<S: StatsStore>(server: Arc<Server<S>>) {
+    let mut interval = interval(Duration::from_secs(server.config.cleanup_period));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-            let country = geoip.lookup_ip(addr.ip()).unwrap_or_default();
-            bucket_info.country = country;
+    loop {
+        interval.tick().await;
 
-            let country_stats = db.lookup_country_mut(country);
-            country_stats.active += 1;
-            country_stats.unique += 1;
+        server
+            .db
+            .cleanup(unix_now(), server.config.update.liveness_window);
+        server.counters.log();
+    }
+}
 
-            let global_stats = db.lookup_country_mut(WORLDWIDE);
-            global_stats.active += 1;
-            global_stats.unique += 1;
-        } else if bucket_info.first_seen != 0 && bucket_info.last_seen == 0 {
-            bucket_info.first_seen = now;
-            bucket_info.last_seen = now;
+async fn checkpoint<S: StatsStore>(server: Arc<Server<S>>) {
+    let mut interval = interval(Duration::from_secs(server.config.checkpoint_period));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-            let country = bucket_info.country;
-            let country_stats = db.lookup_country_mut(country);
-            country_stats.active += 1;
+    loop {
+        interval.tick().await;
 
-            let global_stats = db.lookup_country_mut(WORLDWIDE);
-            global_stats.active += 1;
+        if let Err(err) = server.db.checkpoint() {
+            eprintln!("checkpoint failed: {err}");
         }
+    }
+}
+
+async fn merge<S: StatsStore>(server: Arc<Server<S>>) {
+    let mut interval = interval(server.config.merge_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
 
-        // Reborrow to satisfy borrow checker
-        let bucket_info = db.lookup_ip_mut(addr_v4);
+        let snapshot = server.db.merge(server.config.update.aggregation);
+        *server.snapshot.write().unwrap() = Arc::new(snapshot);
+    }
+}
+
+fn fail(err: impl std::fmt::Display) -> ! {
+    eprintln!("error: {err}");
+    exit(1)
+}
+
+fn snapshot(config: &Config, output: &Path) {
+    let db =
+        dbs::open_existing(&config.ipv4_db, &config.ipv6_db, false).unwrap_or_else(|err| fail(err));
+    let snapshot = db.snapshot(unix_now());
+    snapshot.write(output).unwrap_or_else(|err| fail(err));
+
+    println!(
+        "wrote {} IPv4 and {} IPv6 records to {}",
+        snapshot.v4.len(),
+        snapshot.v6.len(),
+        output.display()
+    );
+}
+
+fn restore(config: &Config, input: &Path) {
+    let snapshot = Snapshot::read(input).unwrap_or_else(|err| fail(err));
+    dbs::restore(&config.ipv4_db, &config.ipv6_db, &snapshot).unwrap_or_else(|err| fail(err));
 
-        if now - bucket_info.last_seen > 10 {
-            let diff = (now - bucket_info.last_seen) as u32;
-            bucket_info.cum_duration += diff;
+    println!(
+        "restored {} IPv4 and {} IPv6 records from {}",
+        snapshot.v4.len(),
+        snapshot.v6.len(),
+        input.display()
+    );
+}
 
-            bucket_info.last_seen = now;
-            bucket_info.hits += 1;
+fn fsck(config: &Config, repair: bool) {
+    let db = dbs::open_existing(&config.ipv4_db, &config.ipv6_db, repair)
+        .unwrap_or_else(|err| fail(err));
+    let discrepancies = db.fsck(repair).unwrap_or_else(|err| fail(err));
 
-            let country = bucket_info.country;
-            let country_seed = db.lookup_country_mut(country);
-            country_seed.seed += (heartbeat.seed as i64 - country_seed.seed) / 2000;
-            country_seed.cum_duration += diff;
+    for Discrepancy {
+        country,
+        stored,
+        counted,
+    } in &discrepancies
+    {
+        let code = String::from_utf8_lossy(&COUNTRIES[*country as usize].0);
+        println!(
+            "{code}: active {} (counted {}), unique {} (counted {}), duration {} (counted {})",
+            stored.active,
+            counted.active,
+            stored.unique,
+            counted.unique,
+            stored.cum_duration,
+            counted.cum_duration
+        );
+    }
 
-            let global_seed = db.lookup_country_mut(WORLDWIDE);
-            global_seed.seed += (heartbeat.seed as i64 - global_seed.seed) / 2000;
-            global_seed.cum_duration += diff;
+    match (discrepancies.len(), repair) {
+        (0, _) => println!("every country matches its records"),
+        (off, true) => println!("countries repaired: {off}"),
+        (off, false) => {
+            println!(
+                "countries that don't match their records: {off}, run with --repair to fix them"
+            );
+            exit(1)
         }
+    }
+}
 
-        if heartbeat.wants_country != 0 {
-            let country = db.lookup_country_mut(heartbeat.wants_country);
-
-            let stats = Stats {
-                connected: country.active,
-                seed: country.seed as i32,
-                // It might be somewhat inefficient to recalculate this every time a request is made, but it's only 250 countries
-                country_heatmap: db.get_country_heatmap(),
-            };
-            let stats = postcard::to_slice(&stats, &mut buf).unwrap();
-            let _ = socket.send_to(stats, addr);
+// Overrides first, then the MaxMind database, then the CSV. Databases that can't be read are left out rather than
+// keeping the server from starting.
+fn geo_providers(config: &Config) -> GeoChain {
+    let mut geo = GeoChain::default();
+    if !config.geo_overrides.is_empty() {
+        geo.push(Overrides::new(config.geo_overrides.clone()));
+    }
+
+    match MaxMindDb::open(&config.geoip_db) {
+        Ok(db) => geo.push(db),
+        Err(err) => eprintln!("warning: {err}"),
+    }
+    if let Some(path) = &config.geoip_csv {
+        match RangeTable::load(path) {
+            Ok(table) => geo.push(table),
+            Err(err) => eprintln!("warning: {err}"),
         }
+    }
 
-        if now - start > 20 {
-            db.cleanup(now);
-            start = now;
+    if geo.is_empty() {
+        eprintln!("warning: no way to locate clients, they're all counted as unknown");
+    }
+    geo
+}
+
+fn main() {
+    let config = Config::load().unwrap_or_else(|err| fail(err));
+
+    match &config.command {
+        Some(Command::Snapshot { output }) => return snapshot(&config, output),
+        Some(Command::Restore { input }) => return restore(&config, input),
+        Some(Command::Fsck { repair }) => return fsck(&config, *repair),
+        None => {}
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .enable_all()
+        .build()
+        .expect("failed to start runtime");
+
+    runtime.block_on(async {
+        let recent_window = config
+            .update
+            .aggregation
+            .window(config.update.aggregation_window);
+
+        match config.store {
+            StoreKind::Mmap => {
+                let db = dbs::open(&config.ipv4_db, &config.ipv6_db, recent_window)
+                    .unwrap_or_else(|err| fail(err));
+                serve(config, db).await
+            }
+            StoreKind::Memory => serve(config, MemoryStore::empty(recent_window)).await,
         }
+    });
+}
+
+async fn serve<S: StatsStore + 'static>(config: Config, db: S) {
+    let socket = UdpSocket::bind(config.addr)
+        .await
+        .unwrap_or_else(|err| fail(format!("failed to bind {}: {err}", config.addr)));
+    let geo = geo_providers(&config);
+
+    // Initial cleanup run in case server was shut down, this also fills in the expiry index
+    let corrected = db.recover(unix_now(), config.update.liveness_window);
+    if corrected > 0 {
+        eprintln!("recounted the clients and connected time of {corrected} countries");
     }
+    let snapshot = db.merge(config.update.aggregation);
+
+    let server = Arc::new(Server {
+        socket,
+        geo,
+        db,
+        snapshot: RwLock::new(Arc::new(snapshot)),
+        cookies: CookieJar::new(),
+        counters: Counters::default(),
+        config,
+    });
+
+    for _ in 0..server.config.workers {
+        tokio::spawn(worker(Arc::clone(&server)));
+    }
+    tokio::spawn(cleanup(Arc::clone(&server)));
+    tokio::spawn(checkpoint(Arc::clone(&server)));
+
+    merge(server).await;
 }
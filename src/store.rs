@@ -0,0 +1,704 @@
+use std::{
+    collections::HashMap,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Mutex,
+};
+
+use crate::{
+    COUNTRIES, Stats, StoredCountryStats, StoredIpStats, StoredIpv6Stats, WORLDWIDE,
+    aggregate::{Aggregation, RecentSeeds},
+    expiry::ExpiryIndex,
+    layout::Snapshot,
+};
+
+// Number of independently locked pieces of a store. It's fixed rather than configurable because it decides which
+// partition of the IPv6 table a prefix lives in.
+pub const SHARDS: usize = 64;
+
+pub const FIRST_V4_BUCKET: u32 = Ipv4Addr::new(1, 0, 0, 0).to_bits() >> 8;
+pub const LAST_V4_BUCKET: u32 = Ipv4Addr::new(223, 255, 255, 255).to_bits() >> 8;
+pub const V4_BUCKETS_PER_SHARD: u32 = (1 << 24) / SHARDS as u32;
+
+// Fibonacci hashing, prefixes are far from uniformly distributed. The top bits pick the shard, the ones below the
+// slot within it.
+pub fn prefix_hash(prefix: u64) -> u64 {
+    prefix.wrapping_mul(0x9E37_79B9_7F4A_7C15)
+}
+
+// None for IPs that will never be tracked, like the unspecified /64, multicast or reserved IPv4 ranges
+pub fn shard_of(addr: IpAddr) -> Option<usize> {
+    match addr {
+        IpAddr::V4(addr) => {
+            let bucket = addr.to_bits() >> 8;
+            (FIRST_V4_BUCKET..=LAST_V4_BUCKET)
+                .contains(&bucket)
+                .then_some((bucket / V4_BUCKETS_PER_SHARD) as usize)
+        }
+        IpAddr::V6(addr) => {
+            let prefix = (addr.to_bits() >> 64) as u64;
+            (prefix != 0 && !addr.is_multicast())
+                .then(|| (prefix_hash(prefix) >> (64 - SHARDS.ilog2())) as usize)
+        }
+    }
+}
+
+// Identifies a contributor to the robust aggregations across shards: the /24 for IPv4, and the /48 for IPv6 since a
+// single site usually gets a whole /48 and could otherwise vote with 65536 /64s. The tag bit keeps the two apart.
+pub fn contributor_id(addr: IpAddr) -> u64 {
+    match addr {
+        IpAddr::V4(addr) => 1 << 63 | (addr.to_bits() >> 8) as u64,
+        IpAddr::V6(addr) => (addr.to_bits() >> 80) as u64,
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct CountryDelta {
+    active: i64,
+    unique: i64,
+    // Wraps like the stored totals do
+    cum_duration: u32,
+    forgotten: u32,
+    forgotten_duration: u32,
+}
+
+// Changes to the country table made by a shard since the last merge, so workers never have to fight over it
+#[derive(Debug)]
+pub struct PendingCountryStats {
+    deltas: Vec<CountryDelta>,
+    // Country, contributor and seed
+    seeds: Vec<(u8, u64, i32)>,
+}
+
+impl Default for PendingCountryStats {
+    fn default() -> Self {
+        Self {
+            deltas: vec![CountryDelta::default(); COUNTRIES.len()],
+            seeds: Vec::new(),
+        }
+    }
+}
+
+impl PendingCountryStats {
+    pub fn activate(&mut self, country: u8, first_time: bool) {
+        let delta = &mut self.deltas[country as usize];
+        delta.active += 1;
+        delta.unique += first_time as i64;
+    }
+
+    // `duration` is how long the client was around since its last contribution
+    pub fn deactivate(&mut self, country: u8, duration: u32) {
+        let delta = &mut self.deltas[country as usize];
+        delta.active -= 1;
+        delta.cum_duration = delta.cum_duration.wrapping_add(duration);
+    }
+
+    // For clients whose record is gone. They stay in the country's totals, the tally is what lets the totals still
+    // be checked against the records.
+    pub fn forget(&mut self, country: u8, cum_duration: u32) {
+        let delta = &mut self.deltas[country as usize];
+        delta.forgotten += 1;
+        delta.forgotten_duration = delta.forgotten_duration.wrapping_add(cum_duration);
+    }
+
+    // For when the totals are recounted from scratch, only the tally of forgotten clients is still to be added
+    fn forget_counts(&mut self) {
+        for delta in &mut self.deltas {
+            delta.active = 0;
+            delta.unique = 0;
+            delta.cum_duration = 0;
+        }
+    }
+
+    pub fn contribute(&mut self, country: u8, contributor: u64, seed: i32, duration: u32) {
+        let delta = &mut self.deltas[country as usize];
+        delta.cum_duration = delta.cum_duration.wrapping_add(duration);
+        self.seeds.push((country, contributor, seed));
+    }
+}
+
+// Where a shard keeps its client records, one of the two things a backend has to provide
+pub trait Records: Send {
+    // Finds a record again without another lookup, it's what the expiry index holds
+    type Key: Copy + Send;
+
+    // IPv4 clients are tracked per /24, IPv6 clients per /64. Returns None if there's no record for the client.
+    fn key(&mut self, addr: IpAddr) -> Option<Self::Key>;
+
+    // Makes room for a new client, returns None if there's none left
+    fn insert(&mut self, addr: IpAddr) -> Option<Self::Key>;
+
+    // None once the record was removed
+    fn get_mut(&mut self, key: Self::Key) -> Option<&mut StoredIpStats>;
+
+    // Gives up the record of a client that went quiet, so its room can be reused. Returns false if the backend keeps
+    // it anyway. The country totals don't change either way, but a client whose record is gone counts as a new one in
+    // `unique` when it comes back, so backends that give up different records count returning clients differently.
+    fn remove(&mut self, _key: Self::Key) -> bool {
+        false
+    }
+
+    // Records that were never touched are all zeroes and may be left out
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Key, &mut StoredIpStats)>;
+
+    // Copies of the records that were ever touched
+    fn snapshot(&mut self, snapshot: &mut Snapshot);
+
+    fn flush(&self) -> io::Result<()>;
+}
+
+// Where the country table lives, the other thing a backend has to provide
+pub trait CountryStorage: Send {
+    fn stats(&self) -> &[StoredCountryStats];
+
+    fn stats_mut(&mut self) -> &mut [StoredCountryStats];
+
+    fn flush(&self) -> io::Result<()>;
+}
+
+// A client's record, borrowed from its locked shard
+pub struct ClientRecord<'a> {
+    pub stats: &'a mut StoredIpStats,
+    pub pending: &'a mut PendingCountryStats,
+    expires_at: Option<u64>,
+}
+
+impl ClientRecord<'_> {
+    // Must be called whenever a client becomes active, cleanup won't know about it otherwise
+    pub fn schedule_expiry(&mut self, due: u64) {
+        self.expires_at = Some(due);
+    }
+}
+
+// Everything the server keeps about clients and countries. Backends agree on every number except how often a client
+// that went quiet and came back is counted in `unique`, which depends on whether its record was given up, see
+// `Records::remove`.
+pub trait StatsStore: Send + Sync {
+    // Hands the record of the client at `addr` to `f` while nothing else can touch it, creating it first if `create`
+    // is set. None for IPs that will never be tracked, for unknown clients without `create`, or when there's no room
+    // left for a new client.
+    fn with_record<T>(
+        &self,
+        addr: IpAddr,
+        create: bool,
+        f: impl FnOnce(&mut ClientRecord<'_>) -> T,
+    ) -> Option<T>;
+
+    // Expires the clients that went quiet
+    fn cleanup(&self, now: u64, liveness_window: u64);
+
+    // Full scan of every record, only needed at startup since the expiry index only lives in memory. The country
+    // table's active, unique and duration totals are recounted from the records along the way, in case a crash left
+    // them off. Returns how many countries had to be corrected.
+    fn recover(&self, now: u64, liveness_window: u64) -> usize;
+
+    // Folds every shard's pending changes into the country table
+    fn merge(&self, aggregation: Aggregation) -> CountrySnapshot;
+
+    // Compares every country's stats with what its records add up to, and with `repair` puts the stats right
+    fn fsck(&self, repair: bool) -> io::Result<Vec<Discrepancy>>;
+
+    // Copies every record that was ever touched and the country table, one shard at a time
+    fn snapshot(&self, created_at: u64) -> Snapshot;
+
+    // Pushes everything changed since the last checkpoint out to wherever it's kept. Records and the country table
+    // aren't written out together, so a crash can leave them from different moments. `recover` is what puts them
+    // back in step: the totals are recounted from the records, only what the records and seeds got since the last
+    // checkpoint that made it out can be lost.
+    fn checkpoint(&self) -> io::Result<()>;
+}
+
+struct Shard<R: Records> {
+    records: R,
+    expiry: ExpiryIndex<R::Key>,
+    pending: PendingCountryStats,
+}
+
+impl<R: Records> Shard<R> {
+    fn expire(&mut self, key: R::Key, now: u64) {
+        let Some(record) = self.records.get_mut(key) else {
+            return;
+        };
+        let duration = (now - record.last_seen) as u32;
+        record.cum_duration = record.cum_duration.wrapping_add(duration);
+        record.last_seen = 0;
+        self.pending.deactivate(record.country, duration);
+
+        let (country, cum_duration) = (record.country, record.cum_duration);
+        if self.records.remove(key) {
+            self.pending.forget(country, cum_duration);
+        }
+    }
+
+    fn with_record<T>(
+        &mut self,
+        addr: IpAddr,
+        create: bool,
+        f: impl FnOnce(&mut ClientRecord<'_>) -> T,
+    ) -> Option<T> {
+        let key = match self.records.key(addr) {
+            Some(key) => key,
+            None if create => self.records.insert(addr)?,
+            None => return None,
+        };
+        let mut record = ClientRecord {
+            stats: self.records.get_mut(key)?,
+            pending: &mut self.pending,
+            expires_at: None,
+        };
+
+        let res = f(&mut record);
+        if let Some(due) = record.expires_at {
+            self.expiry.schedule(key, due);
+        }
+        Some(res)
+    }
+
+    fn recover(&mut self, now: u64, liveness_window: u64, totals: &mut [CountryTotals]) {
+        let mut quiet = Vec::new();
+        for (key, record) in self.records.iter_mut() {
+            if record.first_seen == 0 || record.last_seen == 0 {
+                continue;
+            }
+
+            if now - record.last_seen > liveness_window {
+                quiet.push(key);
+            } else {
+                self.expiry
+                    .schedule(key, record.last_seen + liveness_window + 1);
+            }
+        }
+        for key in quiet {
+            self.expire(key, now);
+        }
+
+        self.count(totals);
+        // Already in the totals
+        self.pending.forget_counts();
+    }
+
+    // Clients forgotten since the last merge count too, they're not in the country table's tally yet
+    fn count(&mut self, totals: &mut [CountryTotals]) {
+        for (_, record) in self.records.iter_mut() {
+            if record.first_seen != 0 {
+                CountryTotals::add(totals, record);
+            }
+        }
+        for (country, delta) in self.pending.deltas.iter().enumerate() {
+            CountryTotals::add_forgotten(
+                totals,
+                country,
+                delta.forgotten,
+                delta.forgotten_duration,
+            );
+        }
+    }
+
+    fn cleanup(&mut self, now: u64, liveness_window: u64) {
+        while let Some(key) = self.expiry.pop_due(now) {
+            // Already expired through a duplicate entry
+            let Some(record) = self
+                .records
+                .get_mut(key)
+                .filter(|record| record.last_seen != 0)
+            else {
+                continue;
+            };
+
+            if now - record.last_seen > liveness_window {
+                self.expire(key, now);
+            } else {
+                let due = record.last_seen + liveness_window + 1;
+                self.expiry.schedule(key, due);
+            }
+        }
+    }
+}
+
+// Read-only copy of the country table as of the last merge, used to answer stats requests
+pub struct CountrySnapshot {
+    countries: Vec<(u32, i32)>,
+    heatmap: [f32; COUNTRIES.len()],
+}
+
+impl CountrySnapshot {
+    pub fn stats(&self, country: u8) -> Stats {
+        let (connected, seed) = self.countries[country as usize];
+
+        Stats {
+            connected,
+            seed,
+            country_heatmap: self.heatmap,
+        }
+    }
+
+    fn new(countries: &[StoredCountryStats]) -> Self {
+        let world_total = countries[WORLDWIDE as usize].active as f32;
+
+        Self {
+            countries: countries
+                .iter()
+                .map(|country| (country.active, country.seed as i32))
+                .collect(),
+            heatmap: std::array::from_fn(|idx| countries[idx].active as f32 / world_total),
+        }
+    }
+}
+
+struct CountryTable<C> {
+    stats: C,
+    // In memory only, the robust aggregations start over from the stored seeds after a restart
+    recent: Vec<RecentSeeds>,
+}
+
+// What a country's records add up to
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CountryTotals {
+    pub active: u32,
+    pub unique: u32,
+    pub cum_duration: u32,
+}
+
+impl CountryTotals {
+    fn stored(stats: &StoredCountryStats) -> Self {
+        Self {
+            active: stats.active,
+            unique: stats.unique,
+            cum_duration: stats.cum_duration,
+        }
+    }
+
+    // Counted towards its country and worldwide, like merges do
+    fn add(totals: &mut [Self], record: &StoredIpStats) {
+        for country in [record.country as usize, WORLDWIDE as usize] {
+            if let Some(totals) = totals.get_mut(country) {
+                totals.active += (record.last_seen != 0) as u32;
+                totals.unique += 1;
+                totals.cum_duration = totals.cum_duration.wrapping_add(record.cum_duration);
+            }
+        }
+    }
+
+    // Clients without a record any more, also towards both
+    fn add_forgotten(totals: &mut [Self], country: usize, clients: u32, duration: u32) {
+        for country in [country, WORLDWIDE as usize] {
+            if let Some(totals) = totals.get_mut(country) {
+                totals.unique = totals.unique.wrapping_add(clients);
+                totals.cum_duration = totals.cum_duration.wrapping_add(duration);
+            }
+        }
+    }
+
+    // What the country's stats should be: its records plus the clients the country table remembers it forgot.
+    // Worldwide's tally already has every country's in it.
+    fn with_forgotten(mut self, stats: &StoredCountryStats) -> Self {
+        self.unique = self.unique.wrapping_add(stats.forgotten);
+        self.cum_duration = self.cum_duration.wrapping_add(stats.forgotten_duration);
+        self
+    }
+}
+
+// A country whose stored stats don't match its records
+#[derive(Clone, Copy, Debug)]
+pub struct Discrepancy {
+    pub country: u8,
+    pub stored: CountryTotals,
+    pub counted: CountryTotals,
+}
+
+// The store logic on top of whatever a backend keeps its records and country table in. Every shard gets its share
+// of the addresses picked by `shard_of`.
+pub struct ShardedStore<R: Records, C> {
+    countries: Mutex<CountryTable<C>>,
+    shards: Box<[Mutex<Shard<R>>]>,
+}
+
+impl<R: Records, C: CountryStorage> ShardedStore<R, C> {
+    // `shards` must have SHARDS entries. recent_window is how many contributors per country to keep around for the
+    // robust aggregations.
+    pub fn new(shards: Vec<R>, countries: C, recent_window: usize) -> Self {
+        assert_eq!(shards.len(), SHARDS);
+
+        Self {
+            countries: Mutex::new(CountryTable {
+                stats: countries,
+                recent: vec![RecentSeeds::new(recent_window); COUNTRIES.len()],
+            }),
+            shards: shards
+                .into_iter()
+                .map(|records| {
+                    Mutex::new(Shard {
+                        records,
+                        expiry: ExpiryIndex::new(),
+                        pending: PendingCountryStats::default(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    // Puts a snapshot's records and country table into a store that's still empty. Returns false if some of the
+    // records have no place in it. Active counts are left for the next recovery to fix.
+    pub fn restore(&self, snapshot: &Snapshot) -> bool {
+        if snapshot.countries.len() != COUNTRIES.len()
+            || snapshot.v4.iter().any(|(bucket, _)| *bucket >= 1 << 24)
+        {
+            return false;
+        }
+
+        let v4 = snapshot
+            .v4
+            .iter()
+            .map(|(bucket, record)| (IpAddr::V4(Ipv4Addr::from_bits(bucket << 8)), record));
+        let v6 = snapshot.v6.iter().map(|slot| {
+            let addr = Ipv6Addr::from_bits((slot.prefix as u128) << 64);
+            (IpAddr::V6(addr), &slot.stats)
+        });
+        for (addr, record) in v4.chain(v6) {
+            if self
+                .with_record(addr, true, |client| *client.stats = *record)
+                .is_none()
+            {
+                return false;
+            }
+        }
+
+        let mut table = self.countries.lock().unwrap();
+        table.stats.stats_mut().copy_from_slice(&snapshot.countries);
+        true
+    }
+
+    fn count(&self, totals: &mut [CountryTotals]) {
+        for shard in &self.shards {
+            shard.lock().unwrap().count(totals);
+        }
+    }
+}
+
+impl<R: Records, C: CountryStorage> StatsStore for ShardedStore<R, C> {
+    fn with_record<T>(
+        &self,
+        addr: IpAddr,
+        create: bool,
+        f: impl FnOnce(&mut ClientRecord<'_>) -> T,
+    ) -> Option<T> {
+        let idx = shard_of(addr)?;
+        self.shards[idx]
+            .lock()
+            .unwrap()
+            .with_record(addr, create, f)
+    }
+
+    fn cleanup(&self, now: u64, liveness_window: u64) {
+        for shard in &self.shards {
+            shard.lock().unwrap().cleanup(now, liveness_window);
+        }
+    }
+
+    fn recover(&self, now: u64, liveness_window: u64) -> usize {
+        let mut totals = vec![CountryTotals::default(); COUNTRIES.len()];
+        for shard in &self.shards {
+            shard
+                .lock()
+                .unwrap()
+                .recover(now, liveness_window, &mut totals);
+        }
+
+        let mut table = self.countries.lock().unwrap();
+        let mut corrected = 0;
+        for (stats, totals) in table.stats.stats_mut().iter_mut().zip(totals) {
+            let totals = totals.with_forgotten(stats);
+            if CountryTotals::stored(stats) != totals {
+                stats.active = totals.active;
+                stats.unique = totals.unique;
+                stats.cum_duration = totals.cum_duration;
+                corrected += 1;
+            }
+        }
+        corrected
+    }
+
+    fn merge(&self, aggregation: Aggregation) -> CountrySnapshot {
+        let mut table = self.countries.lock().unwrap();
+        let CountryTable { stats, recent } = &mut *table;
+        let countries = stats.stats_mut();
+        let mut touched = [false; COUNTRIES.len()];
+
+        for shard in &self.shards {
+            let pending = mem::take(&mut shard.lock().unwrap().pending);
+
+            for (idx, delta) in pending.deltas.iter().enumerate() {
+                for country in [idx, WORLDWIDE as usize] {
+                    let stats = &mut countries[country];
+                    stats.active = (stats.active as i64 + delta.active).max(0) as u32;
+                    stats.unique = (stats.unique as i64 + delta.unique).max(0) as u32;
+                    stats.cum_duration = stats.cum_duration.wrapping_add(delta.cum_duration);
+                    stats.forgotten = stats.forgotten.wrapping_add(delta.forgotten);
+                    stats.forgotten_duration = stats
+                        .forgotten_duration
+                        .wrapping_add(delta.forgotten_duration);
+                }
+            }
+
+            for (country, contributor, seed) in pending.seeds {
+                for country in [country as usize, WORLDWIDE as usize] {
+                    let stats = &mut countries[country];
+                    aggregation.contribute(
+                        &mut stats.seed,
+                        &mut recent[country],
+                        contributor,
+                        seed,
+                    );
+                    touched[country] = true;
+                }
+            }
+        }
+
+        for (country, _) in touched.iter().enumerate().filter(|(_, touched)| **touched) {
+            aggregation.settle(&mut countries[country].seed, &recent[country]);
+        }
+
+        CountrySnapshot::new(countries)
+    }
+
+    fn fsck(&self, repair: bool) -> io::Result<Vec<Discrepancy>> {
+        let mut totals = vec![CountryTotals::default(); COUNTRIES.len()];
+        self.count(&mut totals);
+
+        let mut table = self.countries.lock().unwrap();
+        let countries = table.stats.stats_mut();
+        let discrepancies: Vec<_> = countries
+            .iter()
+            .zip(totals)
+            .map(|(stats, totals)| (stats, totals.with_forgotten(stats)))
+            .enumerate()
+            .filter(|(_, (stats, counted))| CountryTotals::stored(stats) != *counted)
+            .map(|(country, (stats, counted))| Discrepancy {
+                country: country as u8,
+                stored: CountryTotals::stored(stats),
+                counted,
+            })
+            .collect();
+
+        if repair && !discrepancies.is_empty() {
+            for discrepancy in &discrepancies {
+                let stats = &mut countries[discrepancy.country as usize];
+                stats.active = discrepancy.counted.active;
+                stats.unique = discrepancy.counted.unique;
+                stats.cum_duration = discrepancy.counted.cum_duration;
+            }
+            table.stats.flush()?;
+        }
+        Ok(discrepancies)
+    }
+
+    fn snapshot(&self, created_at: u64) -> Snapshot {
+        let mut snapshot = Snapshot {
+            created_at,
+            countries: Vec::new(),
+            v4: Vec::new(),
+            v6: Vec::new(),
+        };
+        for shard in &self.shards {
+            shard.lock().unwrap().records.snapshot(&mut snapshot);
+        }
+
+        snapshot.countries = self.countries.lock().unwrap().stats.stats().to_vec();
+        snapshot
+    }
+
+    // No ordering or generation between the flushes, see the trait
+    fn checkpoint(&self) -> io::Result<()> {
+        for shard in &self.shards {
+            shard.lock().unwrap().records.flush()?;
+        }
+        self.countries.lock().unwrap().stats.flush()
+    }
+}
+
+// Keeps records in a hash map keyed by the client's /24 or /64, so only clients that are active take up memory
+#[derive(Debug, Default)]
+pub struct MemoryRecords(HashMap<IpAddr, StoredIpStats>);
+
+impl MemoryRecords {
+    fn key_of(addr: IpAddr) -> IpAddr {
+        match addr {
+            IpAddr::V4(addr) => IpAddr::V4(Ipv4Addr::from_bits(addr.to_bits() & !0xFF)),
+            IpAddr::V6(addr) => {
+                IpAddr::V6(Ipv6Addr::from_bits(addr.to_bits() & !(u64::MAX as u128)))
+            }
+        }
+    }
+}
+
+impl Records for MemoryRecords {
+    type Key = IpAddr;
+
+    fn key(&mut self, addr: IpAddr) -> Option<IpAddr> {
+        let key = Self::key_of(addr);
+        self.0.contains_key(&key).then_some(key)
+    }
+
+    fn insert(&mut self, addr: IpAddr) -> Option<IpAddr> {
+        let key = Self::key_of(addr);
+        self.0.entry(key).or_default();
+        Some(key)
+    }
+
+    fn get_mut(&mut self, key: IpAddr) -> Option<&mut StoredIpStats> {
+        self.0.get_mut(&key)
+    }
+
+    // Otherwise every address that ever sent a heartbeat would stay in memory for good
+    fn remove(&mut self, key: IpAddr) -> bool {
+        self.0.remove(&key).is_some()
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (IpAddr, &mut StoredIpStats)> {
+        self.0.iter_mut().map(|(key, record)| (*key, record))
+    }
+
+    fn snapshot(&mut self, snapshot: &mut Snapshot) {
+        for (key, record) in &self.0 {
+            match key {
+                IpAddr::V4(addr) => snapshot.v4.push((addr.to_bits() >> 8, *record)),
+                IpAddr::V6(addr) => snapshot.v6.push(StoredIpv6Stats {
+                    prefix: (addr.to_bits() >> 64) as u64,
+                    stats: *record,
+                }),
+            }
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CountryStorage for Vec<StoredCountryStats> {
+    fn stats(&self) -> &[StoredCountryStats] {
+        self
+    }
+
+    fn stats_mut(&mut self) -> &mut [StoredCountryStats] {
+        self
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Forgets everything when it's dropped. For tests, and for deployments small enough not to care about a restart
+// starting over from zero. Unlike the sparse files it also gives up the records of IPv4 networks that went quiet, so
+// those count as new clients when they come back.
+pub type MemoryStore = ShardedStore<MemoryRecords, Vec<StoredCountryStats>>;
+
+impl MemoryStore {
+    pub fn empty(recent_window: usize) -> Self {
+        let shards = (0..SHARDS).map(|_| MemoryRecords::default()).collect();
+        let countries = vec![bytemuck::Zeroable::zeroed(); COUNTRIES.len()];
+
+        Self::new(shards, countries, recent_window)
+    }
+}
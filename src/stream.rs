@@ -0,0 +1,62 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{drums::DrummerState, harmony::HarmonyState, notes::GeneratorState, notes::NoiseRng};
+
+// Everything an arranger needs to carry on exactly where its last chunk stopped, without the tables it was built with
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreamState {
+    pub(crate) melody: GeneratorState,
+    pub(crate) harmony: HarmonyState,
+    pub(crate) voicing: NoiseRng,
+    pub(crate) drums: DrummerState,
+}
+
+#[derive(Debug)]
+pub enum StateError {
+    // Not a token this version wrote
+    Malformed,
+    // Which part doesn't fit the generator it's resumed on, like histories from a different model or scale
+    Mismatched(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed => write!(f, "malformed stream state"),
+            Self::Mismatched(part) => write!(f, "stream state doesn't fit: bad {part}"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl StreamState {
+    pub fn melody(&self) -> &GeneratorState {
+        &self.melody
+    }
+
+    // Postcard, in hex so it can go in a query string as is
+    pub fn to_token(&self) -> String {
+        let bytes = postcard::to_extend(self, Vec::new()).expect("vecs don't run out of room");
+        bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+
+    pub fn from_token(token: &str) -> Result<Self, StateError> {
+        if !token.len().is_multiple_of(2) {
+            return Err(StateError::Malformed);
+        }
+
+        let bytes = (0..token.len())
+            .step_by(2)
+            .map(|idx| {
+                token
+                    .get(idx..idx + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or(StateError::Malformed)?;
+        postcard::from_bytes(&bytes).map_err(|_| StateError::Malformed)
+    }
+}
@@ -0,0 +1,315 @@
+use std::f32::consts::TAU;
+
+use crate::{
+    drums::{CRASH, KICK, OPEN_HAT, SNARE},
+    harmony::{Arrangement, Voice},
+    meter::Meter,
+    notes::Note,
+};
+
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// The page detunes every oscillator by a random amount of up to this many cents
+const MAX_DETUNE_CENTS: f32 = 6.;
+// A whole band adds up to a lot more than a melody, the page turns it down just as much
+const ARRANGEMENT_GAIN: f32 = 0.4;
+
+pub fn midi_to_frequency(pitch: i8) -> f32 {
+    440. * 2f32.powf((pitch as f32 - 69.) / 12.)
+}
+
+// Levels are relative to the note's velocity, times are in seconds
+#[derive(Clone, Copy, Debug)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    // Time constant of the exponential fade out
+    pub release: f32,
+    // How long before the end of the note the release starts
+    pub release_lead: f32,
+}
+
+impl Default for Envelope {
+    // What the page does, plus a few milliseconds of attack so notes don't start with a click
+    fn default() -> Self {
+        Self {
+            attack: 0.005,
+            decay: 0.,
+            sustain: 1.,
+            release: 0.1,
+            release_lead: 0.2,
+        }
+    }
+}
+
+impl Envelope {
+    fn held(&self, t: f32) -> f32 {
+        if t < self.attack {
+            t / self.attack
+        } else if t < self.attack + self.decay {
+            1. - (1. - self.sustain) * (t - self.attack) / self.decay
+        } else {
+            self.sustain
+        }
+    }
+
+    // Level at `t` seconds into a note lasting `length` seconds
+    pub fn level(&self, t: f32, length: f32) -> f32 {
+        let release_start = (length - self.release_lead).max(0.);
+        if t < release_start {
+            return self.held(t);
+        }
+
+        self.held(release_start) * (-(t - release_start) / self.release).exp()
+    }
+}
+
+// Tiny xorshift, the detune and drum noise only have to sound random, not be random
+struct XorShift(u32);
+
+impl XorShift {
+    fn next_unit(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1 << 24) as f32
+    }
+
+    fn next_cents(&mut self) -> f32 {
+        self.next_unit() * MAX_DETUNE_CENTS
+    }
+
+    fn next_noise(&mut self) -> f32 {
+        self.next_unit() * 2. - 1.
+    }
+}
+
+// How a drum sounds: seconds it lasts, how fast its noise and tone die away, and how much of each there is
+struct Drum {
+    length: f32,
+    noise_decay: f32,
+    noise: f32,
+    tone_decay: f32,
+    tone: f32,
+    // Hats are noise with the low end taken out
+    bright: bool,
+}
+
+fn drum(pitch: i8) -> Drum {
+    let hat = |length, noise_decay| Drum {
+        length,
+        noise_decay,
+        noise: 0.6,
+        tone_decay: 1.,
+        tone: 0.,
+        bright: true,
+    };
+
+    match pitch {
+        KICK => Drum {
+            length: 0.4,
+            noise_decay: 0.005,
+            noise: 0.2,
+            tone_decay: 0.15,
+            tone: 1.,
+            bright: false,
+        },
+        SNARE => Drum {
+            length: 0.25,
+            noise_decay: 0.05,
+            noise: 0.7,
+            tone_decay: 0.08,
+            tone: 0.3,
+            bright: false,
+        },
+        OPEN_HAT => hat(0.35, 0.12),
+        CRASH => hat(1.5, 0.5),
+        // Closed hats, and anything else that comes along
+        _ => hat(0.08, 0.015),
+    }
+}
+
+// Start and length in seconds for a note starting `start` beats in that lasts `duration` sixteenths
+fn timing(meter: Meter, start: f32, duration: u8) -> (f32, f32) {
+    let seconds = meter.seconds_per_beat();
+    (start * seconds, duration as f32 / 4. * seconds)
+}
+
+pub struct Synth {
+    pub sample_rate: u32,
+    pub envelope: Envelope,
+}
+
+impl Default for Synth {
+    fn default() -> Self {
+        Self {
+            sample_rate: SAMPLE_RATE,
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl Synth {
+    // Mono samples between -1 and 1, at the meter's tempo and from the bar line before the first note. The detune seed
+    // makes renders of the same notes come out identical.
+    pub fn render(
+        &self,
+        notes: impl IntoIterator<Item = Note>,
+        meter: Meter,
+        detune_seed: i32,
+    ) -> Vec<f32> {
+        let mut detune = XorShift(detune_seed as u32 | 1);
+        let mut samples = Vec::new();
+        let mut origin = None;
+
+        for note in notes {
+            let origin = *origin.get_or_insert_with(|| meter.bar_start(note.start));
+            let (start, length) = timing(meter, note.start - origin, note.duration);
+            self.add_note(&mut samples, start, length, &note, &mut detune);
+        }
+
+        clip(&mut samples);
+        samples
+    }
+
+    // The melody with every voice of its accompaniment, timed the way the page plays them
+    pub fn render_arrangement(&self, arrangement: &Arrangement, detune_seed: i32) -> Vec<f32> {
+        let mut detune = XorShift(detune_seed as u32 | 1);
+        // Separate, so the melody is detuned the same with or without drums
+        let mut noise = XorShift(detune_seed as u32 | 2);
+        let mut samples = Vec::new();
+        let meter = arrangement.meter;
+        let origin = meter.bar_start(arrangement.start);
+
+        for note in &arrangement.melody {
+            let (start, length) = timing(meter, note.start - origin, note.duration);
+            self.add_note(&mut samples, start, length, note, &mut detune);
+        }
+
+        for track in &arrangement.tracks {
+            for note in &track.notes {
+                let (start, length) = timing(meter, note.start - origin, note.duration);
+                if track.voice == Voice::Drums {
+                    self.add_hit(&mut samples, start, note.pitch, note.velocity, &mut noise);
+                    continue;
+                }
+
+                let note = Note {
+                    start: note.start,
+                    pitch: note.pitch,
+                    velocity: note.velocity,
+                    duration: note.duration,
+                };
+                self.add_note(&mut samples, start, length, &note, &mut detune);
+            }
+        }
+
+        for sample in &mut samples {
+            *sample *= ARRANGEMENT_GAIN;
+        }
+        clip(&mut samples);
+        samples
+    }
+
+    // Mixes one note in at `start` seconds, lasting `length` seconds
+    fn add_note(
+        &self,
+        samples: &mut Vec<f32>,
+        start: f32,
+        length: f32,
+        note: &Note,
+        detune: &mut XorShift,
+    ) {
+        let rate = self.sample_rate as f32;
+        let cents = detune.next_cents();
+        let frequency = midi_to_frequency(note.pitch) * 2f32.powf(cents / 1200.);
+
+        let first = (start * rate) as usize;
+        let count = (length * rate) as usize;
+        if samples.len() < first + count {
+            samples.resize(first + count, 0.);
+        }
+
+        for (idx, sample) in samples[first..first + count].iter_mut().enumerate() {
+            let t = idx as f32 / rate;
+            let level = note.velocity * self.envelope.level(t, length);
+            *sample += level * (TAU * frequency * t).sin();
+        }
+    }
+
+    fn add_hit(
+        &self,
+        samples: &mut Vec<f32>,
+        start: f32,
+        pitch: i8,
+        velocity: f32,
+        noise: &mut XorShift,
+    ) {
+        let rate = self.sample_rate as f32;
+        let drum = drum(pitch);
+
+        let first = (start * rate) as usize;
+        let count = (drum.length * rate) as usize;
+        if samples.len() < first + count {
+            samples.resize(first + count, 0.);
+        }
+
+        let mut last_white = 0.;
+        for (idx, sample) in samples[first..first + count].iter_mut().enumerate() {
+            let t = idx as f32 / rate;
+
+            let mut white = noise.next_noise();
+            if drum.bright {
+                (white, last_white) = ((white - last_white) / 2., white);
+            }
+            // Kicks and snares drop in pitch as they ring out, from 150 to 50Hz and 250 to 180Hz
+            let cycles = if pitch == KICK {
+                50. * t + 100. * 0.03 * (1. - (-t / 0.03).exp())
+            } else {
+                180. * t + 70. * 0.02 * (1. - (-t / 0.02).exp())
+            };
+
+            let level = drum.noise * white * (-t / drum.noise_decay).exp()
+                + drum.tone * (TAU * cycles).sin() * (-t / drum.tone_decay).exp();
+            *sample += velocity * level;
+        }
+    }
+}
+
+// Overlapping notes can add up past full scale, which the browser clips too
+fn clip(samples: &mut [f32]) {
+    for sample in samples {
+        *sample = sample.clamp(-1., 1.);
+    }
+}
+
+// 16-bit mono PCM
+pub fn write_wav(samples: &[f32], sample_rate: u32) -> Vec<u8> {
+    let data_len = samples.len() as u32 * 2;
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(36 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, one channel
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&1u16.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    // Block align and bits per sample
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&16u16.to_le_bytes());
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for sample in samples {
+        let pcm = (sample * i16::MAX as f32) as i16;
+        out.extend_from_slice(&pcm.to_le_bytes());
+    }
+
+    out
+}
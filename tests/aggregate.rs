@@ -0,0 +1,87 @@
+use melodybrain::aggregate::{Aggregation, RecentSeeds};
+
+const WINDOW: usize = 101;
+const ADVERSARY: u64 = u64::MAX;
+
+// 100 honest contributors spread evenly over -5000..5000, followed by one contributor flooding an extreme seed
+fn run(aggregation: Aggregation, floods: usize) -> (i64, RecentSeeds) {
+    let mut seed = 0;
+    let mut recent = RecentSeeds::new(aggregation.window(WINDOW));
+
+    for contributor in 0..100 {
+        let value = contributor as i32 * 100 - 5000;
+        aggregation.contribute(&mut seed, &mut recent, contributor, value);
+    }
+    aggregation.settle(&mut seed, &recent);
+
+    for _ in 0..floods {
+        aggregation.contribute(&mut seed, &mut recent, ADVERSARY, i32::MAX);
+        aggregation.settle(&mut seed, &recent);
+    }
+
+    (seed, recent)
+}
+
+#[test]
+fn ema_is_dragged_by_a_single_contributor() {
+    let (seed, _) = run(Aggregation::Ema { divisor: 2000 }, 100_000);
+
+    assert!(seed > i32::MAX as i64 / 2);
+}
+
+#[test]
+fn median_moves_at_most_one_rank() {
+    let (before, _) = run(Aggregation::Median, 0);
+    let (after, recent) = run(Aggregation::Median, 100_000);
+
+    assert_eq!(recent.len(), 101);
+    assert!((-5000..=5000).contains(&before));
+    // The next honest seed up from the middle is only 100 away
+    assert!(after - before <= 100, "{before} -> {after}");
+}
+
+#[test]
+fn trimmed_mean_ignores_the_outlier() {
+    let aggregation = Aggregation::TrimmedMean { trim_percent: 20 };
+    let (before, _) = run(aggregation, 0);
+    let (after, _) = run(aggregation, 100_000);
+
+    // Trimmed away entirely, only shifts which honest seeds are kept
+    assert!((after - before).abs() <= 100, "{before} -> {after}");
+}
+
+#[test]
+fn flooding_does_not_crowd_out_others() {
+    let mut recent = RecentSeeds::new(10);
+
+    for contributor in 0..5 {
+        recent.push(contributor, 0);
+    }
+    for _ in 0..1000 {
+        recent.push(ADVERSARY, i32::MAX);
+    }
+
+    assert_eq!(recent.len(), 6);
+    assert_eq!(recent.median(), Some(0));
+}
+
+#[test]
+fn oldest_contributor_is_evicted() {
+    let mut recent = RecentSeeds::new(3);
+
+    recent.push(1, 1000);
+    recent.push(2, 0);
+    recent.push(3, 0);
+    recent.push(4, 0);
+
+    assert_eq!(recent.len(), 3);
+    assert_eq!(recent.trimmed_mean(0), Some(0));
+}
+
+#[test]
+fn empty_window_leaves_seed_alone() {
+    let mut seed = 1234;
+    Aggregation::Median.settle(&mut seed, &RecentSeeds::new(WINDOW));
+
+    assert_eq!(seed, 1234);
+}
@@ -0,0 +1,242 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use melodybrain::{
+    COUNTRIES, Cookie, Message, ProtocolError, Stats, StatsRequest,
+    client::{Client, Fetched, RETRY_BACKOFF},
+};
+use tokio::net::UdpSocket;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+const COOKIE: Cookie = Cookie([9; 8]);
+
+fn stats(connected: u32) -> Box<Stats> {
+    Box::new(Stats {
+        connected,
+        seed: 0,
+        country_heatmap: [0.0; COUNTRIES.len()],
+    })
+}
+
+// A server socket and a client connected to it, with its dispatcher running
+async fn connect(retries: u32) -> (UdpSocket, Arc<Client>) {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    sock.connect(server.local_addr().unwrap()).await.unwrap();
+
+    let client = Arc::new(Client::new(sock, TIMEOUT, retries));
+    let dispatcher = Arc::clone(&client);
+    tokio::spawn(async move { dispatcher.dispatch().await });
+
+    (server, client)
+}
+
+async fn recv_request(server: &UdpSocket) -> (StatsRequest, SocketAddr) {
+    let mut buf = [0; 1200];
+    loop {
+        let (n, addr) = server.recv_from(&mut buf).await.unwrap();
+        if let Ok(Message::StatsRequest(request)) = Message::decode(&buf[..n]) {
+            return (request, addr);
+        }
+    }
+}
+
+async fn send(server: &UdpSocket, addr: SocketAddr, msg: Message) {
+    let mut buf = [0; 1200];
+    server
+        .send_to(msg.encode(&mut buf).unwrap(), addr)
+        .await
+        .unwrap();
+}
+
+async fn send_stats(server: &UdpSocket, addr: SocketAddr, id: u32, connected: u32) {
+    let msg = Message::Stats {
+        id,
+        stats: stats(connected),
+    };
+    send(server, addr, msg).await;
+}
+
+// Answers every stats request after the first `dropped` with stats whose connected count is the requested country.
+// Returns how many requests it saw.
+fn serve(server: UdpSocket, dropped: usize) -> Arc<AtomicUsize> {
+    let seen = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&seen);
+
+    tokio::spawn(async move {
+        loop {
+            let (request, addr) = recv_request(&server).await;
+            if counter.fetch_add(1, Ordering::Relaxed) < dropped {
+                continue;
+            }
+
+            send_stats(&server, addr, request.id, request.country as u32).await;
+        }
+    });
+    seen
+}
+
+fn connected(fetched: Fetched) -> Option<(u32, bool)> {
+    match fetched {
+        Fetched::Fresh(stats) => Some((stats.connected, false)),
+        Fetched::Stale(stats) => Some((stats.connected, true)),
+        Fetched::Offline => None,
+    }
+}
+
+#[tokio::test]
+async fn replies_reach_the_request_with_their_id() {
+    let (server, client) = connect(0).await;
+
+    let first = tokio::spawn({
+        let client = Arc::clone(&client);
+        async move { client.fetch_stats(1, 0).await }
+    });
+    let (request_a, addr) = recv_request(&server).await;
+    let second = tokio::spawn({
+        let client = Arc::clone(&client);
+        async move { client.fetch_stats(2, 0).await }
+    });
+    let (request_b, _) = recv_request(&server).await;
+    assert_ne!(request_a.id, request_b.id);
+
+    // Nobody asked for these
+    send_stats(&server, addr, 0, 100).await;
+    send_stats(&server, addr, u32::MAX, 100).await;
+    // Answered the other way around
+    for request in [request_b, request_a] {
+        send_stats(&server, addr, request.id, request.country as u32 * 10).await;
+    }
+
+    assert_eq!(connected(first.await.unwrap()), Some((10, false)));
+    assert_eq!(connected(second.await.unwrap()), Some((20, false)));
+}
+
+#[tokio::test]
+async fn late_replies_are_dropped() {
+    let (server, client) = connect(0).await;
+
+    let fetch = tokio::spawn({
+        let client = Arc::clone(&client);
+        async move { client.fetch_stats(1, 0).await }
+    });
+    let (late, addr) = recv_request(&server).await;
+    assert_eq!(connected(fetch.await.unwrap()), None);
+
+    // The next request doesn't take the answer to the one before
+    let fetch = tokio::spawn({
+        let client = Arc::clone(&client);
+        async move { client.fetch_stats(1, 0).await }
+    });
+    let (request, _) = recv_request(&server).await;
+    send_stats(&server, addr, late.id, 1).await;
+    send_stats(&server, addr, request.id, 2).await;
+    assert_eq!(connected(fetch.await.unwrap()), Some((2, false)));
+}
+
+#[tokio::test]
+async fn unanswered_requests_are_retried_with_backoff() {
+    let (server, client) = connect(2).await;
+    let seen = serve(server, 2);
+
+    let start = Instant::now();
+    assert_eq!(connected(client.fetch_stats(3, 0).await), Some((3, false)));
+
+    assert_eq!(seen.load(Ordering::Relaxed), 3);
+    // Two timeouts and the backoff before each retry
+    assert!(start.elapsed() >= 2 * TIMEOUT + RETRY_BACKOFF + 2 * RETRY_BACKOFF);
+}
+
+#[tokio::test]
+async fn the_last_stats_are_served_once_the_server_stops_answering() {
+    let (server, client) = connect(1).await;
+    let seen = Arc::new(AtomicUsize::new(0));
+
+    // Answers the first request only
+    tokio::spawn({
+        let seen = Arc::clone(&seen);
+        async move {
+            loop {
+                let (request, addr) = recv_request(&server).await;
+                if seen.fetch_add(1, Ordering::Relaxed) == 0 {
+                    send_stats(&server, addr, request.id, 6).await;
+                }
+            }
+        }
+    });
+
+    assert_eq!(connected(client.fetch_stats(6, 0).await), Some((6, false)));
+    // Retried once before giving up
+    assert_eq!(connected(client.fetch_stats(6, 0).await), Some((6, true)));
+    assert_eq!(seen.load(Ordering::Relaxed), 3);
+
+    // Known to be offline now, so no more retries
+    assert_eq!(connected(client.fetch_stats(7, 0).await), None);
+    assert_eq!(seen.load(Ordering::Relaxed), 4);
+}
+
+#[tokio::test]
+async fn cookies_are_echoed_back() {
+    let (server, client) = connect(0).await;
+
+    let fetch = tokio::spawn({
+        let client = Arc::clone(&client);
+        async move { client.fetch_stats(8, 0).await }
+    });
+    let (request, addr) = recv_request(&server).await;
+    assert_eq!(request.heartbeat.cookie, Cookie::default());
+    send(
+        &server,
+        addr,
+        Message::Cookie {
+            id: request.id,
+            cookie: COOKIE,
+        },
+    )
+    .await;
+
+    let (request, _) = recv_request(&server).await;
+    assert_eq!(request.heartbeat.cookie, COOKIE);
+    send_stats(&server, addr, request.id, 8).await;
+    assert_eq!(connected(fetch.await.unwrap()), Some((8, false)));
+
+    // Plain heartbeats carry it too
+    client.send_heartbeat(0).await;
+    let mut buf = [0; 1200];
+    let (n, _) = server.recv_from(&mut buf).await.unwrap();
+    assert!(matches!(
+        Message::decode(&buf[..n]),
+        Ok(Message::Heartbeat(heartbeat)) if heartbeat.cookie == COOKIE
+    ));
+}
+
+#[tokio::test]
+async fn refused_requests_are_not_retried() {
+    let (server, client) = connect(3).await;
+
+    let fetch = tokio::spawn({
+        let client = Arc::clone(&client);
+        async move { client.fetch_stats(9, 0).await }
+    });
+    let (request, addr) = recv_request(&server).await;
+    let error = ProtocolError::RateLimited;
+    send(
+        &server,
+        addr,
+        Message::Error {
+            id: request.id,
+            error,
+        },
+    )
+    .await;
+
+    let start = Instant::now();
+    assert_eq!(connected(fetch.await.unwrap()), None);
+    assert!(start.elapsed() < RETRY_BACKOFF);
+}
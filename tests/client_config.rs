@@ -0,0 +1,136 @@
+use std::{fs, path::PathBuf, sync::Mutex, time::Duration};
+
+// The binary's own module, it only needs the library
+#[allow(dead_code)]
+#[path = "../src/selfhosted/config.rs"]
+mod config;
+
+use config::{Config, ConfigError};
+
+// Environment variables are shared by every test in here
+static ENV: Mutex<()> = Mutex::new(());
+
+// A config file in the temp directory, removed again when it's dropped
+struct TempConfig(PathBuf);
+
+impl TempConfig {
+    fn new(name: &str, contents: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "melodybrain-client-{}-{name}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempConfig {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn load(file: &TempConfig, flags: &[&str]) -> Result<Config, ConfigError> {
+    let path = file.0.to_str().unwrap();
+    Config::parse_from(["melodybrain-client", "--config", path].iter().chain(flags))
+}
+
+#[test]
+fn defaults_keep_clients_connected() {
+    let _env = ENV.lock().unwrap();
+    let config = load(&TempConfig::new("empty", ""), &[]).unwrap();
+
+    assert_eq!(config.server, "ravenclaw900.duckdns.org:2026");
+    assert_eq!(config.http_addr, "0.0.0.0:33445".parse().unwrap());
+    assert_eq!(config.stats_timeout, Duration::from_secs(2));
+    assert_eq!(config.stats_retries, 3);
+    assert_eq!(config.crossfade_notes, 0);
+    // Under the server's default liveness window
+    assert!(config.heartbeat_interval < Duration::from_secs(10));
+}
+
+#[test]
+fn flags_win_over_the_environment_and_the_file() {
+    let _env = ENV.lock().unwrap();
+    let file = TempConfig::new(
+        "precedence",
+        "server = \"file.example.com:1\"\n\
+         http_port = 1111\n\
+         heartbeat_interval_secs = 1\n\
+         stats_retries = 1\n",
+    );
+
+    let config = load(&file, &[]).unwrap();
+    assert_eq!(config.server, "file.example.com:1");
+    assert_eq!(config.http_addr.port(), 1111);
+    assert_eq!(config.heartbeat_interval, Duration::from_secs(1));
+    assert_eq!(config.stats_retries, 1);
+
+    // SAFETY: every test that reads the environment holds the lock
+    unsafe {
+        std::env::set_var("MELODYBRAIN_SERVER", "env.example.com:2");
+        std::env::set_var("MELODYBRAIN_HTTP_PORT", "2222");
+    }
+    let from_env = load(&file, &[]);
+    let from_flags = load(&file, &["--server", "[::1]:3", "--stats-retries", "3"]);
+    unsafe {
+        std::env::remove_var("MELODYBRAIN_SERVER");
+        std::env::remove_var("MELODYBRAIN_HTTP_PORT");
+    }
+
+    let config = from_env.unwrap();
+    assert_eq!(config.server, "env.example.com:2");
+    assert_eq!(config.http_addr.port(), 2222);
+    assert_eq!(config.stats_retries, 1);
+
+    let config = from_flags.unwrap();
+    assert_eq!(config.server, "[::1]:3");
+    assert_eq!(config.http_addr.port(), 2222);
+    assert_eq!(config.stats_retries, 3);
+    assert_eq!(config.heartbeat_interval, Duration::from_secs(1));
+}
+
+#[test]
+fn bad_values_are_turned_down() {
+    let _env = ENV.lock().unwrap();
+    let empty = TempConfig::new("bad", "");
+
+    for server in [
+        "example.com",
+        "example.com:http",
+        ":2026",
+        "example.com:70000",
+    ] {
+        assert!(
+            matches!(load(&empty, &["--server", server]), Err(ConfigError::Server(bad)) if bad == server),
+            "{server}"
+        );
+    }
+    assert!(matches!(
+        load(
+            &TempConfig::new("bad-file-server", "server = \"nowhere\""),
+            &[]
+        ),
+        Err(ConfigError::Server(_))
+    ));
+
+    for (flag, option) in [
+        ("--heartbeat-interval-secs", "heartbeat_interval_secs"),
+        ("--stats-timeout-ms", "stats_timeout_ms"),
+        ("--model-order", "model_order"),
+    ] {
+        assert!(
+            matches!(load(&empty, &[flag, "0"]), Err(ConfigError::Zero(zero)) if zero == option),
+            "{option}"
+        );
+    }
+
+    assert!(matches!(
+        load(&TempConfig::new("unknown-key", "sever = \"typo:1\""), &[]),
+        Err(ConfigError::Parse(..))
+    ));
+    assert!(matches!(
+        Config::parse_from(["melodybrain-client", "--config", "/nonexistent/config.toml"]),
+        Err(ConfigError::Read(..))
+    ));
+}
@@ -0,0 +1,74 @@
+use std::{net::IpAddr, time::Duration};
+
+use melodybrain::{
+    Cookie, Message,
+    cookie::{COOKIE_EPOCH_SECS, CookieJar, reply_unverified},
+};
+use tokio::{net::UdpSocket, time::timeout};
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+// Right at the start of an epoch
+const NOW: u64 = 1000 * COOKIE_EPOCH_SECS;
+
+#[test]
+fn cookies_only_verify_for_their_address() {
+    let jar = CookieJar::new();
+    let cookie = jar.issue(ip("1.2.3.4"), NOW);
+
+    assert!(jar.verify(ip("1.2.3.4"), NOW, cookie));
+    assert!(jar.verify(ip("1.2.3.4"), NOW + COOKIE_EPOCH_SECS - 1, cookie));
+    assert!(!jar.verify(ip("1.2.3.5"), NOW, cookie));
+    assert!(!jar.verify(ip("::ffff:1.2.3.5"), NOW, cookie));
+    assert!(!jar.verify(ip("1.2.3.4"), NOW, Cookie::default()));
+
+    // Another jar, like after a restart
+    assert!(!CookieJar::new().verify(ip("1.2.3.4"), NOW, cookie));
+}
+
+#[test]
+fn cookies_outlive_their_epoch_by_one() {
+    let jar = CookieJar::new();
+    let cookie = jar.issue(ip("2001:db8::1"), NOW);
+
+    assert!(jar.verify(ip("2001:db8::1"), NOW + COOKIE_EPOCH_SECS, cookie));
+    assert!(jar.verify(ip("2001:db8::1"), NOW + 2 * COOKIE_EPOCH_SECS - 1, cookie));
+    assert!(!jar.verify(ip("2001:db8::1"), NOW + 2 * COOKIE_EPOCH_SECS, cookie));
+    // Issued in the next epoch, so it's a different one
+    assert_ne!(
+        jar.issue(ip("2001:db8::1"), NOW + COOKIE_EPOCH_SECS),
+        cookie
+    );
+}
+
+#[tokio::test]
+async fn unverified_replies_are_never_bigger_than_the_request() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = client.local_addr().unwrap();
+    let mut buf = [0; 1200];
+
+    let msg = Message::Cookie {
+        id: 0,
+        cookie: Cookie([7; 8]),
+    };
+    let len = msg.encode(&mut buf).unwrap().len();
+
+    assert!(!reply_unverified(&server, &mut buf, client_addr, &msg, len - 1).await);
+    assert!(reply_unverified(&server, &mut buf, client_addr, &msg, len).await);
+
+    // Only the second one arrives
+    let mut received = [0; 1200];
+    let n = client.recv(&mut received).await.unwrap();
+    assert!(matches!(
+        Message::decode(&received[..n]),
+        Ok(Message::Cookie {
+            id: 0,
+            cookie: Cookie([7, ..])
+        })
+    ));
+    let nothing_else = timeout(Duration::from_millis(100), client.recv(&mut received)).await;
+    assert!(nothing_else.is_err());
+}
@@ -0,0 +1,120 @@
+use std::net::IpAddr;
+
+use melodybrain::{
+    aggregate::Aggregation,
+    expiry::ExpiryIndex,
+    heartbeat::{RateLimit, Source, register_heartbeat},
+    store::{MemoryStore, StatsStore},
+};
+
+const LIVENESS_WINDOW: u64 = 10;
+const LIMIT: RateLimit = RateLimit {
+    burst: 100,
+    per_sec: 10,
+};
+const AGGREGATION: Aggregation = Aggregation::Ema { divisor: 1 };
+const COUNTRY: u8 = 5;
+
+fn drain(index: &mut ExpiryIndex<u32>, now: u64) -> Vec<u32> {
+    let mut due: Vec<_> = std::iter::from_fn(|| index.pop_due(now)).collect();
+    due.sort_unstable();
+    due
+}
+
+fn heartbeat(store: &MemoryStore, ip: &str, now: u64) {
+    let source = Source {
+        ip: ip.parse::<IpAddr>().unwrap(),
+        verified: true,
+    };
+    register_heartbeat(store, source, 0, now, LIVENESS_WINDOW, LIMIT, |_| {
+        Some(COUNTRY)
+    });
+}
+
+fn connected(store: &MemoryStore) -> u32 {
+    store.merge(AGGREGATION).stats(COUNTRY).connected
+}
+
+#[test]
+fn keys_come_out_when_they_are_due() {
+    let mut index = ExpiryIndex::new();
+    index.schedule(1, 1000);
+    index.schedule(2, 1003);
+    index.schedule(3, 1003);
+    assert_eq!(index.len(), 3);
+
+    assert_eq!(drain(&mut index, 999), [0u32; 0]);
+    assert_eq!(drain(&mut index, 1002), [1]);
+    assert_eq!(drain(&mut index, 1003), [2, 3]);
+    assert!(index.is_empty());
+}
+
+#[test]
+fn the_wheel_moves_on_past_its_length() {
+    let mut index = ExpiryIndex::new();
+    index.schedule(1, 1000);
+    index.schedule(2, 1005);
+    assert_eq!(drain(&mut index, 1005), [1, 2]);
+
+    // Far beyond every bucket the wheel had so far
+    index.schedule(3, 100_000);
+    // Due before the key that started the wheel over
+    index.schedule(4, 99_990);
+    assert_eq!(drain(&mut index, 99_989), [0u32; 0]);
+    assert_eq!(drain(&mut index, 99_990), [4]);
+    assert_eq!(drain(&mut index, 99_999), [0u32; 0]);
+    assert_eq!(drain(&mut index, 100_000), [3]);
+    assert!(index.is_empty());
+
+    // Already overdue, so it's due at the next pop
+    index.schedule(5, 100_002);
+    index.schedule(6, 50);
+    assert_eq!(drain(&mut index, 100_001), [6]);
+    assert_eq!(drain(&mut index, 100_002), [5]);
+}
+
+#[test]
+fn only_quiet_clients_expire() {
+    let store = MemoryStore::empty(1);
+    heartbeat(&store, "1.2.3.4", 1000);
+    heartbeat(&store, "5.6.7.8", 1000);
+    assert_eq!(connected(&store), 2);
+
+    // Due at 1000 + LIVENESS_WINDOW + 1, not any earlier
+    store.cleanup(1000 + LIVENESS_WINDOW, LIVENESS_WINDOW);
+    assert_eq!(connected(&store), 2);
+
+    // Refreshed, so it's only due again a liveness window after this heartbeat
+    heartbeat(&store, "5.6.7.8", 1000 + LIVENESS_WINDOW + 1);
+    store.cleanup(1000 + LIVENESS_WINDOW + 2, LIVENESS_WINDOW);
+    assert_eq!(connected(&store), 1);
+
+    store.cleanup(1000 + 2 * LIVENESS_WINDOW + 1, LIVENESS_WINDOW);
+    assert_eq!(connected(&store), 1);
+    store.cleanup(1000 + 2 * LIVENESS_WINDOW + 2, LIVENESS_WINDOW);
+    assert_eq!(connected(&store), 0);
+}
+
+#[test]
+fn recovery_rebuilds_the_index_from_records() {
+    let store = MemoryStore::empty(1);
+    heartbeat(&store, "1.2.3.4", 1000);
+    heartbeat(&store, "5.6.7.8", 1005);
+    store.merge(AGGREGATION);
+
+    // A store restored from its records knows nothing about when they're due
+    let restored = MemoryStore::empty(1);
+    assert!(restored.restore(&store.snapshot(1005)));
+    restored.cleanup(2000, LIVENESS_WINDOW);
+    assert_eq!(connected(&restored), 2);
+
+    let restored = MemoryStore::empty(1);
+    assert!(restored.restore(&store.snapshot(1005)));
+    restored.recover(1006, LIVENESS_WINDOW);
+    assert_eq!(connected(&restored), 2);
+
+    restored.cleanup(1000 + LIVENESS_WINDOW + 1, LIVENESS_WINDOW);
+    assert_eq!(connected(&restored), 1);
+    restored.cleanup(1005 + LIVENESS_WINDOW + 1, LIVENESS_WINDOW);
+    assert_eq!(connected(&restored), 0);
+}
@@ -0,0 +1,157 @@
+use std::{net::IpAddr, path::Path};
+
+use melodybrain::{
+    geo::{GeoChain, GeoError, GeoProvider, MaxMindDb, Override, Overrides, RangeTable},
+    search_country,
+};
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+fn country(code: &str) -> Option<u8> {
+    Some(search_country(code).unwrap())
+}
+
+#[test]
+fn dbip_ranges_are_looked_up() {
+    let table = RangeTable::parse(
+        "1.0.0.0,1.0.0.255,AU\n\
+         1.0.4.0,1.0.7.255,au\n\
+         2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,JP\n",
+    )
+    .unwrap();
+
+    assert_eq!(table.lookup(ip("1.0.0.7")), country("AU"));
+    assert_eq!(table.lookup(ip("1.0.5.1")), country("AU"));
+    assert_eq!(table.lookup(ip("::ffff:1.0.0.7")), country("AU"));
+    assert_eq!(table.lookup(ip("2001:200::1")), country("JP"));
+    assert_eq!(table.lookup(ip("1.0.1.0")), None);
+    assert_eq!(table.lookup(ip("0.255.255.255")), None);
+    assert_eq!(table.lookup(ip("2001:201::1")), None);
+}
+
+#[test]
+fn pseudo_countries_stay_unknown() {
+    let table = RangeTable::parse(
+        "1.0.0.0,1.0.0.255,XW
+         1.0.1.0,1.0.1.255,aa
+         1.0.2.0,1.0.2.255,NZ
+",
+    )
+    .unwrap();
+
+    assert_eq!(table.len(), 1);
+    assert_eq!(table.lookup(ip("1.0.0.1")), None);
+    assert_eq!(table.lookup(ip("1.0.1.1")), None);
+    assert_eq!(table.lookup(ip("1.0.2.1")), country("NZ"));
+    // They're real lines, only not ones with a country
+    assert_eq!(table.skipped(), None);
+}
+
+#[test]
+fn ip2location_ranges_are_looked_up() {
+    let table = RangeTable::parse(
+        "\"0\",\"16777215\",\"-\",\"-\"\n\
+         \"16777216\",\"16777471\",\"US\",\"United States of America\"\n\
+         \"281470698520832\",\"281470698521087\",\"DE\",\"Germany\"\n",
+    )
+    .unwrap();
+
+    // The unknown range isn't kept
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.lookup(ip("1.0.0.1")), country("US"));
+    // 1.0.1.0/24, written as an IPv4-mapped IPv6 number
+    assert_eq!(table.lookup(ip("1.0.1.200")), country("DE"));
+    assert_eq!(table.lookup(ip("0.0.0.1")), None);
+}
+
+#[test]
+fn header_rows_are_skipped() {
+    let table = RangeTable::parse(
+        "ip_from,ip_to,country\n\
+         1.0.0.0,1.0.0.255,AU\n\
+         2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,JP\n",
+    )
+    .unwrap();
+
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.skipped(), Some((1, 1)));
+    assert_eq!(table.lookup(ip("1.0.0.7")), country("AU"));
+    assert_eq!(table.lookup(ip("2001:200::1")), country("JP"));
+}
+
+#[test]
+fn malformed_lines_are_skipped_and_counted() {
+    let table = RangeTable::parse(
+        "1.0.0.0,1.0.0.255,AU\n\
+         1.0.1.0,AU\n\
+         1.0.2.255,1.0.2.0,AU\n\
+         1.0.4.0,1.0.7.255,AU\n",
+    )
+    .unwrap();
+    assert_eq!(table.len(), 2);
+    assert_eq!(table.skipped(), Some((2, 2)));
+    assert_eq!(table.lookup(ip("1.0.5.1")), country("AU"));
+    assert_eq!(
+        RangeTable::parse("1.0.0.0,1.0.0.255,AU").unwrap().skipped(),
+        None
+    );
+
+    // Only a file with nothing but those is turned down
+    assert_eq!(RangeTable::parse("1.0.0.255,1.0.0.0,AU").err(), Some(1));
+    assert_eq!(
+        RangeTable::parse("# ranges\nip_from,ip_to,country\n1.0.1.0,AU\n").err(),
+        Some(2)
+    );
+
+    assert!(matches!(
+        RangeTable::load(Path::new("/nonexistent/ranges.csv")),
+        Err(GeoError::Io(..))
+    ));
+    assert!(matches!(
+        MaxMindDb::open(Path::new("/nonexistent/GeoLite2-Country.mmdb")),
+        Err(GeoError::MaxMind(..))
+    ));
+}
+
+#[test]
+fn the_most_specific_override_wins() {
+    let overrides = Overrides::new(vec![
+        "10.0.0.0/8=US".parse().unwrap(),
+        "10.1.0.0/16=de".parse().unwrap(),
+        "fd00:1234::/32=FR".parse().unwrap(),
+        "192.168.1.10=NL".parse().unwrap(),
+    ]);
+
+    assert_eq!(overrides.lookup(ip("10.1.2.3")), country("DE"));
+    assert_eq!(overrides.lookup(ip("10.2.0.1")), country("US"));
+    assert_eq!(overrides.lookup(ip("fd00:1234:5::1")), country("FR"));
+    assert_eq!(overrides.lookup(ip("192.168.1.10")), country("NL"));
+    assert_eq!(overrides.lookup(ip("192.168.1.11")), None);
+
+    // Worldwide is every client's already, and AA nobody's
+    for bad in [
+        "10.0.0.0/8",
+        "10.0.0.0/33=US",
+        "10.0.0.0/8=XY",
+        "10.0.0.0/8=XW",
+        "10.0.0.0/8=aa",
+        "office=US",
+    ] {
+        assert!(bad.parse::<Override>().is_err(), "{bad}");
+    }
+}
+
+#[test]
+fn providers_are_asked_in_order() {
+    let mut geo = GeoChain::default();
+    assert_eq!(geo.lookup(ip("1.0.0.1")), None);
+
+    geo.push(Overrides::new(vec!["1.0.0.0/28=NL".parse().unwrap()]));
+    geo.push(RangeTable::parse("1.0.0.0,1.0.0.255,AU").unwrap());
+
+    assert_eq!(geo.lookup(ip("1.0.0.1")), country("NL"));
+    assert_eq!(geo.lookup(ip("1.0.0.100")), country("AU"));
+    assert_eq!(geo.lookup(ip("8.8.8.8")), None);
+}
@@ -0,0 +1,129 @@
+use melodybrain::{
+    drums::{KICK, SNARE},
+    harmony::{Arrangement, Arranger, Voice},
+    notes::{NoteGenerator, Scale},
+};
+
+fn arrange(seed: i32, scale: Scale) -> Arrangement {
+    Arranger::new(NoteGenerator::with_scale(0, seed, scale), 0, seed, scale).arrange(128)
+}
+
+// Share of melody notes that are in the chord playing under them
+fn chord_tone_share(arrangement: &Arrangement, melody: &[i8], key: i8) -> f32 {
+    let bar = arrangement.meter.bar_length();
+    let mut hits = 0;
+
+    for (note, &pitch) in arrangement.melody.iter().zip(melody) {
+        let chord = &arrangement.chords[(note.start * 4.) as usize / bar as usize];
+        if chord
+            .tones
+            .iter()
+            .any(|tone| (pitch - key - tone).rem_euclid(12) == 0)
+        {
+            hits += 1;
+        }
+    }
+
+    hits as f32 / melody.len() as f32
+}
+
+#[test]
+fn melody_leans_towards_chord_tones() {
+    let (mut arranged, mut plain) = (0., 0.);
+
+    for seed in 0..50 {
+        let arrangement = arrange(seed * 131, Scale::Major);
+        let key = 60 + NoteGenerator::new(0, seed * 131).key_offset();
+        let melody: Vec<_> = arrangement.melody.iter().map(|note| note.pitch).collect();
+        let unbiased: Vec<_> = NoteGenerator::new(0, seed * 131)
+            .take(128)
+            .map(|note| note.pitch)
+            .collect();
+
+        arranged += chord_tone_share(&arrangement, &melody, key);
+        plain += chord_tone_share(&arrangement, &unbiased, key);
+    }
+
+    assert!(arranged > plain + 0.15 * 50., "{arranged} vs {plain}");
+}
+
+#[test]
+fn accompaniment_stays_under_the_melody() {
+    for scale in Scale::ALL {
+        let arrangement = arrange(1234, scale);
+        let length: u32 = arrangement
+            .melody
+            .iter()
+            .map(|note| note.duration as u32)
+            .sum();
+        let bar = arrangement.meter.bar_length();
+        assert_eq!(arrangement.length, length as f32 / 4.);
+        assert_eq!(arrangement.chords.len() as u32, length.div_ceil(bar));
+        // The last bar gets played out
+        let bars = (arrangement.chords.len() as u32 * bar) as f32 / 4.;
+
+        let voices: Vec<_> = arrangement.tracks.iter().map(|track| track.voice).collect();
+        assert_eq!(
+            voices,
+            [Voice::Bass, Voice::Pad, Voice::Arpeggio, Voice::Drums]
+        );
+        for track in &arrangement.tracks {
+            assert!(!track.notes.is_empty());
+            for note in &track.notes {
+                assert!(note.start + note.duration as f32 / 4. <= bars);
+            }
+        }
+    }
+}
+
+#[test]
+fn progressions_start_at_home_and_repeat_per_seed() {
+    let a = arrange(99, Scale::Dorian);
+    let b = arrange(99, Scale::Dorian);
+
+    assert_eq!(a.chords[0].tones, [0, 3, 7]);
+    let tones = |arrangement: &Arrangement| -> Vec<_> {
+        arrangement.chords.iter().map(|chord| chord.tones).collect()
+    };
+    assert_eq!(tones(&a), tones(&b));
+}
+
+#[test]
+fn drums_keep_time_and_fill_every_fourth_bar() {
+    for seed in [1, 2, 3, 4, 5] {
+        let arrangement = arrange(seed * 1000, Scale::Major);
+        let drums = arrangement
+            .tracks
+            .iter()
+            .find(|track| track.voice == Voice::Drums)
+            .unwrap();
+        let bar_length = arrangement.meter.bar_length();
+        // Starts are in beats, bars in sixteenths
+        let bar_of = |start: f32| (start * 4.) as u32 / bar_length;
+        let hits = |bar: u32, pitch| {
+            drums
+                .notes
+                .iter()
+                .filter(|note| bar_of(note.start) == bar && note.pitch == pitch)
+                .count()
+        };
+
+        assert!(drums.notes.iter().all(|note| note.duration == 1));
+        assert!(
+            drums
+                .notes
+                .windows(2)
+                .all(|pair| pair[0].start <= pair[1].start)
+        );
+        for bar in 0..8 {
+            assert!(
+                drums
+                    .notes
+                    .iter()
+                    .any(|note| note.start * 4. == (bar * bar_length) as f32 && note.pitch == KICK)
+            );
+        }
+        assert!(hits(3, SNARE) > hits(2, SNARE));
+        assert!(hits(7, SNARE) > hits(6, SNARE));
+    }
+}
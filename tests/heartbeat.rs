@@ -0,0 +1,123 @@
+use std::net::IpAddr;
+
+use melodybrain::{
+    Cookie, StoredIpStats,
+    aggregate::Aggregation,
+    cookie::{COOKIE_EPOCH_SECS, CookieJar},
+    heartbeat::{RateLimit, Source, Verdict, register_heartbeat, take_token},
+    store::{MemoryStore, StatsStore},
+};
+
+const LIVENESS_WINDOW: u64 = 10;
+const COUNTRY: u8 = 7;
+const NOW: u64 = 1000 * COOKIE_EPOCH_SECS;
+
+fn ip(ip: &str) -> IpAddr {
+    ip.parse().unwrap()
+}
+
+#[test]
+fn buckets_refill_up_to_the_burst() {
+    let limit = RateLimit {
+        burst: 3,
+        per_sec: 1,
+    };
+    let mut record = StoredIpStats::default();
+
+    // A fresh record starts out full
+    for _ in 0..3 {
+        assert!(take_token(&mut record, 1000, limit));
+    }
+    assert!(!take_token(&mut record, 1000, limit));
+
+    assert!(take_token(&mut record, 1001, limit));
+    assert!(!take_token(&mut record, 1001, limit));
+
+    // However long it was quiet, it never has more than a burst
+    let taken = (0..10)
+        .filter(|_| take_token(&mut record, 5000, limit))
+        .count();
+    assert_eq!(taken, 3);
+}
+
+#[test]
+fn buckets_refill_across_timestamp_wraps() {
+    let limit = RateLimit {
+        burst: 10,
+        per_sec: 1,
+    };
+    let mut record = StoredIpStats {
+        refilled_at: u32::MAX,
+        ..Default::default()
+    };
+
+    // Two seconds later, in the next 2^32 seconds
+    let taken = (0..10)
+        .filter(|_| take_token(&mut record, (1 << 32) + 1, limit))
+        .count();
+    assert_eq!(taken, 2);
+}
+
+#[test]
+fn cookies_let_new_networks_in() {
+    let store = MemoryStore::empty(1);
+    let jar = CookieJar::new();
+    let limit = RateLimit {
+        burst: 100,
+        per_sec: 10,
+    };
+    let heartbeat = |addr: &str, cookie: Cookie| {
+        let addr = ip(addr);
+        let source = Source {
+            ip: addr,
+            verified: jar.verify(addr, NOW, cookie),
+        };
+        register_heartbeat(&store, source, 0, NOW, LIVENESS_WINDOW, limit, |_| {
+            Some(COUNTRY)
+        })
+    };
+
+    // Challenged first, like the server does with `Verdict::Unverified`
+    assert_eq!(heartbeat("1.2.3.4", Cookie::default()), Verdict::Unverified);
+    let cookie = jar.issue(ip("1.2.3.4"), NOW);
+    // Someone else's cookie proves nothing
+    assert_eq!(heartbeat("5.6.7.8", cookie), Verdict::Unverified);
+    assert_eq!(heartbeat("1.2.3.4", cookie), Verdict::Accepted);
+
+    let snapshot = store.merge(Aggregation::Median);
+    assert_eq!(snapshot.stats(COUNTRY).connected, 1);
+}
+
+#[test]
+fn rate_limited_heartbeats_count_for_nothing() {
+    let store = MemoryStore::empty(1);
+    let limit = RateLimit {
+        burst: 1,
+        per_sec: 1,
+    };
+    let heartbeat = |seed, now| {
+        let source = Source {
+            ip: ip("2001:db8::1"),
+            verified: true,
+        };
+        register_heartbeat(&store, source, seed, now, LIVENESS_WINDOW, limit, |_| {
+            Some(COUNTRY)
+        })
+    };
+
+    assert_eq!(heartbeat(1000, NOW), Verdict::Accepted);
+    store.merge(Aggregation::Median);
+    assert_eq!(
+        heartbeat(-1000, NOW + LIVENESS_WINDOW + 1),
+        Verdict::Accepted
+    );
+    // Out of tokens, so its seed doesn't get a say
+    assert_eq!(
+        heartbeat(5000, NOW + LIVENESS_WINDOW + 1),
+        Verdict::RateLimited
+    );
+
+    let snapshot = store.merge(Aggregation::Median);
+    assert_eq!(snapshot.stats(COUNTRY).connected, 1);
+    assert_eq!(snapshot.stats(COUNTRY).seed, -1000);
+}
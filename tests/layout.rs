@@ -0,0 +1,293 @@
+use std::{fs, os::unix::fs::FileExt, path::PathBuf};
+
+use bytemuck::{Zeroable, bytes_of, pod_read_unaligned};
+use melodybrain::{
+    StoredCountryStats, StoredIpStats, StoredIpv6Stats,
+    layout::{
+        self, COUNTRY_SIZE, COUNTRY_TABLE_OFFSET, FILE_LEN, FORMAT_VERSION, IPV6_MAX_PROBE,
+        Ipv6Slots, LayoutError, RECORD_SIZE, Snapshot, SnapshotError, TOMBSTONE,
+    },
+};
+
+// A fresh path in the temp directory, removed again when it's dropped
+struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("melodybrain-{}-{name}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn new_databases_get_a_header() {
+    let path = TempPath::new("new");
+    let file = layout::open_v4(&path.0).unwrap();
+
+    let header = layout::read_header(&file).unwrap().unwrap();
+    assert_eq!(header.version, FORMAT_VERSION);
+    assert_eq!(header.record_size as usize, RECORD_SIZE);
+    assert_eq!(header.country_size as usize, COUNTRY_SIZE);
+    assert_eq!(file.metadata().unwrap().len(), FILE_LEN);
+}
+
+#[test]
+fn headerless_databases_are_upgraded_in_place() {
+    let path = TempPath::new("headerless");
+    let country = StoredCountryStats {
+        seed: -1234,
+        active: 7,
+        unique: 9,
+        cum_duration: 600,
+        ..Zeroable::zeroed()
+    };
+    let record = StoredIpStats {
+        first_seen: 1_700_000_000,
+        last_seen: 1_700_000_100,
+        country: 5,
+        ..Default::default()
+    };
+    // 1.2.3.0/24
+    let record_offset = (0x010203 * RECORD_SIZE) as u64;
+    {
+        let file = fs::File::create(&path.0).unwrap();
+        file.set_len(FILE_LEN).unwrap();
+        file.write_all_at(bytes_of(&country), 5 * COUNTRY_SIZE as u64)
+            .unwrap();
+        file.write_all_at(bytes_of(&record), record_offset).unwrap();
+    }
+
+    // Twice, the second time there's nothing left to do
+    for _ in 0..2 {
+        let file = layout::open_v4(&path.0).unwrap();
+        assert_eq!(
+            layout::read_header(&file).unwrap().unwrap().version,
+            FORMAT_VERSION
+        );
+
+        let mut bytes = [0; COUNTRY_SIZE];
+        file.read_exact_at(&mut bytes, COUNTRY_TABLE_OFFSET + 5 * COUNTRY_SIZE as u64)
+            .unwrap();
+        let moved: StoredCountryStats = pod_read_unaligned(&bytes);
+        assert_eq!(
+            (moved.seed, moved.active, moved.unique, moved.cum_duration),
+            (-1234, 7, 9, 600)
+        );
+
+        let mut bytes = [0; RECORD_SIZE];
+        file.read_exact_at(&mut bytes, record_offset).unwrap();
+        let kept: StoredIpStats = pod_read_unaligned(&bytes);
+        assert_eq!((kept.first_seen, kept.country), (1_700_000_000, 5));
+    }
+}
+
+#[test]
+fn other_layouts_are_refused() {
+    let path = TempPath::new("mismatched");
+    let header = layout::read_header(&layout::open_v4(&path.0).unwrap())
+        .unwrap()
+        .unwrap();
+    let file = fs::OpenOptions::new().write(true).open(&path.0).unwrap();
+
+    let bigger = layout::StoredHeader {
+        record_size: 40,
+        ..header
+    };
+    file.write_all_at(bytes_of(&bigger), 0).unwrap();
+    assert!(matches!(
+        layout::open_v4(&path.0),
+        Err(LayoutError::Mismatched {
+            field: "record size",
+            found: 40,
+            ..
+        })
+    ));
+
+    let newer = layout::StoredHeader {
+        version: FORMAT_VERSION + 1,
+        ..header
+    };
+    file.write_all_at(bytes_of(&newer), 0).unwrap();
+    assert!(matches!(
+        layout::open_v4(&path.0),
+        Err(LayoutError::TooNew(_, version)) if version == FORMAT_VERSION + 1
+    ));
+
+    // Something else entirely is left alone
+    let other = TempPath::new("other");
+    fs::write(&other.0, "not a database").unwrap();
+    assert!(matches!(
+        layout::open_v4(&other.0),
+        Err(LayoutError::Unrecognized(_))
+    ));
+    assert_eq!(fs::read(&other.0).unwrap(), b"not a database");
+}
+
+#[test]
+fn one_process_at_a_time() {
+    let path = TempPath::new("locked");
+    let first = layout::open_v4(&path.0).unwrap();
+
+    assert!(matches!(
+        layout::open_v4(&path.0),
+        Err(LayoutError::InUse(_))
+    ));
+    drop(first);
+    assert!(layout::open_v4(&path.0).is_ok());
+}
+
+#[test]
+fn tools_only_open_databases_that_are_there() {
+    let path = TempPath::new("existing");
+    assert!(matches!(
+        layout::open_v4_existing(&path.0, false),
+        Err(LayoutError::Io(_, err)) if err.kind() == std::io::ErrorKind::NotFound
+    ));
+    assert!(!path.0.exists());
+
+    drop(layout::open_v4(&path.0).unwrap());
+    let file = layout::open_v4_existing(&path.0, false).unwrap();
+    assert!(matches!(
+        layout::open_v4_existing(&path.0, true),
+        Err(LayoutError::InUse(_))
+    ));
+    drop(file);
+    assert!(layout::open_v4_existing(&path.0, true).is_ok());
+
+    // Upgrading is left to the server
+    let headerless = TempPath::new("existing-headerless");
+    fs::File::create(&headerless.0)
+        .unwrap()
+        .set_len(FILE_LEN)
+        .unwrap();
+    assert!(matches!(
+        layout::open_v4_existing(&headerless.0, true),
+        Err(LayoutError::Outdated(_, 0))
+    ));
+    let file = fs::File::open(&headerless.0).unwrap();
+    assert!(layout::read_header(&file).unwrap().is_none());
+}
+
+#[test]
+fn snapshots_round_trip_and_catch_damage() {
+    let path = TempPath::new("snapshot");
+    let snapshot = Snapshot {
+        created_at: 1_700_000_000,
+        countries: vec![
+            StoredCountryStats {
+                seed: 42,
+                active: 1,
+                ..Zeroable::zeroed()
+            };
+            3
+        ],
+        v4: vec![(
+            0x010203,
+            StoredIpStats {
+                first_seen: 1,
+                hits: 2,
+                ..Default::default()
+            },
+        )],
+        v6: vec![StoredIpv6Stats {
+            prefix: 0x2001_0db8_0000_0001,
+            ..Default::default()
+        }],
+    };
+    snapshot.write(&path.0).unwrap();
+
+    let read = Snapshot::read(&path.0).unwrap();
+    assert_eq!(read.created_at, snapshot.created_at);
+    assert_eq!(read.countries[2].seed, 42);
+    assert_eq!((read.v4[0].0, read.v4[0].1.hits), (0x010203, 2));
+    assert_eq!(read.v6[0].prefix, 0x2001_0db8_0000_0001);
+
+    let mut bytes = snapshot.encode();
+    bytes[40] ^= 1;
+    assert!(Snapshot::decode(&bytes).is_none());
+    bytes.truncate(bytes.len() / 2);
+    fs::write(&path.0, &bytes).unwrap();
+    assert!(matches!(
+        Snapshot::read(&path.0),
+        Err(SnapshotError::Malformed(_))
+    ));
+}
+
+// Prefixes whose probing starts at the same slot of a table with `len` slots
+fn colliding_prefixes(len: usize, count: usize) -> Vec<u64> {
+    let mut table = vec![StoredIpv6Stats::zeroed(); len];
+    let slots = Ipv6Slots(&mut table);
+    let home = slots.home(1);
+    (1..)
+        .filter(|&prefix| slots.home(prefix) == home)
+        .take(count)
+        .collect()
+}
+
+#[test]
+fn colliding_prefixes_probe_past_each_other() {
+    let prefixes = colliding_prefixes(256, 3);
+    let mut table = vec![StoredIpv6Stats::zeroed(); 256];
+    let mut slots = Ipv6Slots(&mut table);
+    let home = slots.home(prefixes[0]) as u32;
+
+    assert_eq!(slots.find(prefixes[0]), None);
+    let claimed: Vec<_> = prefixes
+        .iter()
+        .map(|&prefix| slots.claim(prefix).unwrap())
+        .collect();
+    assert_eq!(claimed, [home, (home + 1) % 256, (home + 2) % 256]);
+
+    // Claiming again finds the same slot
+    assert_eq!(slots.claim(prefixes[2]), Some(claimed[2]));
+    assert_eq!(slots.find(prefixes[1]), Some(claimed[1]));
+    assert_eq!(Ipv6Slots(&mut table).taken().count(), 3);
+}
+
+#[test]
+fn a_full_neighbourhood_takes_no_new_prefixes() {
+    let prefixes = colliding_prefixes(256, IPV6_MAX_PROBE + 1);
+    let mut table = vec![StoredIpv6Stats::zeroed(); 256];
+    let mut slots = Ipv6Slots(&mut table);
+
+    for &prefix in &prefixes[..IPV6_MAX_PROBE] {
+        assert!(slots.claim(prefix).is_some());
+    }
+    assert_eq!(slots.claim(prefixes[IPV6_MAX_PROBE]), None);
+    assert_eq!(slots.find(prefixes[IPV6_MAX_PROBE]), None);
+    // Everything that made it in is still found
+    assert!(
+        prefixes[..IPV6_MAX_PROBE]
+            .iter()
+            .all(|&prefix| slots.find(prefix).is_some())
+    );
+}
+
+#[test]
+fn removed_slots_are_reused() {
+    let prefixes = colliding_prefixes(256, 3);
+    let mut table = vec![StoredIpv6Stats::zeroed(); 256];
+    let mut slots = Ipv6Slots(&mut table);
+
+    let first = slots.claim(prefixes[0]).unwrap();
+    let second = slots.claim(prefixes[1]).unwrap();
+    slots.remove(first);
+
+    // The tombstone doesn't cut off the prefix behind it
+    assert_eq!(slots.find(prefixes[0]), None);
+    assert_eq!(slots.find(prefixes[1]), Some(second));
+    assert_eq!(Ipv6Slots(&mut table).record(first).map(|_| ()), None);
+
+    let mut slots = Ipv6Slots(&mut table);
+    assert_eq!(slots.claim(prefixes[2]), Some(first));
+    assert_eq!(slots.claim(prefixes[1]), Some(second));
+    assert_eq!(table[first as usize].prefix, prefixes[2]);
+    assert!(table.iter().all(|slot| slot.prefix != TOMBSTONE));
+}
@@ -0,0 +1,46 @@
+use melodybrain::{
+    meter::{Meter, TimeSignature},
+    notes::{NoteGenerator, Scale},
+};
+
+#[test]
+fn notes_fill_bars_without_crossing_them() {
+    for seed in 0..50 {
+        let mut generator = NoteGenerator::with_scale(0, seed * 7919, Scale::Major);
+        let bar = generator.meter().bar_length();
+        let mut position = 0;
+
+        for _ in 0..256 {
+            let note = generator.next().unwrap();
+            // Starts are in beats, right where the last note ended
+            assert_eq!(note.start, position as f32 / 4.);
+            assert!(note.duration > 0);
+            assert!(position % bar + note.duration as u32 <= bar);
+            position += note.duration as u32;
+        }
+    }
+}
+
+#[test]
+fn meter_comes_from_the_seed() {
+    let meters: Vec<_> = (0..500).map(|seed| Meter::for_seed(seed * 131)).collect();
+
+    for time_signature in [
+        TimeSignature::COMMON,
+        TimeSignature::WALTZ,
+        TimeSignature::SIX_EIGHT,
+    ] {
+        assert!(
+            meters
+                .iter()
+                .any(|meter| meter.time_signature == time_signature)
+        );
+    }
+    assert!(meters.iter().all(|meter| (60..=100).contains(&meter.bpm)));
+
+    // Every chunk of the same melody keeps time with the others
+    assert_eq!(
+        NoteGenerator::new(0, 1234).meter(),
+        NoteGenerator::new(500, 1234).meter()
+    );
+}
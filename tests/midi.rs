@@ -0,0 +1,281 @@
+use melodybrain::{
+    drums,
+    harmony::{Arranger, Voice},
+    meter::TimeSignature,
+    midi::{self, SmfFormat, TICKS_PER_BEAT},
+    notes::{Note, NoteGenerator, Scale},
+};
+
+#[derive(Debug, PartialEq)]
+struct ParsedNote {
+    pitch: u8,
+    velocity: u8,
+    start: u32,
+    ticks: u32,
+}
+
+#[derive(Debug, Default)]
+struct ParsedSmf {
+    format: u16,
+    ticks_per_beat: u16,
+    micros_per_beat: Option<u32>,
+    // Beats, unit as a power of two and the track it was in
+    time_signature: Option<(u8, u8, u16)>,
+    notes: Vec<ParsedNote>,
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        head
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.take(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.take(4).try_into().unwrap())
+    }
+
+    fn vlq(&mut self) -> u32 {
+        let mut value = 0;
+        loop {
+            let byte = self.u8();
+            value = value << 7 | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+}
+
+// Just enough of an SMF parser for what the writer emits: note on/off, tempo, time signature and end of track, no
+// running status
+fn parse(bytes: &[u8]) -> ParsedSmf {
+    let mut reader = Reader(bytes);
+    let mut smf = ParsedSmf::default();
+
+    assert_eq!(reader.take(4), b"MThd");
+    assert_eq!(reader.u32(), 6);
+    smf.format = reader.u16();
+    let tracks = reader.u16();
+    smf.ticks_per_beat = reader.u16();
+
+    for track_idx in 0..tracks {
+        assert_eq!(reader.take(4), b"MTrk");
+        let len = reader.u32() as usize;
+        let mut track = Reader(reader.take(len));
+
+        let mut now = 0;
+        let mut open: Vec<(u8, u8, u32)> = Vec::new();
+        loop {
+            now += track.vlq();
+            match track.u8() {
+                0xFF => {
+                    let kind = track.u8();
+                    let len = track.vlq() as usize;
+                    let data = track.take(len);
+                    match kind {
+                        0x51 => {
+                            smf.micros_per_beat =
+                                Some(u32::from_be_bytes([0, data[0], data[1], data[2]]))
+                        }
+                        0x58 => smf.time_signature = Some((data[0], data[1], track_idx)),
+                        0x2F => break,
+                        _ => {}
+                    }
+                }
+                status if status & 0xF0 == 0x90 => {
+                    let (pitch, velocity) = (track.u8(), track.u8());
+                    assert_ne!(velocity, 0, "note on with velocity 0");
+                    open.push((pitch, velocity, now));
+                }
+                status if status & 0xF0 == 0x80 => {
+                    let (pitch, _) = (track.u8(), track.u8());
+                    let idx = open.iter().position(|&(p, ..)| p == pitch).unwrap();
+                    let (pitch, velocity, start) = open.remove(idx);
+                    smf.notes.push(ParsedNote {
+                        pitch,
+                        velocity,
+                        start,
+                        ticks: now - start,
+                    });
+                }
+                status => panic!("unexpected status byte {status:#x}"),
+            }
+        }
+
+        assert!(open.is_empty(), "notes left hanging");
+        assert!(track.0.is_empty(), "data after end of track");
+    }
+
+    assert!(reader.0.is_empty(), "data after last track");
+    smf
+}
+
+fn expected(notes: &[Note]) -> Vec<ParsedNote> {
+    let mut start = 0;
+    notes
+        .iter()
+        .map(|note| {
+            let ticks = midi::duration_ticks(note.duration);
+            let parsed = ParsedNote {
+                pitch: note.pitch as u8,
+                velocity: midi::velocity_to_midi(note.velocity),
+                start,
+                ticks,
+            };
+            start += ticks;
+            parsed
+        })
+        .collect()
+}
+
+#[test]
+fn round_trips_generated_melody() {
+    let notes: Vec<_> = NoteGenerator::new(0, 12345).take(256).collect();
+
+    for (format, format_id) in [(SmfFormat::SingleTrack, 0), (SmfFormat::MultiTrack, 1)] {
+        let smf = parse(&midi::write_smf(
+            &notes,
+            TimeSignature::SIX_EIGHT,
+            120,
+            format,
+        ));
+
+        assert_eq!(smf.format, format_id);
+        assert_eq!(smf.ticks_per_beat, TICKS_PER_BEAT);
+        assert_eq!(smf.micros_per_beat, Some(500_000));
+        // 6/8, in the first track whatever the format
+        assert_eq!(smf.time_signature, Some((6, 3, 0)));
+        assert_eq!(smf.notes, expected(&notes));
+    }
+}
+
+#[test]
+fn velocity_covers_midi_range() {
+    assert_eq!(midi::velocity_to_midi(0.0), 1);
+    assert_eq!(midi::velocity_to_midi(0.5), 64);
+    assert_eq!(midi::velocity_to_midi(1.0), 127);
+    assert_eq!(midi::velocity_to_midi(2.0), 127);
+}
+
+#[test]
+fn long_notes_use_multibyte_delta_times() {
+    let notes = [Note {
+        start: 0.,
+        pitch: 60,
+        velocity: 0.5,
+        duration: 255,
+    }];
+    let smf = parse(&midi::write_smf(
+        &notes,
+        TimeSignature::COMMON,
+        midi::DEFAULT_BPM,
+        SmfFormat::SingleTrack,
+    ));
+
+    assert_eq!(smf.notes[0].ticks, 255 * TICKS_PER_BEAT as u32 / 4);
+}
+
+#[test]
+fn empty_melody_is_still_valid() {
+    let smf = parse(&midi::write_smf(
+        &[],
+        TimeSignature::WALTZ,
+        midi::DEFAULT_BPM,
+        SmfFormat::MultiTrack,
+    ));
+
+    assert!(smf.notes.is_empty());
+    assert_eq!(smf.time_signature, Some((3, 2, 0)));
+}
+
+#[test]
+fn reads_back_written_files() {
+    let notes: Vec<_> = NoteGenerator::new(0, 12345).take(256).collect();
+
+    for format in [SmfFormat::SingleTrack, SmfFormat::MultiTrack] {
+        let smf =
+            midi::read_smf(&midi::write_smf(&notes, TimeSignature::COMMON, 120, format)).unwrap();
+
+        assert_eq!(smf.ticks_per_beat, TICKS_PER_BEAT);
+        assert_eq!(smf.voices.len(), 1);
+        let read: Vec<_> = smf.voices[0]
+            .iter()
+            .map(|note| ParsedNote {
+                pitch: note.pitch,
+                velocity: note.velocity,
+                start: note.start,
+                ticks: note.ticks,
+            })
+            .collect();
+        assert_eq!(read, expected(&notes));
+    }
+}
+
+#[test]
+fn rejects_files_that_arent_midi() {
+    assert!(matches!(
+        midi::read_smf(b"RIFF\0\0\0\0WAVE"),
+        Err(midi::MidiError::NotMidi)
+    ));
+    assert!(matches!(
+        midi::read_smf(b"MThd\0\0\0\x06\0\x01"),
+        Err(midi::MidiError::Truncated)
+    ));
+}
+
+#[test]
+fn arrangement_puts_every_voice_on_its_own_channel() {
+    let arrangement =
+        Arranger::new(NoteGenerator::new(0, 12345), 0, 12345, Scale::Major).arrange(128);
+    // Reading leaves out the percussion channel
+    let mut expected: Vec<_> = arrangement
+        .tracks
+        .iter()
+        .filter(|track| track.voice != Voice::Drums)
+        .map(|track| track.notes.len())
+        .collect();
+    expected.insert(0, arrangement.melody.len());
+
+    for format in [SmfFormat::SingleTrack, SmfFormat::MultiTrack] {
+        let bytes = midi::write_arrangement(&arrangement, 120, format);
+        // Drums are note ons on channel 10
+        assert!(
+            bytes
+                .windows(2)
+                .any(|pair| pair == [0x99, drums::KICK as u8])
+        );
+        // So DAWs draw the bar lines where the chords change
+        let time_signature = arrangement.meter.time_signature;
+        assert!(
+            bytes
+                .windows(4)
+                .any(|meta| meta == [0xFF, 0x58, 0x04, time_signature.beats])
+        );
+
+        let smf = midi::read_smf(&bytes).unwrap();
+        let voices: Vec<_> = smf.voices.iter().map(|voice| voice.len()).collect();
+        assert_eq!(voices, expected);
+
+        for (note, read) in arrangement.melody.iter().zip(&smf.voices[0]) {
+            assert_eq!(read.start, (note.start * TICKS_PER_BEAT as f32) as u32);
+        }
+
+        let bass = &smf.voices[1];
+        assert_eq!(bass[0].start, 0);
+        assert_eq!(
+            bass[0].ticks,
+            midi::duration_ticks(arrangement.tracks[0].notes[0].duration)
+        );
+    }
+}
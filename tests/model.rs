@@ -0,0 +1,132 @@
+use melodybrain::{
+    meter::TimeSignature,
+    midi::{self, SmfFormat},
+    model::{MarkovModel, ModelError, Trainer},
+    notes::{Note, NoteGenerator, Scale},
+};
+
+// A phrase in C major with a rhythm of its own, where the same notes lead to different places depending on what came
+// before them
+const MOTIF: [(i8, u8); 8] = [
+    (60, 2),
+    (62, 1),
+    (64, 1),
+    (60, 2),
+    (67, 4),
+    (65, 2),
+    (64, 2),
+    (62, 4),
+];
+
+fn train(order: usize, joint: bool) -> MarkovModel {
+    let notes: Vec<_> = MOTIF
+        .iter()
+        .cycle()
+        .take(MOTIF.len() * 100)
+        .map(|&(pitch, duration)| Note {
+            start: 0.,
+            pitch,
+            velocity: 0.5,
+            duration,
+        })
+        .collect();
+    let smf = midi::read_smf(&midi::write_smf(
+        &notes,
+        TimeSignature::COMMON,
+        120,
+        SmfFormat::SingleTrack,
+    ))
+    .unwrap();
+
+    let mut trainer = Trainer::new(Scale::Major, order, joint);
+    trainer.add(&smf);
+    assert_eq!(trainer.notes(), notes.len());
+    trainer.finish()
+}
+
+// Notes get cut short where they'd cross into the next bar
+fn ends_on_barline(note: &Note, bar: u32) -> bool {
+    ((note.start * 4.) as u32 + note.duration as u32).is_multiple_of(bar)
+}
+
+fn repeats_motif(notes: &[Note], bar: u32) -> bool {
+    // The first few notes come from wherever the generator starts, give it time to find the phrase
+    let settled = &notes[MOTIF.len()..];
+    settled.iter().zip(&settled[MOTIF.len()..]).all(|(a, b)| {
+        a.pitch == b.pitch
+            && (a.duration == b.duration || ends_on_barline(a, bar) || ends_on_barline(b, bar))
+    })
+}
+
+#[test]
+fn learns_transitions_from_midi() {
+    let model = train(1, false);
+    let pitch = model.pitch.first_order();
+
+    // The tonic goes to the 2nd and the 5th equally often and nowhere else
+    assert_eq!(pitch[0], [0., 0.5, 0., 0., 0.5, 0., 0.]);
+    assert_eq!(pitch[4], [0., 0., 0., 1., 0., 0., 0.]);
+    assert!(model.joint.is_none());
+}
+
+#[test]
+fn higher_orders_remember_the_phrase() {
+    let model = train(3, true);
+
+    for order in [2, 3] {
+        let generator = NoteGenerator::with_model(0, 42, Scale::Major, &model, order);
+        let bar = generator.meter().bar_length();
+        let notes: Vec<_> = generator.take(64).collect();
+        assert!(repeats_motif(&notes, bar), "order {order} lost the phrase");
+    }
+}
+
+// Whether every note is one from the motif, with the duration it had there or cut off by a barline, in some key
+fn only_motif_pairs(notes: &[Note], bar: u32) -> bool {
+    (-24..=24).any(|shift| {
+        notes.iter().all(|note| {
+            MOTIF.iter().any(|&(pitch, duration)| {
+                note.pitch - shift == pitch
+                    && (note.duration == duration
+                        || note.duration < duration && ends_on_barline(note, bar))
+            })
+        })
+    })
+}
+
+#[test]
+fn joint_model_keeps_rhythm_with_pitch() {
+    let generate = |joint| {
+        let model = train(1, joint);
+        let generator = NoteGenerator::with_model(0, 42, Scale::Major, &model, 1);
+        let bar = generator.meter().bar_length();
+        only_motif_pairs(&generator.take(256).collect::<Vec<_>>(), bar)
+    };
+
+    assert!(generate(true));
+    // Picked separately, the 5th ends up with rhythms it never had
+    assert!(!generate(false));
+}
+
+#[test]
+fn model_works_with_scales_of_other_sizes() {
+    let model = train(2, true);
+
+    let notes: Vec<_> = NoteGenerator::with_model(0, 42, Scale::Pentatonic, &model, 2)
+        .take(64)
+        .collect();
+    assert_eq!(notes.len(), 64);
+}
+
+#[test]
+fn rejects_mismatched_tables() {
+    let mut model = train(1, false);
+    model.duration.states = 3;
+
+    let path = std::env::temp_dir().join(format!("melodybrain-model-{}.json", std::process::id()));
+    model.save(&path).unwrap();
+    let loaded = MarkovModel::load(&path);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(loaded, Err(ModelError::Invalid("duration"))));
+}
@@ -0,0 +1,44 @@
+use melodybrain::notes::{NoteGenerator, Scale};
+
+#[test]
+fn scales_span_one_octave() {
+    for scale in Scale::ALL {
+        let intervals = scale.intervals();
+
+        assert_eq!(intervals[0], 0, "{scale:?} starts on the tonic");
+        assert!(
+            intervals.windows(2).all(|pair| pair[0] < pair[1]),
+            "{scale:?} goes up"
+        );
+        assert!(intervals.iter().all(|&interval| interval < 12), "{scale:?}");
+
+        let pitches = scale.pitches();
+        assert_eq!(pitches.len(), intervals.len());
+        assert!(pitches.iter().all(|&pitch| pitch - pitches[0] < 12));
+
+        let transitions = scale.transitions();
+        assert_eq!(transitions.len(), intervals.len());
+        assert!(transitions.iter().all(|row| row.len() == intervals.len()));
+    }
+}
+
+#[test]
+fn melodies_stay_in_their_scale() {
+    for scale in Scale::ALL {
+        let tonic = scale.pitches()[0];
+
+        for seed in [1, 42, -7919, i32::MAX] {
+            let mut generator = NoteGenerator::with_scale(0, seed, scale);
+            let key = generator.key_offset();
+
+            for note in generator.by_ref().take(200) {
+                let interval = (note.pitch - tonic - key).rem_euclid(12);
+                assert!(
+                    scale.intervals().contains(&interval),
+                    "{scale:?} seed {seed} played {} in key {key}",
+                    note.pitch
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use melodybrain::{
+    COUNTRIES, Cookie, DecodeError, Heartbeat, MAGIC, Message, PROTOCOL_VERSION, ProtocolError,
+    Stats, StatsRequest,
+};
+
+const COOKIE: Cookie = Cookie([1, 2, 3, 4, 5, 6, 7, 8]);
+
+fn round_trip(msg: &Message) -> Message {
+    let mut buf = [0; 1200];
+    let bytes = msg.encode(&mut buf).unwrap();
+    assert_eq!(bytes[..2], [MAGIC, PROTOCOL_VERSION]);
+
+    Message::decode(bytes).unwrap()
+}
+
+fn encoded(msg: &Message) -> Vec<u8> {
+    let mut buf = [0; 1200];
+    msg.encode(&mut buf).unwrap().to_vec()
+}
+
+fn heartbeat() -> Message {
+    Message::Heartbeat(Heartbeat {
+        seed: -12345,
+        cookie: COOKIE,
+    })
+}
+
+#[test]
+fn every_message_survives_a_round_trip() {
+    let Message::Heartbeat(heartbeat) = round_trip(&heartbeat()) else {
+        panic!("not a heartbeat");
+    };
+    assert_eq!((heartbeat.seed, heartbeat.cookie), (-12345, COOKIE));
+
+    let request = Message::StatsRequest(StatsRequest {
+        id: 77,
+        heartbeat: Heartbeat {
+            seed: i32::MAX,
+            cookie: COOKIE,
+        },
+        country: 12,
+    });
+    let Message::StatsRequest(request) = round_trip(&request) else {
+        panic!("not a stats request");
+    };
+    assert_eq!(
+        (
+            request.id,
+            request.heartbeat.seed,
+            request.heartbeat.cookie,
+            request.country
+        ),
+        (77, i32::MAX, COOKIE, 12)
+    );
+
+    let heatmap = std::array::from_fn(|idx| idx as f32 / COUNTRIES.len() as f32);
+    let stats = Message::Stats {
+        id: 78,
+        stats: Box::new(Stats {
+            connected: 42,
+            seed: i32::MIN,
+            country_heatmap: heatmap,
+        }),
+    };
+    let Message::Stats { id, stats } = round_trip(&stats) else {
+        panic!("not stats");
+    };
+    assert_eq!((id, stats.connected, stats.seed), (78, 42, i32::MIN));
+    assert_eq!(stats.country_heatmap, heatmap);
+
+    for error in [
+        ProtocolError::UnsupportedVersion { supported: 9 },
+        ProtocolError::Malformed,
+        ProtocolError::UnknownCountry,
+        ProtocolError::RateLimited,
+    ] {
+        let msg = Message::Error { id: 79, error };
+        let Message::Error { id, error: decoded } = round_trip(&msg) else {
+            panic!("not an error");
+        };
+        assert_eq!(id, 79);
+        assert_eq!(decoded.to_string(), error.to_string());
+    }
+
+    let cookie = Message::Cookie {
+        id: 80,
+        cookie: COOKIE,
+    };
+    let Message::Cookie { id, cookie } = round_trip(&cookie) else {
+        panic!("not a cookie");
+    };
+    assert_eq!((id, cookie), (80, COOKIE));
+}
+
+#[test]
+fn foreign_packets_are_told_apart() {
+    let mut bytes = encoded(&heartbeat());
+    bytes[0] = !MAGIC;
+    assert!(matches!(
+        Message::decode(&bytes),
+        Err(DecodeError::BadMagic)
+    ));
+    assert!(matches!(
+        Message::decode(&[MAGIC]),
+        Err(DecodeError::BadMagic)
+    ));
+    assert!(matches!(Message::decode(&[]), Err(DecodeError::BadMagic)));
+
+    let mut bytes = encoded(&heartbeat());
+    bytes[1] = PROTOCOL_VERSION + 1;
+    assert!(matches!(
+        Message::decode(&bytes),
+        Err(DecodeError::UnsupportedVersion(version)) if version == PROTOCOL_VERSION + 1
+    ));
+}
+
+#[test]
+fn truncated_packets_are_malformed() {
+    let bytes = encoded(&heartbeat());
+
+    for len in 2..bytes.len() {
+        assert!(
+            matches!(Message::decode(&bytes[..len]), Err(DecodeError::Malformed)),
+            "{len} bytes"
+        );
+    }
+    // Not a message type at all
+    assert!(matches!(
+        Message::decode(&[MAGIC, PROTOCOL_VERSION, 0xFF]),
+        Err(DecodeError::Malformed)
+    ));
+}
+
+#[test]
+fn cookies_are_never_bigger_than_the_heartbeat_they_answer() {
+    for seed in [0, -1, 1, i32::MIN, i32::MAX] {
+        let heartbeat = encoded(&Message::Heartbeat(Heartbeat {
+            seed,
+            cookie: COOKIE,
+        }));
+        let cookie = encoded(&Message::Cookie {
+            id: 0,
+            cookie: COOKIE,
+        });
+        assert!(cookie.len() <= heartbeat.len(), "seed {seed}");
+    }
+}
@@ -0,0 +1,132 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use melodybrain::aggregate::Aggregation;
+
+// The binary's own module, it only needs the library
+#[allow(dead_code)]
+#[path = "../src/server/config.rs"]
+mod config;
+
+use config::{Config, ConfigError, StoreKind};
+
+// A config file in the temp directory, removed again when it's dropped
+struct TempConfig(PathBuf);
+
+impl TempConfig {
+    fn new(name: &str, contents: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "melodybrain-server-{}-{name}.toml",
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        Self(path)
+    }
+}
+
+impl Drop for TempConfig {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn parse(flags: &[&str]) -> Result<Config, ConfigError> {
+    Config::parse_from(["melodybrain-server"].iter().chain(flags))
+}
+
+#[test]
+fn defaults_are_what_the_server_always_used() {
+    let config = parse(&[]).unwrap();
+
+    assert_eq!(config.addr, "[::]:2026".parse().unwrap());
+    assert_eq!(config.geoip_db, PathBuf::from("./GeoLite2-Country.mmdb"));
+    assert_eq!(config.ipv4_db, PathBuf::from("./ipv4.bin"));
+    assert_eq!(config.update.liveness_window, 10);
+    assert_eq!(
+        config.update.aggregation,
+        Aggregation::Ema { divisor: 2000 }
+    );
+    assert!(matches!(config.store, StoreKind::Mmap));
+    assert!(config.geoip_csv.is_none() && config.geo_overrides.is_empty());
+    assert!(config.command.is_none());
+}
+
+#[test]
+fn flags_win_over_the_file() {
+    let file = TempConfig::new(
+        "precedence",
+        "port = 3000\n\
+         liveness_window_secs = 30\n\
+         aggregation = \"trimmed-mean\"\n\
+         trim_percent = 10\n\
+         merge_interval_ms = 50\n",
+    );
+    let path = file.0.to_str().unwrap();
+
+    let config = parse(&["--config", path]).unwrap();
+    assert_eq!(config.addr.port(), 3000);
+    assert_eq!(config.update.liveness_window, 30);
+    assert_eq!(
+        config.update.aggregation,
+        Aggregation::TrimmedMean { trim_percent: 10 }
+    );
+    assert_eq!(config.merge_interval, Duration::from_millis(50));
+
+    let config = parse(&["--config", path, "--port", "4000", "--trim-percent", "25"]).unwrap();
+    assert_eq!(config.addr.port(), 4000);
+    assert_eq!(config.update.liveness_window, 30);
+    assert_eq!(
+        config.update.aggregation,
+        Aggregation::TrimmedMean { trim_percent: 25 }
+    );
+}
+
+#[test]
+fn bad_values_are_turned_down() {
+    for (flag, option) in [
+        ("--liveness-window-secs", "liveness_window_secs"),
+        ("--cleanup-period-secs", "cleanup_period_secs"),
+        ("--averaging-divisor", "averaging_divisor"),
+        ("--aggregation-window", "aggregation_window"),
+        ("--workers", "workers"),
+        ("--merge-interval-ms", "merge_interval_ms"),
+        ("--rate-limit-burst", "rate_limit_burst"),
+        ("--rate-limit-per-sec", "rate_limit_per_sec"),
+        ("--checkpoint-period-secs", "checkpoint_period_secs"),
+    ] {
+        let err = parse(&[flag, "0"]).unwrap_err();
+        assert!(matches!(err, ConfigError::NotPositive(bad) if bad == option));
+        assert_eq!(
+            err.to_string(),
+            format!("{option} must be greater than zero")
+        );
+    }
+    assert!(matches!(
+        parse(&["--averaging-divisor=-5"]),
+        Err(ConfigError::NotPositive("averaging_divisor"))
+    ));
+
+    for percent in ["50", "99"] {
+        let err = parse(&["--trim-percent", percent]).unwrap_err();
+        assert!(matches!(err, ConfigError::TrimTooHigh(_)));
+        assert_eq!(
+            err.to_string(),
+            format!("trim_percent must be below 50, got {percent}")
+        );
+    }
+    assert!(parse(&["--trim-percent", "49"]).is_ok());
+
+    // Overrides from the file are checked too, the flags already are by clap
+    let file = TempConfig::new(
+        "bad-override",
+        "[geo_overrides]\n\"10.0.0.0/33\" = \"DE\"\n",
+    );
+    assert!(matches!(
+        parse(&["--config", file.0.to_str().unwrap()]),
+        Err(ConfigError::Override(_))
+    ));
+    let file = TempConfig::new("unknown-key", "prot = 2026\n");
+    assert!(matches!(
+        parse(&["--config", file.0.to_str().unwrap()]),
+        Err(ConfigError::Parse(..))
+    ));
+}
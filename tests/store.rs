@@ -0,0 +1,473 @@
+use std::{
+    fs,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use melodybrain::{
+    WORLDWIDE,
+    aggregate::Aggregation,
+    heartbeat::{RateLimit, Source, Verdict, register_heartbeat},
+    store::{
+        CountryStorage, Discrepancy, MemoryStore, Records, ShardedStore, StatsStore, contributor_id,
+    },
+};
+
+// The server's own sparse file backend, it only needs the library
+#[allow(dead_code)]
+#[path = "../src/server/dbs.rs"]
+mod dbs;
+
+const LIVENESS_WINDOW: u64 = 10;
+const LIMIT: RateLimit = RateLimit {
+    burst: 100,
+    per_sec: 10,
+};
+const AGGREGATION: Aggregation = Aggregation::Ema { divisor: 1 };
+const COUNTRY: u8 = 5;
+
+fn verified(ip: &str) -> Source {
+    Source {
+        ip: ip.parse().unwrap(),
+        verified: true,
+    }
+}
+
+fn heartbeat(store: &impl StatsStore, ip: &str, now: u64) -> Verdict {
+    register_heartbeat(
+        store,
+        verified(ip),
+        1234,
+        now,
+        LIVENESS_WINDOW,
+        LIMIT,
+        |_| Some(COUNTRY),
+    )
+}
+
+// A way to get an empty store, and what sets the backend apart from the others
+struct Backend<R: Records, C> {
+    new: fn() -> ShardedStore<R, C>,
+    // Whether IPv4 records stay after their clients went quiet, see `Records::remove`
+    keeps_ipv4: bool,
+}
+
+const MEMORY: Backend<melodybrain::store::MemoryRecords, Vec<melodybrain::StoredCountryStats>> =
+    Backend {
+        new: || MemoryStore::empty(1),
+        keeps_ipv4: false,
+    };
+
+const MMAP: Backend<dbs::ShardRecords, dbs::CountryTable> = Backend {
+    new: mmap_store,
+    keeps_ipv4: true,
+};
+
+// Fresh sparse files in the temp directory. They're unlinked right away, the mappings keep them around until the
+// store is dropped.
+fn mmap_store() -> dbs::GeneralIpDb {
+    static OPENED: AtomicUsize = AtomicUsize::new(0);
+    let idx = OPENED.fetch_add(1, Ordering::Relaxed);
+    let path = |name| {
+        std::env::temp_dir().join(format!(
+            "melodybrain-store-{}-{idx}-{name}",
+            std::process::id()
+        ))
+    };
+    let (v4, v6) = (path("ipv4.bin"), path("ipv6.bin"));
+
+    let db = dbs::open(&v4, &v6, 1).unwrap();
+    fs::remove_file(v4).unwrap();
+    fs::remove_file(v6).unwrap();
+    db
+}
+
+// Every test listed runs once on each backend, with the same expectations
+macro_rules! on_both_backends {
+    ($($test:ident),* $(,)?) => {
+        mod memory {
+            $(#[test]
+            fn $test() {
+                super::$test(super::MEMORY);
+            })*
+        }
+
+        mod mmap {
+            $(#[test]
+            fn $test() {
+                super::$test(super::MMAP);
+            })*
+        }
+    };
+}
+
+on_both_backends!(
+    clients_are_counted_per_network,
+    quiet_clients_expire,
+    forgotten_clients_stay_in_the_totals,
+    only_verified_heartbeats_add_clients,
+    snapshots_restore_into_a_fresh_store,
+    recovery_recounts_drifted_countries,
+    fsck_lists_drifted_countries_and_repairs_them,
+    crashes_between_checkpoint_flushes_are_recovered,
+);
+
+fn clients_are_counted_per_network<R: Records, C: CountryStorage>(backend: Backend<R, C>) {
+    let store = (backend.new)();
+
+    for ip in [
+        "1.2.3.4",
+        "1.2.3.5",
+        "5.6.7.8",
+        "2001:db8::1",
+        "2001:db8::2",
+    ] {
+        assert_eq!(heartbeat(&store, ip, 1000), Verdict::Accepted);
+    }
+
+    let snapshot = store.merge(AGGREGATION);
+    assert_eq!(snapshot.stats(COUNTRY).connected, 3);
+    assert_eq!(snapshot.stats(WORLDWIDE).connected, 3);
+    assert_eq!(
+        snapshot.stats(COUNTRY).country_heatmap[COUNTRY as usize],
+        1.0
+    );
+}
+
+fn quiet_clients_expire<R: Records, C: CountryStorage>(backend: Backend<R, C>) {
+    let store = (backend.new)();
+    heartbeat(&store, "1.2.3.4", 1000);
+    heartbeat(&store, "5.6.7.8", 1000);
+
+    // Just in time to stay active
+    heartbeat(&store, "5.6.7.8", 1000 + LIVENESS_WINDOW + 1);
+    store.cleanup(1000 + LIVENESS_WINDOW + 2, LIVENESS_WINDOW);
+    assert_eq!(store.merge(AGGREGATION).stats(COUNTRY).connected, 1);
+
+    store.cleanup(1000 + 3 * LIVENESS_WINDOW, LIVENESS_WINDOW);
+    assert_eq!(store.merge(AGGREGATION).stats(COUNTRY).connected, 0);
+    // They stay in the totals, their records only where the backend keeps them
+    let snapshot = store.snapshot(1000 + 3 * LIVENESS_WINDOW);
+    let kept = if backend.keeps_ipv4 { 2 } else { 0 };
+    assert_eq!((snapshot.v4.len(), snapshot.v6.len()), (kept, 0));
+    assert_eq!(snapshot.countries[COUNTRY as usize].unique, 2);
+
+    // Coming back without a record starts over as a new client, and the counts still add up either way
+    heartbeat(&store, "1.2.3.4", 2000);
+    let snapshot = store.merge(AGGREGATION);
+    assert_eq!(snapshot.stats(COUNTRY).connected, 1);
+    let unique = if backend.keeps_ipv4 { 2 } else { 3 };
+    assert_eq!(
+        store.snapshot(2000).countries[COUNTRY as usize].unique,
+        unique
+    );
+    assert!(store.fsck(false).unwrap().is_empty());
+}
+
+fn forgotten_clients_stay_in_the_totals<R: Records, C: CountryStorage>(backend: Backend<R, C>) {
+    let store = (backend.new)();
+    heartbeat(&store, "2001:db8::1", 1000);
+    heartbeat(&store, "2001:db8::1", 1000 + LIVENESS_WINDOW + 1);
+    store.merge(AGGREGATION);
+    let before = store.snapshot(1000).countries[COUNTRY as usize];
+    assert_eq!(
+        (before.unique, before.cum_duration),
+        (1, LIVENESS_WINDOW as u32 + 1)
+    );
+
+    // Its slot is given back, its history isn't
+    let now = 1000 + 5 * LIVENESS_WINDOW;
+    store.cleanup(now, LIVENESS_WINDOW);
+    store.merge(AGGREGATION);
+    let snapshot = store.snapshot(now);
+    assert!(snapshot.v6.is_empty());
+
+    let lifetime = (now - 1000) as u32;
+    for country in [COUNTRY, WORLDWIDE] {
+        let stats = snapshot.countries[country as usize];
+        assert_eq!(
+            (stats.active, stats.unique, stats.cum_duration),
+            (0, 1, lifetime)
+        );
+        assert_eq!((stats.forgotten, stats.forgotten_duration), (1, lifetime));
+    }
+    assert!(store.fsck(false).unwrap().is_empty());
+    assert_eq!(store.recover(now, LIVENESS_WINDOW), 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_heartbeats_add_up() {
+    const TASKS: usize = 8;
+    const CLIENTS: usize = 500;
+    let store = Arc::new(MemoryStore::empty(1));
+
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|task| {
+            let store = Arc::clone(&store);
+            tokio::spawn(async move {
+                for client in 0..CLIENTS {
+                    // A /24 and a /64 of its own, spread over every shard
+                    let idx = task * CLIENTS + client;
+                    let v4 = format!("{}.{}.{}.1", 1 + idx / 65536, idx / 256 % 256, idx % 256);
+                    let v6 = format!("2001:db8:{:x}:{:x}::1", idx / 65536, idx % 65536);
+                    assert_eq!(heartbeat(&*store, &v4, 1000), Verdict::Accepted);
+                    assert_eq!(heartbeat(&*store, &v6, 1000), Verdict::Accepted);
+                    // And a network every task shares
+                    heartbeat(&*store, "9.9.9.9", 1000);
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+    // Merges going on at the same time mustn't lose anything either
+    let merger = {
+        let store = Arc::clone(&store);
+        tokio::spawn(async move {
+            for _ in 0..100 {
+                store.merge(AGGREGATION);
+                tokio::task::yield_now().await;
+            }
+        })
+    };
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+    merger.await.unwrap();
+
+    let snapshot = store.merge(AGGREGATION);
+    let clients = (2 * TASKS * CLIENTS) as u32 + 1;
+    assert_eq!(snapshot.stats(COUNTRY).connected, clients);
+    assert_eq!(snapshot.stats(WORLDWIDE).connected, clients);
+    assert_eq!(
+        store.snapshot(1000).countries[COUNTRY as usize].unique,
+        clients
+    );
+    assert!(store.fsck(false).unwrap().is_empty());
+}
+
+#[test]
+fn networks_are_rate_limited() {
+    let store = MemoryStore::empty(1);
+    let limit = RateLimit {
+        burst: 2,
+        per_sec: 1,
+    };
+    let heartbeat = |now| {
+        let source = verified("1.2.3.4");
+        register_heartbeat(&store, source, 0, now, LIVENESS_WINDOW, limit, |_| None)
+    };
+
+    assert_eq!(heartbeat(1000), Verdict::Accepted);
+    assert_eq!(heartbeat(1000), Verdict::Accepted);
+    assert_eq!(heartbeat(1000), Verdict::RateLimited);
+    assert_eq!(heartbeat(1001), Verdict::Accepted);
+}
+
+#[test]
+fn reserved_addresses_are_untracked() {
+    let store = MemoryStore::empty(1);
+
+    for ip in ["0.1.2.3", "224.0.0.1", "255.255.255.255", "::1", "ff02::1"] {
+        assert_eq!(heartbeat(&store, ip, 1000), Verdict::Untracked);
+    }
+    assert!(store.snapshot(1000).v4.is_empty());
+}
+
+fn only_verified_heartbeats_add_clients<R: Records, C: CountryStorage>(backend: Backend<R, C>) {
+    let store = (backend.new)();
+    let unverified = |ip: &str, now| {
+        let source = Source {
+            ip: ip.parse().unwrap(),
+            verified: false,
+        };
+        register_heartbeat(&store, source, 0, now, LIVENESS_WINDOW, LIMIT, |_| {
+            Some(COUNTRY)
+        })
+    };
+
+    assert_eq!(unverified("1.2.3.4", 1000), Verdict::Unverified);
+    assert_eq!(unverified("2001:db8::1", 1000), Verdict::Unverified);
+    assert_eq!(unverified("::1", 1000), Verdict::Untracked);
+    assert_eq!(store.merge(AGGREGATION).stats(COUNTRY).connected, 0);
+
+    // Even once a network is known, heartbeats without a cookie don't count
+    assert_eq!(heartbeat(&store, "1.2.3.4", 1000), Verdict::Accepted);
+    assert_eq!(
+        heartbeat(&store, "1.2.3.4", 1000 + LIVENESS_WINDOW + 1),
+        Verdict::Accepted
+    );
+    let before = store.merge(AGGREGATION).stats(COUNTRY);
+    assert_eq!((before.connected, before.seed), (1, 1234));
+    let (_, stored) = store.snapshot(1000).v4[0];
+
+    assert_eq!(
+        unverified("1.2.3.4", 1000 + 2 * LIVENESS_WINDOW + 2),
+        Verdict::Unverified
+    );
+    let after = store.merge(AGGREGATION).stats(COUNTRY);
+    assert_eq!((after.connected, after.seed), (1, 1234));
+    let (_, record) = store.snapshot(1000).v4[0];
+    assert_eq!(
+        (record.last_seen, record.cum_duration, record.hits),
+        (stored.last_seen, stored.cum_duration, stored.hits)
+    );
+}
+
+#[test]
+fn ipv6_sites_contribute_once() {
+    let id = |ip: &str| contributor_id(ip.parse().unwrap());
+
+    // Every /64 of a /48 is the same contributor
+    assert_eq!(id("2001:db8:1:2::1"), id("2001:db8:1:ffff::2"));
+    assert_ne!(id("2001:db8:1::1"), id("2001:db8:2::1"));
+    assert_eq!(id("1.2.3.4"), id("1.2.3.200"));
+    assert_ne!(id("1.2.3.4"), id("1.2.4.4"));
+    // Never mistaken for one another
+    assert_ne!(id("0:1:203::"), id("1.2.3.4"));
+    assert_ne!(id("ffff:ffff:ffff::"), id("255.255.255.255"));
+}
+
+#[test]
+fn the_country_is_only_looked_up_once() {
+    let store = MemoryStore::empty(1);
+    let mut lookups = 0;
+
+    for now in [1000, 1001, 1100] {
+        register_heartbeat(
+            &store,
+            verified("1.2.3.4"),
+            0,
+            now,
+            LIVENESS_WINDOW,
+            LIMIT,
+            |_| {
+                lookups += 1;
+                Some(COUNTRY)
+            },
+        );
+    }
+    assert_eq!(lookups, 1);
+}
+
+fn snapshots_restore_into_a_fresh_store<R: Records, C: CountryStorage>(backend: Backend<R, C>) {
+    let store = (backend.new)();
+    heartbeat(&store, "1.2.3.4", 1000);
+    heartbeat(&store, "2001:db8::1", 1000);
+    store.merge(AGGREGATION);
+
+    let snapshot = store.snapshot(1000);
+    assert_eq!((snapshot.v4.len(), snapshot.v6.len()), (1, 1));
+
+    let restored = (backend.new)();
+    assert!(restored.restore(&snapshot));
+    assert_eq!(restored.recover(1005, LIVENESS_WINDOW), 0);
+    assert_eq!(restored.merge(AGGREGATION).stats(COUNTRY).connected, 2);
+    assert!(restored.fsck(false).unwrap().is_empty());
+
+    // Still tracked for expiry after the restore
+    restored.cleanup(1000 + 2 * LIVENESS_WINDOW, LIVENESS_WINDOW);
+    assert_eq!(restored.merge(AGGREGATION).stats(COUNTRY).connected, 0);
+}
+
+fn recovery_recounts_drifted_countries<R: Records, C: CountryStorage>(backend: Backend<R, C>) {
+    let store = (backend.new)();
+    heartbeat(&store, "1.2.3.4", 1000);
+    store.merge(AGGREGATION);
+
+    let mut snapshot = store.snapshot(1000);
+    snapshot.countries[COUNTRY as usize].active = 40;
+    let restored = (backend.new)();
+    restored.restore(&snapshot);
+
+    assert_eq!(restored.fsck(false).unwrap().len(), 1);
+    assert_eq!(restored.recover(1005, LIVENESS_WINDOW), 1);
+    assert_eq!(restored.merge(AGGREGATION).stats(COUNTRY).connected, 1);
+}
+
+fn fsck_lists_drifted_countries_and_repairs_them<R: Records, C: CountryStorage>(
+    backend: Backend<R, C>,
+) {
+    let store = (backend.new)();
+    heartbeat(&store, "1.2.3.4", 1000);
+    heartbeat(&store, "1.2.3.4", 1011);
+    heartbeat(&store, "2001:db8::1", 1011);
+    store.merge(AGGREGATION);
+    assert!(store.fsck(false).unwrap().is_empty());
+
+    let mut snapshot = store.snapshot(1011);
+    let stats = &mut snapshot.countries[COUNTRY as usize];
+    (stats.active, stats.unique, stats.cum_duration) = (7, 1, 500);
+    let restored = (backend.new)();
+    restored.restore(&snapshot);
+
+    let discrepancies = restored.fsck(false).unwrap();
+    assert_eq!(discrepancies.len(), 1);
+    let Discrepancy {
+        country,
+        stored,
+        counted,
+    } = discrepancies[0];
+    assert_eq!(country, COUNTRY);
+    assert_eq!(
+        (stored.active, stored.unique, stored.cum_duration),
+        (7, 1, 500)
+    );
+    assert_eq!(
+        (counted.active, counted.unique, counted.cum_duration),
+        (2, 2, 11)
+    );
+    // Only looking changes nothing
+    assert_eq!(restored.fsck(false).unwrap().len(), 1);
+
+    assert_eq!(restored.fsck(true).unwrap().len(), 1);
+    assert!(restored.fsck(false).unwrap().is_empty());
+    let stats = restored.snapshot(1011).countries[COUNTRY as usize];
+    assert_eq!((stats.active, stats.unique, stats.cum_duration), (2, 2, 11));
+    assert_eq!(restored.merge(AGGREGATION).stats(COUNTRY).connected, 2);
+}
+
+fn crashes_between_checkpoint_flushes_are_recovered<R: Records, C: CountryStorage>(
+    backend: Backend<R, C>,
+) {
+    let store = (backend.new)();
+    heartbeat(&store, "1.2.3.4", 1000);
+    // Around for a while already, so it has connected time of its own
+    heartbeat(&store, "1.2.3.4", 1011);
+    store.merge(AGGREGATION);
+    let before = store.snapshot(1011);
+
+    for ip in ["1.2.3.4", "5.6.7.8", "2001:db8::1"] {
+        heartbeat(&store, ip, 1022);
+    }
+    store.merge(AGGREGATION);
+    let after = store.snapshot(1022);
+
+    // Records written out without the country table that goes with them, and the other way around
+    let mut records_only = after.clone();
+    records_only.countries = before.countries.clone();
+    let mut table_only = before.clone();
+    table_only.countries = after.countries.clone();
+
+    // The records from before the crash say 1.2.3.4 went quiet at 1011, so it expires on the way
+    for (crashed, connected, unique, cum_duration) in
+        [(records_only, 3, 3, 22), (table_only, 0, 1, 25)]
+    {
+        let restored = (backend.new)();
+        assert!(restored.restore(&crashed));
+        assert!(!restored.fsck(false).unwrap().is_empty());
+
+        assert_eq!(restored.recover(1025, LIVENESS_WINDOW), 2);
+        assert!(restored.fsck(false).unwrap().is_empty());
+        let snapshot = restored.merge(AGGREGATION);
+        assert_eq!(snapshot.stats(COUNTRY).connected, connected);
+        assert_eq!(snapshot.stats(WORLDWIDE).connected, connected);
+        for country in [COUNTRY, WORLDWIDE] {
+            let stats = restored.snapshot(1025).countries[country as usize];
+            assert_eq!((stats.unique, stats.cum_duration), (unique, cum_duration));
+        }
+        assert!(restored.fsck(false).unwrap().is_empty());
+    }
+}
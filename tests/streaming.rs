@@ -0,0 +1,362 @@
+use melodybrain::{
+    harmony::{Arrangement, Arranger},
+    meter::TimeSignature,
+    midi::{self, SmfFormat},
+    model::{MarkovModel, Trainer},
+    notes::{Note, NoteGenerator, Scale, key_for_seed},
+    stream::{StateError, StreamState},
+};
+
+// Trained on the built in generator, so it's a model with longer histories and joint steps to carry over
+fn model() -> MarkovModel {
+    let notes: Vec<_> = NoteGenerator::new(0, 777).take(2000).collect();
+    let smf = midi::read_smf(&midi::write_smf(
+        &notes,
+        TimeSignature::COMMON,
+        120,
+        SmfFormat::SingleTrack,
+    ))
+    .unwrap();
+
+    let mut trainer = Trainer::new(Scale::Major, 3, true);
+    trainer.add(&smf);
+    trainer.finish()
+}
+
+fn arranger(seed: i32, scale: Scale, model: Option<&MarkovModel>) -> Arranger {
+    let generator = match model {
+        Some(model) => NoteGenerator::with_model(0, seed, scale, model, 3),
+        None => NoteGenerator::with_scale(0, seed, scale),
+    };
+    Arranger::new(generator, 0, seed, scale)
+}
+
+// Several chunks one after the other, as a single arrangement
+fn join(chunks: &[Arrangement]) -> Arrangement {
+    let mut joined = chunks[0].clone();
+    for chunk in &chunks[1..] {
+        joined.melody.extend(chunk.melody.iter().cloned());
+        joined.chords.extend(chunk.chords.iter().cloned());
+        for (track, more) in joined.tracks.iter_mut().zip(&chunk.tracks) {
+            track.notes.extend(more.notes.iter().cloned());
+        }
+        joined.length += chunk.length;
+    }
+    joined
+}
+
+fn assert_same(a: &Arrangement, b: &Arrangement) {
+    assert_eq!(a.start, b.start);
+    assert_eq!(a.length, b.length);
+    assert_eq!(a.melody, b.melody);
+    assert_eq!(a.chords, b.chords);
+    assert_eq!(a.tracks, b.tracks);
+}
+
+#[test]
+fn chunks_carry_on_exactly_where_the_last_one_stopped() {
+    let model = model();
+
+    for (seed, scale, model) in [
+        (1234, Scale::Major, None),
+        (-99, Scale::Dorian, None),
+        (5, Scale::Pentatonic, None),
+        (42, Scale::Major, Some(&model)),
+    ] {
+        let whole = arranger(seed, scale, model).arrange(300);
+
+        // Every chunk gets a new arranger that only has the token from the one before
+        let mut token: Option<String> = None;
+        let mut chunks = Vec::new();
+        for count in [100, 1, 128, 71] {
+            let mut chunk = arranger(seed, scale, model);
+            if let Some(token) = &token {
+                chunk
+                    .resume(&StreamState::from_token(token).unwrap())
+                    .unwrap();
+            }
+            chunks.push(chunk.arrange(count));
+            token = Some(chunk.state().to_token());
+        }
+
+        assert_eq!(chunks[1].start, chunks[0].start + chunks[0].length);
+        assert_same(&join(&chunks), &whole);
+    }
+}
+
+#[test]
+fn arranging_again_carries_on_too() {
+    let mut chunked = arranger(31337, Scale::Mixolydian, None);
+    let chunks: Vec<_> = (0..3).map(|_| chunked.arrange(64)).collect();
+
+    assert_same(
+        &join(&chunks),
+        &arranger(31337, Scale::Mixolydian, None).arrange(192),
+    );
+}
+
+#[test]
+fn melodies_resume_on_their_own() {
+    let mut first = NoteGenerator::with_scale(0, 8080, Scale::Blues);
+    let whole: Vec<_> = NoteGenerator::with_scale(0, 8080, Scale::Blues)
+        .take(200)
+        .collect();
+
+    let mut notes: Vec<_> = first.by_ref().take(77).collect();
+    let mut second = NoteGenerator::with_scale(0, 8080, Scale::Blues);
+    second.resume(&first.state()).unwrap();
+    notes.extend(second.take(123));
+
+    assert_eq!(notes, whole);
+}
+
+#[test]
+fn new_seeds_steer_the_rest_of_the_stream() {
+    let mut a = arranger(1000, Scale::Major, None);
+    let first = a.arrange(64);
+    let state = a.state();
+
+    let mut b = arranger(1000, Scale::Major, None);
+    b.resume(&state).unwrap();
+    b.reseed(2000);
+    let next = b.arrange(64);
+
+    // Same key, tempo and bar grid, other notes
+    assert_eq!(next.meter, first.meter);
+    assert_eq!(next.start, first.start + first.length);
+    assert_ne!(next.melody, a.arrange(64).melody);
+}
+
+#[test]
+fn crossfades_carry_over_between_chunks() {
+    let mut whole = arranger(1000, Scale::Major, None);
+    let mut chunks = vec![whole.arrange(50)];
+    whole.crossfade(2000, 40);
+    let whole = join(&[chunks[0].clone(), whole.arrange(150)]);
+
+    let mut faded = arranger(1000, Scale::Major, None);
+    faded.arrange(50);
+    faded.crossfade(2000, 40);
+    let mut token = faded.state().to_token();
+    for count in [70, 80] {
+        let mut chunk = arranger(1000, Scale::Major, None);
+        chunk
+            .resume(&StreamState::from_token(&token).unwrap())
+            .unwrap();
+        chunks.push(chunk.arrange(count));
+        token = chunk.state().to_token();
+    }
+    assert_same(&join(&chunks), &whole);
+
+    // Over no notes at all it's a plain reseed
+    let mut reseeded = arranger(1000, Scale::Major, None);
+    reseeded.arrange(50);
+    let mut crossfaded = arranger(1000, Scale::Major, None);
+    crossfaded.arrange(50);
+    reseeded.reseed(2000);
+    crossfaded.crossfade(2000, 0);
+    assert_same(&reseeded.arrange(100), &crossfaded.arrange(100));
+}
+
+#[test]
+fn crossfades_asked_for_mid_fade_wait_their_turn() {
+    let step = 1. / 17.;
+    let mut melody = NoteGenerator::new(0, 1000);
+    melody.by_ref().take(10).for_each(drop);
+    melody.crossfade(2000, 16);
+    melody.by_ref().take(5).for_each(drop);
+    // Twice more before it's done, the last one is what it goes on to
+    melody.crossfade(3000, 16);
+    melody.by_ref().take(3).for_each(drop);
+    melody.crossfade(4000, 16);
+
+    let mut last = (melody.seed(), melody.fade());
+    assert_eq!(last.0, 2000);
+    for _ in 0..40 {
+        melody.next();
+        let now = (melody.seed(), melody.fade());
+        if now.0 == last.0 {
+            assert!(
+                now.1 <= last.1 && last.1 - now.1 <= step + 1e-6,
+                "{last:?} to {now:?}"
+            );
+        } else {
+            // Only once the old seed is all but gone, and then all of it is the one just faded in
+            assert!(last.1 <= step + 1e-6, "{last:?} to {now:?}");
+            assert_eq!(now, (4000, 1.));
+        }
+        last = now;
+    }
+    assert_eq!(last, (4000, 0.));
+
+    // The one waiting goes in the token with the rest
+    let switched = |stream: &mut Arranger| {
+        let mut chunks = vec![stream.arrange(50)];
+        stream.crossfade(2000, 40);
+        chunks.push(stream.arrange(20));
+        stream.crossfade(3000, 40);
+        chunks
+    };
+    let mut whole = arranger(1000, Scale::Major, None);
+    let mut chunks = switched(&mut whole);
+    chunks.push(whole.arrange(150));
+    let whole = join(&chunks);
+
+    let mut stream = arranger(1000, Scale::Major, None);
+    chunks = switched(&mut stream);
+    let mut token = stream.state().to_token();
+    for count in [70, 80] {
+        let mut chunk = arranger(1000, Scale::Major, None);
+        chunk
+            .resume(&StreamState::from_token(&token).unwrap())
+            .unwrap();
+        chunks.push(chunk.arrange(count));
+        token = chunk.state().to_token();
+    }
+    assert_same(&join(&chunks), &whole);
+}
+
+#[test]
+fn crossfades_change_key_to_the_new_seeds() {
+    // The tonic is a C, so pitch classes are semitones above the key
+    let in_key = |notes: &[Note], key: i8| {
+        notes.iter().all(|note| {
+            Scale::Major
+                .intervals()
+                .contains(&(note.pitch - key).rem_euclid(12))
+        })
+    };
+
+    let old = key_for_seed(1000);
+    let seeds: Vec<_> = (2000..2050)
+        .filter(|&seed| key_for_seed(seed) != old)
+        .collect();
+    assert!(!seeds.is_empty());
+
+    for seed in seeds {
+        let new = key_for_seed(seed);
+        let mut stream = arranger(1000, Scale::Major, None);
+        assert!(in_key(&stream.arrange(64).melody, old));
+
+        stream.crossfade(seed, 16);
+        let next = stream.arrange(128);
+        assert!(in_key(&next.melody[64..], new));
+        // The key changes a bar after a chord that's in both, so no note is out of both of them
+        assert!(
+            next.melody
+                .chunks(1)
+                .all(|note| in_key(note, old) || in_key(note, new))
+        );
+    }
+}
+
+#[test]
+fn states_that_dont_fit_are_turned_down() {
+    assert!(matches!(
+        StreamState::from_token("not a token"),
+        Err(StateError::Malformed)
+    ));
+    assert!(matches!(
+        StreamState::from_token("00ff"),
+        Err(StateError::Malformed)
+    ));
+
+    // Joint histories from a trained model don't go on the built in tables
+    let model = model();
+    let mut trained = arranger(7, Scale::Major, Some(&model));
+    trained.arrange(32);
+    let state = StreamState::from_token(&trained.state().to_token()).unwrap();
+
+    let mut plain = arranger(7, Scale::Major, None);
+    assert!(matches!(
+        plain.resume(&state),
+        Err(StateError::Mismatched("steps"))
+    ));
+    // And nothing changed
+    assert_same(
+        &plain.arrange(64),
+        &arranger(7, Scale::Major, None).arrange(64),
+    );
+}
+
+// A state partway into a crossfade, with `edit` done to it
+fn tampered(edit: impl FnOnce(&mut serde_json::Value)) -> StreamState {
+    let mut stream = arranger(7, Scale::Major, None);
+    stream.arrange(32);
+    stream.crossfade(99, 16);
+    stream.arrange(4);
+
+    let mut state = serde_json::to_value(stream.state()).unwrap();
+    edit(&mut state);
+    serde_json::from_value(state).unwrap()
+}
+
+fn turned_down(state: &StreamState, part: &str) -> bool {
+    let mut stream = arranger(7, Scale::Major, None);
+    matches!(stream.resume(state), Err(StateError::Mismatched(bad)) if bad == part)
+}
+
+#[test]
+fn crafted_states_are_turned_down() {
+    arranger(7, Scale::Major, None)
+        .resume(&tampered(|_| {}))
+        .unwrap();
+
+    // 1e39 is past the largest f32, so it comes out infinite
+    for left in [0., -0.5, 2., 1e39] {
+        let state =
+            tampered(|state| state["melody"]["rng"]["fade"] = serde_json::json!([99, left]));
+        assert!(turned_down(&state, "noise"), "fade {left}");
+    }
+    assert!(turned_down(
+        &tampered(|state| state["melody"]["rng"]["stream"] = 1.into()),
+        "noise"
+    ));
+    assert!(turned_down(
+        &tampered(|state| state["harmony"]["rng"]["stream"] = 1.into()),
+        "chord noise"
+    ));
+    assert!(turned_down(
+        &tampered(|state| state["voicing"]["fade"] = serde_json::json!([99, -1.])),
+        "voicing"
+    ));
+    assert!(turned_down(
+        &tampered(|state| state["drums"]["origin"]["stream"] = 1.into()),
+        "drums"
+    ));
+    assert!(turned_down(
+        &tampered(|state| state["melody"]["position"] = u32::MAX.into()),
+        "position"
+    ));
+    assert!(turned_down(
+        &tampered(|state| state["melody"]["crossfade"]["next"] = serde_json::json!([5, 0])),
+        "crossfade"
+    ));
+
+    // JSON has no NaN, so that one goes in the token itself
+    let marked = tampered(|state| state["melody"]["rng"]["fade"] = serde_json::json!([99, 0.123]));
+    let hex = |value: f32| -> String {
+        value
+            .to_le_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    };
+    let token = marked.to_token();
+    assert_eq!(token.matches(&hex(0.123)).count(), 1);
+    let state = StreamState::from_token(&token.replace(&hex(0.123), &hex(f32::NAN))).unwrap();
+    assert!(turned_down(&state, "noise"));
+}
+
+#[test]
+fn noise_wraps_around_at_the_end() {
+    let state = tampered(|state| {
+        for rng in ["/melody/rng", "/harmony/rng", "/voicing", "/drums/rng"] {
+            state.pointer_mut(rng).unwrap()["x"] = (u32::MAX - 3).into();
+        }
+    });
+
+    let mut stream = arranger(7, Scale::Major, None);
+    stream.resume(&state).unwrap();
+    assert_eq!(stream.arrange(64).melody.len(), 64);
+}
@@ -0,0 +1,98 @@
+use melodybrain::{
+    meter::{Meter, TimeSignature},
+    notes::Note,
+    synth::{self, Synth},
+};
+
+const RATE: u32 = 8000;
+// Half a second per beat
+const METER: Meter = Meter {
+    bpm: 120,
+    time_signature: TimeSignature::COMMON,
+};
+
+fn note(start: f32, pitch: i8, duration: u8) -> Note {
+    Note {
+        start,
+        pitch,
+        velocity: 0.8,
+        duration,
+    }
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn synth() -> Synth {
+    Synth {
+        sample_rate: RATE,
+        ..Synth::default()
+    }
+}
+
+#[test]
+fn notes_are_rendered_where_they_belong() {
+    // A beat of A4, a beat of rest, then a beat of A5
+    let notes = [note(0., 69, 4), note(2., 81, 4)];
+    let samples = synth().render(notes, METER, 1);
+
+    // Three beats at half a second each
+    assert_eq!(samples.len(), (1.5 * RATE as f32) as usize);
+    assert!(samples.iter().all(|sample| (-1. ..=1.).contains(sample)));
+
+    let beat = RATE as usize / 2;
+    let loudest = |range: std::ops::Range<usize>| {
+        samples[range]
+            .iter()
+            .fold(0f32, |loudest, sample| loudest.max(sample.abs()))
+    };
+    assert!(loudest(0..beat) > 0.5);
+    assert_eq!(loudest(beat..2 * beat), 0.);
+    assert!(loudest(2 * beat..3 * beat) > 0.5);
+}
+
+#[test]
+fn renders_are_repeatable() {
+    let notes = [note(0., 60, 2), note(0.5, 64, 2), note(1., 67, 8)];
+
+    assert_eq!(
+        synth().render(notes.clone(), METER, 42),
+        synth().render(notes, METER, 42)
+    );
+}
+
+#[test]
+fn wav_headers_describe_the_samples() {
+    let samples = synth().render([note(0., 69, 4), note(2., 81, 4)], METER, 1);
+    let wav = synth::write_wav(&samples, RATE);
+    let data_len = samples.len() as u32 * 2;
+
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(u32_at(&wav, 4), 36 + data_len);
+    assert_eq!(&wav[8..12], b"WAVE");
+
+    assert_eq!(&wav[12..16], b"fmt ");
+    assert_eq!(u32_at(&wav, 16), 16);
+    // PCM, mono
+    assert_eq!(u16_at(&wav, 20), 1);
+    assert_eq!(u16_at(&wav, 22), 1);
+    assert_eq!(u32_at(&wav, 24), RATE);
+    // Byte rate, block align and bits per sample
+    assert_eq!(u32_at(&wav, 28), RATE * 2);
+    assert_eq!(u16_at(&wav, 32), 2);
+    assert_eq!(u16_at(&wav, 34), 16);
+
+    assert_eq!(&wav[36..40], b"data");
+    assert_eq!(u32_at(&wav, 40), data_len);
+    assert_eq!(wav.len(), 44 + data_len as usize);
+
+    for (idx, sample) in samples.iter().enumerate().step_by(97) {
+        let pcm = u16_at(&wav, 44 + 2 * idx) as i16;
+        assert_eq!(pcm, (sample * i16::MAX as f32) as i16);
+    }
+}